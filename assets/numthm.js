@@ -0,0 +1,14 @@
+/* Scripts of the mdbook-numthm preprocessor. */
+
+/* Copy the label shown next to a header in draft mode to the clipboard when it is clicked. */
+document.addEventListener("click", (event) => {
+    const badge = event.target.closest(".numthm-label");
+    if (!badge || !navigator.clipboard) {
+        return;
+    }
+    const label = badge.textContent.replace(/^⟨|⟩$/g, "");
+    navigator.clipboard.writeText(label).then(() => {
+        badge.classList.add("numthm-copied");
+        setTimeout(() => badge.classList.remove("numthm-copied"), 1000);
+    });
+});
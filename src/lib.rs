@@ -1,43 +1,449 @@
 //! An [mdBook](https://github.com/rust-lang/mdBook) preprocessor for automatically numbering theorems, lemmas, etc.
 
-use log::warn;
-use mdbook::book::{Book, BookItem};
-use mdbook::errors::Result;
-use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use mdbook::book::{Book, BookItem, Chapter, SectionNumber};
+use mdbook::config::TextDirection;
+use mdbook::errors::{Error, Result};
+use mdbook::preprocess::{LinkPreprocessor, Preprocessor, PreprocessorContext};
+use mdbook::utils::fs::path_to_root;
 use pathdiff::diff_paths;
+use rayon::prelude::*;
 use regex::Regex;
-use serde::Deserialize;
-use std::collections::HashMap;
-use std::ops::{Deref, DerefMut};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::{Deref, DerefMut, Range};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 /// The preprocessor name.
 const NAME: &str = "numthm";
 
+/// The comment starting processed chapters, which are left as is if processed again.
+const PROCESSED_MARKER: &str = "<!-- numthm: processed -->\n";
+
+/// The builtin assets, as pairs of a file name and its content.
+const ASSETS: &[(&str, &str)] = &[
+    ("numthm.css", include_str!("../assets/numthm.css")),
+    ("numthm.js", include_str!("../assets/numthm.js")),
+];
+
 /// An environment handled by the preprocessor.
 #[derive(Debug, Clone, Deserialize)]
 struct Env {
     /// The name to display in the header, e.g. "Theorem".
     #[serde(default = "Env::name_default")]
     name: String,
-    /// The markdown emphasis delimiter to apply to the header, e.g. "**" for bold.
+    /// The names in other languages, e.g. "Théorème" for "fr", used by environments with a `lang` attribute.
+    #[serde(default)]
+    names: HashMap<String, String>,
+    /// The markdown emphasis delimiter or HTML tag opening the header, e.g. "**" for bold.
     #[serde(default = "Env::emph_default")]
     emph: String,
+    /// The delimiter closing the header, e.g. "**" for bold or `</strong>` for `<strong class="thm">`.
+    #[serde(default = "Env::emph_default")]
+    emph_close: String,
+    /// The minimal width of the counter, padded with leading zeros, e.g. 2 to get "01", "02", etc.
+    #[serde(default)]
+    pad: usize,
+    /// The name of the counter group the environment belongs to, if any.
+    #[serde(default)]
+    counter: Option<String>,
+    /// The prefix that labels of the environment are expected to start with, e.g. "thm:".
+    #[serde(default)]
+    label_prefix: Option<String>,
+    /// The prefix prepended to labels in anchors of the environment, e.g. "thm--".
+    #[serde(default)]
+    anchor_prefix: String,
+    /// Whether the environment is numbered, e.g. false for proofs.
+    #[serde(default = "Env::numbered_default")]
+    numbered: bool,
+    /// Whether the end of block environments is marked with the QED symbol, e.g. for proofs.
+    #[serde(default)]
+    qed: bool,
+    /// The numeral style of the counter, e.g. roman numerals.
+    #[serde(default)]
+    numbering: NumberStyle,
+    /// The template of the header, see `format_header`, if it differs from the default one.
+    #[serde(default)]
+    format: Option<String>,
+    /// Whether the counter runs across the whole book, without prefix, whatever the numbering scope.
+    #[serde(default)]
+    book_wide: bool,
+    /// The name to display in references, e.g. "Thm.", if it differs from `name`.
+    #[serde(default)]
+    ref_name: Option<String>,
+    /// The name to display in the header, if it differs from `name`.
+    #[serde(default)]
+    header_name: Option<String>,
+    /// The name of the LaTeX environment, e.g. "theorem", if it differs from the lowercase name.
+    #[serde(default)]
+    latex: Option<String>,
+    /// The key of the environment within which the environment is numbered, e.g. "thm" for corollaries.
+    #[serde(default)]
+    within: Option<String>,
+    /// The number of the first environment after each reset of the counter, e.g. 0 or 5.
+    #[serde(default = "Env::start_default")]
+    start: u32,
+    /// The level of the headings of labeled environments with `output = "markdown"`, if they are listed in the
+    /// table of contents, e.g. for theorems but not for remarks.
+    #[serde(default)]
+    heading: Option<usize>,
+    /// The form of references to the environment, e.g. "Thm. 1.2" or "1.2".
+    #[serde(default)]
+    ref_style: RefStyle,
 }
 
 impl Env {
     fn create(name: &str, emph: &str) -> Self {
         Env {
             name: name.to_string(),
+            names: HashMap::new(),
             emph: emph.to_string(),
+            emph_close: emph.to_string(),
+            pad: 0,
+            counter: None,
+            label_prefix: None,
+            anchor_prefix: String::new(),
+            numbered: true,
+            qed: false,
+            numbering: NumberStyle::default(),
+            format: None,
+            book_wide: false,
+            ref_name: None,
+            header_name: None,
+            latex: None,
+            within: None,
+            start: 1,
+            heading: None,
+            ref_style: RefStyle::default(),
         }
     }
+    fn numbered_default() -> bool {
+        true
+    }
+    fn start_default() -> u32 {
+        1
+    }
     fn name_default() -> String {
         String::from("Environment")
     }
     fn emph_default() -> String {
         String::from("**")
     }
+    /// Updates the environment with the entries of a table from the configuration,
+    /// selecting the values given for `renderer` when they differ per renderer,
+    /// and the name given for `language` if any.
+    fn update(&mut self, entry: &toml::value::Table, renderer: &str, language: Option<&str>) {
+        if let Some(v) = entry.get("name").and_then(toml::Value::as_str) {
+            self.name = v.to_string();
+        }
+
+        if let Some(names) = entry.get("names").and_then(toml::Value::as_table) {
+            for (lang, v) in names {
+                if let Some(v) = v.as_str() {
+                    self.names.insert(lang.to_string(), v.to_string());
+                }
+            }
+            if let Some(v) = language.and_then(|language| names.get(language)?.as_str()) {
+                self.name = v.to_string();
+            }
+        }
+
+        if let Some(v) = entry.get("ref_name").and_then(toml::Value::as_str) {
+            self.ref_name = Some(v.to_string());
+        }
+
+        if let Some(v) = entry.get("header_name").and_then(toml::Value::as_str) {
+            self.header_name = Some(v.to_string());
+        }
+
+        if let Some(v) = entry.get("ref_style").and_then(toml::Value::as_str) {
+            match RefStyle::parse(v) {
+                Some(style) => self.ref_style = style,
+                None => warn!(
+                    "{}: Unknown reference style `{v}', using `short' instead",
+                    self.name
+                ),
+            }
+        }
+
+        // the emphasis is either given for all renderers or for each renderer, with an optional default
+        // entries for renderers which don't render markdown (e.g. a LaTeX backend) are taken verbatim
+        let (emph, verbatim) = match entry.get("emph") {
+            Some(toml::Value::Table(renderers)) => match renderers.get(renderer) {
+                Some(value) => (Some(value), !MARKDOWN_RENDERERS.contains(&renderer)),
+                None => (renderers.get("default"), false),
+            },
+            value => (value, false),
+        };
+        if let Some(v) = emph {
+            match parse_emph(v, verbatim) {
+                Some((open, close)) => {
+                    self.emph = open;
+                    self.emph_close = close;
+                }
+                None => warn!(
+                    "{}: Invalid emphasis {v}, expected a markdown emphasis delimiter or an HTML tag, keeping `{}'",
+                    self.name, self.emph
+                ),
+            }
+        }
+
+        if let Some(v) = entry.get("pad").and_then(toml::Value::as_integer) {
+            self.pad = v.max(0) as usize;
+        }
+
+        if let Some(v) = entry.get("numbered").and_then(toml::Value::as_bool) {
+            self.numbered = v;
+        }
+
+        if let Some(v) = entry.get("qed").and_then(toml::Value::as_bool) {
+            self.qed = v;
+        }
+
+        if let Some(v) = entry.get("numbering").and_then(toml::Value::as_str) {
+            match NumberStyle::parse(v) {
+                Some(style) => self.numbering = style,
+                None => warn!(
+                    "{}: Unknown numbering style `{v}', using `arabic' instead",
+                    self.name
+                ),
+            }
+        }
+
+        if let Some(v) = entry.get("format").and_then(toml::Value::as_str) {
+            self.format = Some(v.to_string());
+        }
+
+        if let Some(v) = entry.get("latex").and_then(toml::Value::as_str) {
+            self.latex = Some(v.to_string());
+        }
+
+        if let Some(v) = entry.get("within").and_then(toml::Value::as_str) {
+            self.within = Some(v.to_string());
+        }
+
+        match entry.get("heading") {
+            Some(toml::Value::Integer(level @ 1..=6)) => self.heading = Some(*level as usize),
+            Some(toml::Value::Boolean(false)) => self.heading = None,
+            Some(toml::Value::Boolean(true)) => self.heading = Some(4),
+            Some(v) => warn!(
+                "{}: Invalid heading {v}, expected a level between 1 and 6, true or false",
+                self.name
+            ),
+            None => {}
+        }
+
+        if let Some(v) = entry.get("start").and_then(toml::Value::as_integer) {
+            match u32::try_from(v) {
+                Ok(start) => self.start = start,
+                Err(_) => warn!(
+                    "{}: Invalid start `{v}', expected a nonnegative integer, keeping {}",
+                    self.name, self.start
+                ),
+            }
+        }
+
+        if let Some(v) = entry.get("numbering_scope").and_then(toml::Value::as_str) {
+            match v {
+                "book" => self.book_wide = true,
+                _ => warn!(
+                    "{}: Only the `book' numbering scope can be set for an environment, ignoring `{v}'",
+                    self.name
+                ),
+            }
+        }
+
+        if let Some(v) = entry.get("counter").and_then(toml::Value::as_str) {
+            self.counter = Some(v.to_string());
+        }
+
+        if let Some(v) = entry.get("label_prefix").and_then(toml::Value::as_str) {
+            self.label_prefix = Some(v.to_string());
+        }
+
+        if let Some(v) = entry.get("anchor_prefix").and_then(toml::Value::as_str) {
+            self.anchor_prefix = v.to_string();
+        }
+    }
+    /// Returns the key of the counter used to number the environment with key `key`.
+    fn counter_key<'a>(&'a self, key: &'a str) -> &'a str {
+        self.counter.as_deref().unwrap_or(key)
+    }
+    /// Returns the name to display in references.
+    fn ref_name(&self) -> &str {
+        self.ref_name.as_deref().unwrap_or(&self.name)
+    }
+    /// Returns the name to display in the header.
+    fn header_name(&self) -> &str {
+        self.header_name.as_deref().unwrap_or(&self.name)
+    }
+    /// Returns the name of the LaTeX environment.
+    fn latex_name(&self) -> String {
+        self.latex
+            .clone()
+            .unwrap_or_else(|| self.name.to_lowercase())
+    }
+    /// Returns the name of the LaTeX environment of the unnumbered variant `{{key*}}`, e.g. `theorem*`.
+    fn starred_latex_name(&self) -> String {
+        let name = self.latex_name();
+        match name.ends_with('*') {
+            true => name,
+            false => name + "*",
+        }
+    }
+}
+
+/// Implements the conversion of strings to the configuration enums `$ty` with their `parse` functions,
+/// so that the enums are deserialized from the names accepted in `book.toml`, listed only once.
+macro_rules! parse_from_string {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl TryFrom<String> for $ty {
+                type Error = String;
+
+                fn try_from(s: String) -> std::result::Result<Self, String> {
+                    Self::parse(&s).ok_or_else(|| format!("unknown value `{s}'"))
+                }
+            }
+        )*
+    };
+}
+
+parse_from_string!(
+    RefStyle,
+    NumberStyle,
+    Namespace,
+    NumberingScope,
+    TrefFallback,
+    PlainRefs,
+    OutputFormat,
+    AnchorPlacement,
+    HeaderPiece,
+    DepthOverflow,
+    DuplicateLabels,
+    CompletionsFormat,
+    NameCase,
+    SectionNumbers,
+    IntroEnvironments,
+    FrontMatterPrefix,
+    UnnumberedChapters,
+    SamePageLinks,
+    AssetInjection,
+);
+
+/// The form of references to an environment.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum RefStyle {
+    /// The name of the environment and the number, e.g. "Theorem 1.2".
+    Full,
+    /// The reference name of the environment, if any, and the number, e.g. "Thm. 1.2".
+    #[default]
+    Short,
+    /// The number only, e.g. "1.2".
+    Number,
+}
+
+impl RefStyle {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "full" => Some(RefStyle::Full),
+            "short" => Some(RefStyle::Short),
+            "number" => Some(RefStyle::Number),
+            _ => None,
+        }
+    }
+}
+
+/// The numeral style of environment counters, named after the corresponding LaTeX commands.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum NumberStyle {
+    /// Arabic numerals, e.g. "3".
+    #[default]
+    Arabic,
+    /// Lowercase roman numerals, e.g. "iii".
+    LowerRoman,
+    /// Uppercase roman numerals, e.g. "III".
+    UpperRoman,
+    /// Lowercase letters, e.g. "c", followed by "aa" after "z".
+    LowerAlph,
+    /// Uppercase letters, e.g. "C", followed by "AA" after "Z".
+    UpperAlph,
+}
+
+impl NumberStyle {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "arabic" => Some(NumberStyle::Arabic),
+            "roman" => Some(NumberStyle::LowerRoman),
+            "Roman" => Some(NumberStyle::UpperRoman),
+            "alph" => Some(NumberStyle::LowerAlph),
+            "Alph" => Some(NumberStyle::UpperAlph),
+            _ => None,
+        }
+    }
+
+    /// Returns the counter value `n` in this style, arabic numerals being padded with leading zeros to width `pad`.
+    /// Roman numerals and letters have no zero, which is rendered as "0" in these styles (e.g. a `within` counter
+    /// before the first environment of its parent, or `start = 0`).
+    fn apply(self, n: u32, pad: usize) -> String {
+        match self {
+            NumberStyle::Arabic => format!("{n:0pad$}"),
+            _ if n == 0 => "0".to_string(),
+            NumberStyle::LowerRoman => to_roman(n).to_lowercase(),
+            NumberStyle::UpperRoman => to_roman(n),
+            NumberStyle::LowerAlph => to_letters(n).to_lowercase(),
+            NumberStyle::UpperAlph => to_letters(n),
+        }
+    }
+}
+
+/// The renderers which render markdown, for which emphases are validated.
+const MARKDOWN_RENDERERS: &[&str] = &["html", "markdown"];
+
+/// The renderers which render LaTeX, for which the output format is `latex` unless configured otherwise.
+const LATEX_RENDERERS: &[&str] = &["latex", "tectonic"];
+
+/// Parses the emphasis of headers, either a markdown emphasis delimiter (e.g. "**"), an HTML opening tag
+/// (e.g. `<strong class="thm">`, closed with `</strong>`), or a pair of opening and closing delimiters,
+/// and returns the opening and closing delimiters if they are balanced or if `verbatim` is true.
+fn parse_emph(value: &toml::Value, verbatim: bool) -> Option<(String, String)> {
+    let (open, close) = match value {
+        toml::Value::String(open) => {
+            let close = match html_tag(open) {
+                Some(tag) => format!("</{tag}>"),
+                None => open.clone(),
+            };
+            (open.clone(), close)
+        }
+        toml::Value::Array(pair) => match pair.as_slice() {
+            [toml::Value::String(open), toml::Value::String(close)] => {
+                (open.clone(), close.clone())
+            }
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let valid = match html_tag(&open) {
+        _ if verbatim => true,
+        Some(tag) => cached_regex(&format!(r"^</{}\s*>$", regex::escape(tag))).is_match(&close),
+        None => {
+            open == close
+                && open.len() <= 3
+                && (open.chars().all(|c| c == '*') || open.chars().all(|c| c == '_'))
+        }
+    };
+    valid.then_some((open, close))
+}
+
+/// Returns the name of the tag if `s` is an HTML opening tag, e.g. "strong" for `<strong class="thm">`.
+fn html_tag(s: &str) -> Option<&str> {
+    cached_regex(r"^<(?P<tag>[A-Za-z][A-Za-z0-9-]*)(?:\s[^<>]*)?>$")
+        .captures(s)
+        .map(|caps| caps.name("tag").unwrap().as_str())
 }
 
 /// Environment collection
@@ -52,6 +458,17 @@ impl Default for EnvMap {
         envs.insert("prop".to_string(), Env::create("Proposition", "**"));
         envs.insert("def".to_string(), Env::create("Definition", "**"));
         envs.insert("rem".to_string(), Env::create("Remark", "*"));
+        envs.insert("exercise".to_string(), Env::create("Exercise", "**"));
+        let mut proof = Env::create("Proof", "*");
+        proof.numbered = false;
+        proof.qed = true;
+        envs.insert("proof".to_string(), proof);
+        // figures and tables are captioned rather than titled
+        for (key, name) in [("fig", "Figure"), ("tab", "Table")] {
+            let mut env = Env::create(name, "**");
+            env.format = Some("{emph}{name} {number}.{emph} {caption}".to_string());
+            envs.insert(key.to_string(), env);
+        }
         EnvMap(envs)
     }
 }
@@ -69,407 +486,10365 @@ impl DerefMut for EnvMap {
 }
 
 /// The `LabelInfo` structure contains information for formatting the hyperlink to a specific theorem, lemma, etc.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 struct LabelInfo {
+    /// The key of the environment with the label, e.g. "thm".
+    env: String,
     /// The "numbered name" associated with the label, e.g. "Theorem 1.2.1".
     num_name: String,
+    /// The name of the environment, if it differs from the name of its key, e.g. "Théorème" with `lang=fr`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
     /// The path to the file containing the environment with the label.
     path: PathBuf,
     /// An optional title.
     title: Option<String>,
+    /// Whether the label is exported, i.e., can be referred to from other chapters when labels are private.
+    #[serde(default)]
+    exported: bool,
+    /// The numbered name displayed in the chapter of the environment, if it differs from `num_name`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    local_name: Option<String>,
+    /// The label of the environment this one derives from (e.g. the theorem of a corollary), if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    of: Option<String>,
+    /// The chapter containing the environment with the label.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chapter: Option<ChapterInfo>,
+    /// The values of the fields given as attributes, e.g. `author="Gauss"`, see `NumThmPreprocessor::fields`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    fields: BTreeMap<String, String>,
 }
 
-/// A preprocessor for automatically numbering theorems, lemmas, etc.
-#[derive(Clone, Debug, Default, Deserialize)]
-pub struct NumThmPreprocessor {
-    /// The list of environments handled by the preprocessor.
-    environments: EnvMap,
-    /// Whether theorem numbers must be prefixed by the section number.
-    with_prefix: bool,
+/// The title and number of a chapter, available to templates.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct ChapterInfo {
+    /// The title of the chapter, e.g. "Groups".
+    title: String,
+    /// The number of the chapter without trailing dot, e.g. "3.2", if the chapter is numbered.
+    number: Option<String>,
 }
 
-impl NumThmPreprocessor {
-    pub fn new(ctx: &PreprocessorContext) -> Self {
-        let mut config = Self::default();
-
-        let toml_config: &toml::value::Table = ctx.config.get_preprocessor("numthm").unwrap();
+/// Whether macros must be namespaced, e.g. `{{numthm:thm}}` instead of `{{thm}}`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum Namespace {
+    /// Only macros without namespace are recognized.
+    #[default]
+    Off,
+    /// Macros are recognized with or without namespace.
+    Optional,
+    /// Only namespaced macros are recognized.
+    Required,
+}
 
-        // Set use of prefix conf.
-        if let Some(b) = toml_config.get("prefix").and_then(toml::Value::as_bool) {
-            config.with_prefix = b;
+impl Namespace {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "off" => Some(Namespace::Off),
+            "optional" => Some(Namespace::Optional),
+            "required" => Some(Namespace::Required),
+            _ => None,
         }
+    }
+}
 
-        // Get environments table
-        if let Some(envs) = toml_config
-            .get("environments")
-            .and_then(toml::Value::as_table)
-        {
-            for (key, value) in envs.iter() {
-                // Update from entries, but only if data is available
-                if let Some(entry) = toml::Value::as_table(value) {
-                    // Allow removal of entry
-                    if let Some(ignore) = entry.get("ignore").and_then(toml::Value::as_bool) {
-                        if ignore {
-                            config.environments.remove(key);
-                            continue;
-                        }
-                    }
+/// The syntax of macros, i.e., the delimiters enclosing them, e.g. `{{` and `}}` in `{{thm}}`,
+/// and whether they must be namespaced.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+struct MacroSyntax {
+    /// The opening delimiter.
+    open: String,
+    /// The closing delimiter.
+    close: String,
+    /// Whether macros must be prefixed by `numthm:`.
+    namespace: Namespace,
+    /// The maximal length of labels and titles, bounding the work done on unterminated macros.
+    max_capture: usize,
+}
 
-                    let name = entry.get("name").and_then(toml::Value::as_str);
-                    let emph = entry.get("emph").and_then(toml::Value::as_str);
+impl Default for MacroSyntax {
+    fn default() -> Self {
+        MacroSyntax {
+            open: String::from("{{"),
+            close: String::from("}}"),
+            namespace: Namespace::Off,
+            max_capture: Self::DEFAULT_MAX_CAPTURE,
+        }
+    }
+}
 
-                    if let Some(env) = config.environments.get_mut(key) {
-                        if let Some(v) = name {
-                            env.name = v.to_string();
-                        }
+impl MacroSyntax {
+    /// The namespace prefix of macros.
+    const NAMESPACE: &'static str = "numthm:";
+    /// The default maximal length of labels and titles.
+    const DEFAULT_MAX_CAPTURE: usize = 256;
+    /// The largest accepted maximal length of labels and titles, beyond which regexes get too large.
+    const MAX_MAX_CAPTURE: usize = 4096;
 
-                        if let Some(v) = emph {
-                            env.emph = v.to_string();
-                        }
-                    } else {
-                        config.environments.insert(
-                            String::from(key),
-                            Env::create(name.unwrap_or("Environment"), emph.unwrap_or("**")),
-                        );
-                    }
-                }
-            }
+    /// Returns a regex pattern matching `inner` enclosed in the delimiters, preceded by the namespace if needed.
+    fn pattern(&self, inner: &str) -> String {
+        format!(
+            "{}{}{inner}{}",
+            regex::escape(&self.open),
+            self.namespace_pattern(),
+            regex::escape(&self.close)
+        )
+    }
+    /// Returns a regex pattern matching the namespace prefix of macros as required.
+    fn namespace_pattern(&self) -> String {
+        match self.namespace {
+            Namespace::Off => String::new(),
+            Namespace::Optional => format!("(?:{})?", regex::escape(Self::NAMESPACE)),
+            Namespace::Required => regex::escape(Self::NAMESPACE),
         }
+    }
+    /// Returns a lazy regex pattern matching at most `max_capture` characters of a line,
+    /// used instead of `.*?` so that unterminated macros on huge lines are given up quickly.
+    fn capture(&self) -> String {
+        format!(".{{0,{}}}?", self.max_capture)
+    }
+    /// Returns the macro with content `inner`, e.g. `{{ref: label}}`.
+    fn wrap(&self, inner: &str) -> String {
+        let namespace = match self.namespace {
+            Namespace::Required => Self::NAMESPACE,
+            _ => "",
+        };
+        format!("{}{namespace}{inner}{}", self.open, self.close)
+    }
+    /// Returns the regex matching exports `{{export: label}}`.
+    fn export_regex(&self) -> Regex {
+        cached_regex(&self.pattern(&format!(r"export:\s*(?P<label>{})\s*", self.capture())))
+    }
+    /// Returns the regex matching restatements `{{restate: label}}`.
+    fn restate_regex(&self) -> Regex {
+        cached_regex(&self.pattern(&format!(r"restate:\s*(?P<label>{})\s*", self.capture())))
+    }
+    /// Returns the regex matching solutions `{{solution: label}}`.
+    fn solution_regex(&self) -> Regex {
+        cached_regex(&self.pattern(&format!(r"solution:\s*(?P<label>{})\s*", self.capture())))
+    }
+    /// Returns the regex matching references `{{ref: label}}`, `{{tref: label}}`, `{{pref: label}}`,
+    /// and `{{nref: label}}`.
+    fn ref_regex(&self) -> Regex {
+        // see https://regex101.com/ for an explanation of the regex "\{\{(?P<reftype>ref|tref|pref|nref)(?:\s+(?P<attrs>(?:[\w-]+\s*=\s*[\w-]+\s*)+))?:\s*(?P<label>.*?)(?:\s*\|\s*(?P<text>.*?))?\}\}"
+        cached_regex(&self.pattern(&format!(
+            r"(?P<reftype>ref|tref|pref|nref)(?:\s+(?P<attrs>(?:[\w-]+\s*=\s*[\w-]+\s*)+))?:\s*(?P<label>{capture})(?:\s*\|\s*(?P<text>{capture}))?",
+            capture = self.capture()
+        )))
+    }
+    /// Returns the regex matching equation markers `{{eq}}{label}`, where `{label}` is optional.
+    fn eq_regex(&self) -> Regex {
+        cached_regex(&format!(
+            r"{}(\{{(?P<label>{capture})\}})?",
+            self.pattern(r"eq\s*"),
+            capture = self.capture()
+        ))
+    }
+}
 
-        config
+/// Normalizes a captured label by trimming it and collapsing internal whitespace,
+/// e.g. `thm:x` for `{{ref:   thm:x }}`.
+fn normalize_label(label: &str) -> String {
+    label.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// Turns `label` into a valid fragment identifier, keeping letters (including non-ASCII ones), digits,
+/// and the characters `-`, `_`, `:`, and `.`, and replacing runs of other characters with a single `-`,
+/// e.g. `thm:central-limit` for `thm:central limit` and `thm:gödel-s` for `thm:gödel’s`.
+fn slugify(label: &str) -> String {
+    let mut slug = String::with_capacity(label.len());
+    let mut replaced = false;
+    for c in label.chars() {
+        if c.is_alphanumeric() || matches!(c, '-' | '_' | ':' | '.') {
+            slug.push(c);
+            replaced = false;
+        } else if !replaced {
+            slug.push('-');
+            replaced = true;
+        }
     }
+    slug
 }
 
-impl Preprocessor for NumThmPreprocessor {
-    fn name(&self) -> &str {
-        NAME
+/// Compiles `pattern`, reusing the regex compiled by a previous call with the same pattern
+/// since patterns with bounded captures are expensive to compile for each chapter.
+fn cached_regex(pattern: &str) -> Regex {
+    lazy_static! {
+        static ref CACHE: Mutex<HashMap<String, Regex>> = Mutex::new(HashMap::new());
     }
+    CACHE
+        .lock()
+        .unwrap()
+        .entry(pattern.to_string())
+        .or_insert_with(|| Regex::new(pattern).unwrap())
+        .clone()
+}
 
-    fn run(&self, _ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
-        // a hashmap mapping labels to `LabelInfo` structs
-        let mut refs: HashMap<String, LabelInfo> = HashMap::new();
+/// A statement defined once in the snippets file and instantiated in the book.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct Snippet {
+    /// The key of the environment of the statement, e.g. "thm".
+    env: String,
+    /// The label of the statement, defaults to the name of the snippet.
+    label: Option<String>,
+    /// An optional title.
+    title: Option<String>,
+    /// The body of the statement.
+    body: String,
+}
 
-        book.for_each_mut(|item: &mut BookItem| {
-            if let BookItem::Chapter(chapter) = item {
-                if !chapter.is_draft_chapter() {
-                    // one can safely unwrap chapter.path which must be Some(...)
-                    let prefix = if self.with_prefix {
-                        match &chapter.number {
-                            Some(sn) => sn.to_string(),
-                            None => String::new(),
-                        }
-                    } else {
-                        String::new()
-                    };
-                    let path = chapter.path.as_ref().unwrap();
-                    chapter.content = find_and_replace_envs(
-                        &chapter.content,
-                        &prefix,
-                        path,
-                        &self.environments,
-                        &mut refs,
-                    );
-                }
-            }
-        });
+/// A reference found in the book.
+#[derive(Debug, PartialEq)]
+struct RefOccurrence {
+    /// The path to the file containing the reference.
+    path: PathBuf,
+    /// The line of the reference in the source file.
+    line: usize,
+    /// The referenced label.
+    label: String,
+}
 
-        book.for_each_mut(|item: &mut BookItem| {
-            if let BookItem::Chapter(chapter) = item {
-                if !chapter.is_draft_chapter() {
-                    // one can safely unwrap chapter.path which must be Some(...)
-                    let path = chapter.path.as_ref().unwrap();
-                    chapter.content = find_and_replace_refs(&chapter.content, path, &refs);
-                }
-            }
-        });
+/// The kind of a problem found by [`NumThmPreprocessor::check`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckKind {
+    /// A label defined by several environments or equations.
+    DuplicateLabel,
+    /// A reference to a label which is not defined.
+    UnresolvedRef,
+    /// A label which is never referenced.
+    UnusedLabel,
+    /// A label whose prefix (e.g. `thm:`) doesn't match the environment it is given to.
+    LabelPrefix,
+}
 
-        Ok(book)
-    }
+/// A problem found by [`NumThmPreprocessor::check`].
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CheckIssue {
+    /// The kind of the problem.
+    pub kind: CheckKind,
+    /// Whether the problem is an error, i.e. anything but an unused label or a duplicate label which is
+    /// suffixed (see `duplicate_labels`).
+    pub error: bool,
+    /// The path to the chapter containing the label or reference.
+    pub path: PathBuf,
+    /// The line of the label or reference in the chapter.
+    pub line: usize,
+    /// The label.
+    pub label: String,
+    /// The description of the problem, e.g. "Reference to undefined label `thm:a'".
+    pub message: String,
 }
 
-/// Finds all patterns `{{key}}{mylabel}[mytitle]` where `key` is the key field of `env` (e.g. `thm`)
-/// and replaces them with a header (including the title if a title `mytitle` is provided)
-/// and potentially an anchor if a label `mylabel` is provided;
-/// if a label is provided, it updates the hashmap `refs` with an entry (label, LabelInfo)
-/// allowing to format links to the theorem.
-fn find_and_replace_envs(
-    s: &str,
-    prefix: &str,
-    path: &Path,
-    envs: &EnvMap,
-    refs: &mut HashMap<String, LabelInfo>,
-) -> String {
-    let mut counter: HashMap<String, u32> = envs.iter().map(|(k, _)| (k.clone(), 0)).collect();
+impl std::fmt::Display for CheckIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.path.display(), self.line, self.message)
+    }
+}
 
-    let keys = envs
-        .keys()
-        .map(String::as_str)
-        .collect::<Vec<&str>>()
-        .join("|");
-    let pattern = format!(
-        r"\{{\{{(?P<key>{})\}}\}}(\{{(?P<label>.*?)\}})?(\[(?P<title>.*?)\])?",
-        keys
-    );
-    // see https://regex101.com/ for an explanation of the regex "\{\{(?P<key>key1|key2)\}\}(\{(?P<label>.*?)\})?(\[(?P<title>.*?)\])?"
-    // matches {{key}}{label}[title] where {label} and [title] are optional
-    let re: Regex = Regex::new(pattern.as_str()).unwrap();
+/// A numbered environment, as passed to [`Hooks::environment`].
+#[derive(Debug)]
+pub struct Environment<'a> {
+    /// The environment key (e.g. `thm`).
+    pub key: &'a str,
+    /// The number (e.g. "1.2").
+    pub number: &'a str,
+    /// The numbered name (e.g. "Theorem 1.2").
+    pub num_name: &'a str,
+    /// The label, if any.
+    pub label: Option<&'a str>,
+    /// The title, if any.
+    pub title: Option<&'a str>,
+    /// The path to the chapter containing the environment.
+    pub path: &'a Path,
+    /// The markdown the environment is replaced with.
+    pub output: &'a str,
+}
 
-    re.replace_all(s, |caps: &regex::Captures| {
-        // key must have been matched
-        let key = caps.name("key").unwrap().as_str();
+/// A reference, as passed to [`Hooks::reference`].
+#[derive(Debug)]
+pub struct Reference<'a> {
+    /// The referenced label.
+    pub label: &'a str,
+    /// Whether this is a title reference (`{{tref: label}}`) rather than a reference (`{{ref: label}}`).
+    pub title_ref: bool,
+    /// The path to the chapter containing the reference.
+    pub path: &'a Path,
+    /// The path to the chapter containing the referenced environment, if the label is known.
+    pub target: Option<&'a Path>,
+    /// The markdown the reference is replaced with.
+    pub output: &'a str,
+}
 
-        // key is absolutely part of env, so unwrap should be ok
-        let env = envs.get(key).unwrap();
-        let name = &env.name;
-        let emph = &env.emph;
-        let ctr = counter.get_mut(key).unwrap();
-        *ctr += 1;
+/// Hooks invoked for each environment and each reference when the preprocessor is used as a library,
+/// allowing to implement custom behaviors (e.g. metrics or custom outputs) without forking the preprocessor.
+/// Environments are processed in a first pass over the whole book, before references are resolved in a second pass
+/// processing chapters in parallel, hence `reference` may be called concurrently for references of different chapters.
+pub trait Hooks: Send + Sync {
+    /// Called for each environment; returning `Some(output)` replaces the generated markdown with `output`.
+    fn environment(&self, _env: &Environment) -> Option<String> {
+        None
+    }
 
-        let anchor = match caps.name("label") {
-            Some(match_label) => {
-                // if a label is given, we must update the hashmap
-                let label = match_label.as_str().to_string();
-                if refs.contains_key(&label) {
-                    // if the same label has already been used we emit a warning and don't update the hashmap
-                    warn!("{name} {prefix}{ctr}: Label `{label}' already used");
-                } else {
-                    refs.insert(
-                        label.clone(),
-                        LabelInfo {
-                            num_name: format!("{name} {prefix}{ctr}"),
-                            path: path.to_path_buf(),
-                            title: caps.name("title").map(|t| t.as_str().to_string()),
-                        },
-                    );
-                }
-                format!("<a name=\"{label}\"></a>\n")
-            }
-            None => String::new(),
-        };
-        let header = match caps.name("title") {
-            Some(match_title) => {
-                let title = match_title.as_str().to_string();
-                format!("{emph}{name} {prefix}{ctr} ({title}).{emph}")
-            }
-            None => {
-                format!("{emph}{name} {prefix}{ctr}.{emph}")
-            }
-        };
-        format!("{anchor}{header}")
-    })
-    .to_string()
+    /// Called for each reference; returning `Some(output)` replaces the generated markdown with `output`.
+    fn reference(&self, _reference: &Reference) -> Option<String> {
+        None
+    }
 }
 
-/// Finds and replaces all patterns {{ref: label}} where label is an existing key in hashmap `refs`
-/// with a link towards the relevant theorem.
-fn find_and_replace_refs(
-    s: &str,
-    chap_path: &PathBuf,
-    refs: &HashMap<String, LabelInfo>,
-) -> String {
-    // see https://regex101.com/ for an explanation of the regex
-    let re: Regex = Regex::new(r"\{\{(?P<reftype>ref:|tref:)\s*(?P<label>.*?)\}\}").unwrap();
+/// The hooks registered with [`NumThmPreprocessor::with_hooks`], called in registration order.
+#[derive(Clone, Default)]
+struct HookList(Vec<Arc<dyn Hooks>>);
 
-    re.replace_all(s, |caps: &regex::Captures| {
-        let label = caps.name("label").unwrap().as_str().to_string();
-        if refs.contains_key(&label) {
-            let text = match caps.name("reftype").unwrap().as_str() {
-                "ref:" => &refs.get(&label).unwrap().num_name,
-                _ => {
-                    // this must be tref if there is a match
-                    match &refs.get(&label).unwrap().title {
-                        Some(t) => t,
-                        // fallback to the numbered name in case the label does not have an associated title
-                        None => &refs.get(&label).unwrap().num_name,
-                    }
-                }
-            };
-            let path_to_ref = &refs.get(&label).unwrap().path;
-            let rel_path = compute_rel_path(chap_path, path_to_ref);
-            format!("[{text}]({rel_path}#{label})")
-        } else {
-            warn!("Unknown reference: {}", label);
-            "**[??]**".to_string()
-        }
-    })
-    .to_string()
+impl std::fmt::Debug for HookList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HookList({} hooks)", self.0.len())
+    }
 }
 
-/// Computes the relative path from the folder containing `chap_path` to the file `path_to_ref`.
-fn compute_rel_path(chap_path: &PathBuf, path_to_ref: &PathBuf) -> String {
-    if chap_path == path_to_ref {
-        return "".to_string();
+impl HookList {
+    /// Runs the hooks on an environment, each one seeing the output of the previous ones.
+    fn environment(&self, env: Environment, output: String) -> String {
+        self.0.iter().fold(output, |output, hooks| {
+            let env = Environment {
+                output: &output,
+                ..env
+            };
+            hooks.environment(&env).unwrap_or(output)
+        })
+    }
+
+    /// Runs the hooks on a reference, each one seeing the output of the previous ones.
+    fn reference(&self, reference: Reference, output: String) -> String {
+        self.0.iter().fold(output, |output, hooks| {
+            let reference = Reference {
+                output: &output,
+                ..reference
+            };
+            hooks.reference(&reference).unwrap_or(output)
+        })
     }
-    let mut local_chap_path = chap_path.clone();
-    local_chap_path.pop();
-    format!(
-        "{}",
-        diff_paths(path_to_ref, &local_chap_path).unwrap().display()
-    )
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use lazy_static::lazy_static;
+/// A numbered environment found in the book, listed by `{{numthm-list}}`.
+#[derive(Debug, Clone, PartialEq)]
+struct EnvEntry {
+    /// The environment key (e.g. `thm`).
+    env: String,
+    /// The number (e.g. "1.2").
+    number: String,
+    /// The numbered name (e.g. "Theorem 1.2").
+    num_name: String,
+    /// The path to the file containing the environment.
+    path: PathBuf,
+    /// The label, if any.
+    label: Option<String>,
+    /// The title, if any.
+    title: Option<String>,
+    /// The numbered name displayed in the chapter of the environment, if it differs from `num_name`.
+    local_name: Option<String>,
+    /// The chapter containing the environment.
+    chapter: Option<ChapterInfo>,
+    /// Whether the environment is numbered, unnumbered ones being only listed on demand.
+    numbered: bool,
+    /// The values of the fields given as attributes.
+    fields: BTreeMap<String, String>,
+}
 
-    const SECNUM: &str = "1.2.";
+/// The scope within which environment counters run before being reset.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum NumberingScope {
+    /// Counters are reset at the beginning of each (sub)chapter.
+    #[default]
+    Chapter,
+    /// Counters run across all chapters of a part and are prefixed by the part number in roman numerals.
+    Part,
+    /// Counters run across the whole book and are not prefixed.
+    Book,
+}
 
-    lazy_static! {
-        static ref ENVMAP: EnvMap = EnvMap::default();
-        static ref PATH: PathBuf = "crypto/groups.md".into();
+impl NumberingScope {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "chapter" => Some(NumberingScope::Chapter),
+            "part" => Some(NumberingScope::Part),
+            "book" => Some(NumberingScope::Book),
+            _ => None,
+        }
+    }
+}
+
+/// The behavior of `{{tref: label}}` when the label has no associated title.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum TrefFallback {
+    /// Fall back to the numbered name, e.g. "Theorem 1.2.1".
+    #[default]
+    Name,
+    /// Fall back to the raw label.
+    Label,
+    /// Fail the build.
+    Error,
+}
+
+impl TrefFallback {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "name" => Some(TrefFallback::Name),
+            "label" => Some(TrefFallback::Label),
+            "error" => Some(TrefFallback::Error),
+            _ => None,
+        }
+    }
+}
+
+/// The replacement of references in image alt texts and in link texts and titles, where links can't be nested.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum PlainRefs {
+    /// The text of the link, e.g. "Theorem 1.2".
+    #[default]
+    Text,
+    /// The number only, e.g. "1.2".
+    Number,
+    /// A link as elsewhere, which produces invalid markdown.
+    Link,
+}
+
+impl PlainRefs {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "text" => Some(PlainRefs::Text),
+            "number" => Some(PlainRefs::Number),
+            "link" => Some(PlainRefs::Link),
+            _ => None,
+        }
+    }
+}
+
+/// The flavor of the generated markdown.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum OutputFormat {
+    /// Markdown with raw HTML anchors.
+    #[default]
+    Html,
+    /// Markdown without raw HTML, where environments have no anchors.
+    Markdown,
+    /// Markdown with raw LaTeX environments `\begin{theorem}` and references `\Cref{label}`,
+    /// numbered by LaTeX.
+    Latex,
+}
+
+impl OutputFormat {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "html" => Some(OutputFormat::Html),
+            "markdown" => Some(OutputFormat::Markdown),
+            "latex" => Some(OutputFormat::Latex),
+            _ => None,
+        }
+    }
+}
+
+/// The placement of the anchors of labeled environments in HTML output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum AnchorPlacement {
+    /// An `<a id="label">` element precedes the header, inside its paragraph.
+    #[default]
+    Inline,
+    /// The anchor is the id of the `div` wrapping block environments, or of an empty `div` preceding the
+    /// paragraph of other environments, so that no element is inserted in the text of paragraphs.
+    Wrapper,
+}
+
+impl AnchorPlacement {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "inline" => Some(AnchorPlacement::Inline),
+            "wrapper" => Some(AnchorPlacement::Wrapper),
+            _ => None,
+        }
+    }
+}
+
+/// A piece of the header of environments, see `header_layout`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum HeaderPiece {
+    /// The anchor of labeled environments in HTML output, e.g. `<a id="thm:x"></a>`.
+    Anchor,
+    /// The header proper, e.g. "**Theorem 1.2 (Lagrange).**", built from the `format` of the environment.
+    Header,
+    /// A link of labeled environments in HTML and markdown output to the environment itself, e.g. "¶".
+    Permalink,
+    /// The label shown in draft mode.
+    Label,
+}
+
+impl HeaderPiece {
+    /// The pieces of headers and their order, unless configured otherwise.
+    const DEFAULT_LAYOUT: &'static [HeaderPiece] =
+        &[HeaderPiece::Anchor, HeaderPiece::Header, HeaderPiece::Label];
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "anchor" => Some(HeaderPiece::Anchor),
+            "header" => Some(HeaderPiece::Header),
+            "permalink" => Some(HeaderPiece::Permalink),
+            "label" => Some(HeaderPiece::Label),
+            _ => None,
+        }
+    }
+}
+
+/// The handling of numbers with more components than allowed by `max_depth`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum DepthOverflow {
+    /// A warning is emitted and the number is kept.
+    #[default]
+    Warn,
+    /// The deepest components of the prefix are dropped, e.g. "1.2.3.4.5" becomes "1.4.5" with `max_depth = 3`
+    /// if "4.5" is the number of the environment within the chapter.
+    Truncate,
+}
+
+impl DepthOverflow {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "warn" => Some(DepthOverflow::Warn),
+            "truncate" => Some(DepthOverflow::Truncate),
+            _ => None,
+        }
+    }
+}
+
+/// The strategy used to resolve duplicate labels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum DuplicateLabels {
+    /// References point to the first environment with the label.
+    #[default]
+    KeepFirst,
+    /// References point to the last environment with the label.
+    KeepLast,
+    /// Duplicate labels are renamed by appending a numeric suffix, e.g. `thm:main-2`.
+    Suffix,
+}
+
+impl DuplicateLabels {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "keep-first" => Some(DuplicateLabels::KeepFirst),
+            "keep-last" => Some(DuplicateLabels::KeepLast),
+            "suffix" => Some(DuplicateLabels::Suffix),
+            _ => None,
+        }
+    }
+}
+
+/// The format of the completions file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum CompletionsFormat {
+    /// A JSON object mapping labels to their environment, numbered name, title, and path.
+    #[default]
+    Json,
+    /// A VS Code snippets file.
+    Vscode,
+}
+
+impl CompletionsFormat {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "json" => Some(CompletionsFormat::Json),
+            "vscode" => Some(CompletionsFormat::Vscode),
+            _ => None,
+        }
+    }
+}
+
+/// The casing of environment names.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum NameCase {
+    /// The name as configured.
+    #[default]
+    AsIs,
+    /// All letters in lowercase, e.g. "theorem".
+    Lower,
+    /// All letters in uppercase, e.g. "THEOREM".
+    Upper,
+    /// The first letter of each word in uppercase, e.g. "Main Theorem".
+    Title,
+}
+
+impl NameCase {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "as-is" => Some(NameCase::AsIs),
+            "lower" => Some(NameCase::Lower),
+            "upper" => Some(NameCase::Upper),
+            "title" => Some(NameCase::Title),
+            _ => None,
+        }
+    }
+
+    /// Returns `name` with this casing.
+    fn apply(self, name: &str) -> String {
+        match self {
+            NameCase::AsIs => name.to_string(),
+            NameCase::Lower => name.to_lowercase(),
+            NameCase::Upper => name.to_uppercase(),
+            NameCase::Title => name
+                .split(' ')
+                .map(|word| {
+                    let mut chars = word.chars();
+                    match chars.next() {
+                        Some(first) => first.to_uppercase().chain(chars).collect(),
+                        None => String::new(),
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join(" "),
+        }
+    }
+}
+
+/// The casing of environment names in each context in which they appear.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+struct NameCases {
+    /// The casing in headers.
+    header: NameCase,
+    /// The casing in references, which usually appear mid-sentence.
+    reference: NameCase,
+    /// The casing in lists of environments.
+    list: NameCase,
+}
+
+/// The source of chapter numbers in prefixes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum SectionNumbers {
+    /// The numbers assigned by mdBook from the summary.
+    #[default]
+    Summary,
+    /// The numbers at the beginning of the first heading of each chapter, e.g. emitted by a heading-numbering
+    /// preprocessor; chapters whose first heading has no number are unnumbered.
+    Headings,
+}
+
+/// The prefix of environments preceding the first heading of a chapter whose prefix is read from headings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum IntroEnvironments {
+    /// They are attached to the section of the following heading, e.g. "Theorem 2.3.1" before `# 2.3. Groups`.
+    #[default]
+    Section,
+    /// They get the chapter part of the number of the following heading, e.g. "Theorem 2.1" before
+    /// `# 2.3. Groups`, and counters are reset at the heading.
+    Chapter,
+}
+
+impl IntroEnvironments {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "section" => Some(IntroEnvironments::Section),
+            "chapter" => Some(IntroEnvironments::Chapter),
+            _ => None,
+        }
+    }
+}
+
+impl SectionNumbers {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "summary" => Some(SectionNumbers::Summary),
+            "headings" => Some(SectionNumbers::Headings),
+            _ => None,
+        }
+    }
+}
+
+/// The prefix of environment numbers in front-matter chapters, i.e., unnumbered chapters before the first numbered one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum FrontMatterPrefix {
+    /// No prefix, as in other unnumbered chapters.
+    #[default]
+    Empty,
+    /// The position of the chapter in the front matter in lowercase roman numerals, e.g. "ii." for the second one.
+    Roman,
+}
+
+impl FrontMatterPrefix {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "none" => Some(FrontMatterPrefix::Empty),
+            "roman" => Some(FrontMatterPrefix::Roman),
+            _ => None,
+        }
+    }
+}
+
+/// The numbering of environments in unnumbered chapters when numbers are prefixed by the chapter number.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum UnnumberedChapters {
+    /// Environments are numbered without prefix.
+    #[default]
+    None,
+    /// Environments are not numbered.
+    Skip,
+    /// Environments are numbered with the fallback prefix.
+    Fallback,
+    /// Environments are numbered without prefix by counters running across all unnumbered chapters.
+    Global,
+    /// Fail the build.
+    Error,
+}
+
+impl UnnumberedChapters {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "none" => Some(UnnumberedChapters::None),
+            "skip" => Some(UnnumberedChapters::Skip),
+            "fallback" => Some(UnnumberedChapters::Fallback),
+            "global" => Some(UnnumberedChapters::Global),
+            "error" => Some(UnnumberedChapters::Error),
+            _ => None,
+        }
+    }
+}
+
+/// The markup of block environments `{{#key}} ... {{/key}}`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+struct Blocks {
+    /// The class of the `div` wrapping block environments, where `{key}` is replaced with the environment key.
+    class: String,
+    /// The symbol marking the end of proofs.
+    qed_symbol: String,
+}
+
+impl Default for Blocks {
+    fn default() -> Self {
+        Blocks {
+            class: "numthm numthm-{key}".to_string(),
+            qed_symbol: "∎".to_string(),
+        }
+    }
+}
+
+/// The templates of the texts of references and list items, see `fill_template`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+struct Templates {
+    /// The template of the link text of references.
+    reference: String,
+    /// The template of the link text of prose references `{{pref: label}}`.
+    prose: String,
+    /// The template of the text of list items.
+    list: String,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Templates {
+            reference: "{text}".to_string(),
+            prose: "{text}".to_string(),
+            list: "{text}".to_string(),
+        }
+    }
+}
+
+/// The texts generated by the preprocessor besides environment names, which can be translated.
+#[derive(Clone, Debug, Deserialize)]
+struct Strings {
+    /// The text replacing unresolved references and other macros which can't be replaced.
+    unresolved: String,
+    /// The word introducing the environment another one derives from, e.g. "of" in "Corollary 2 (of Theorem 1)".
+    of: String,
+    /// The text introducing the chapters referring to an environment, e.g. "Referenced in:".
+    referenced_in: String,
+    /// The name of solutions, e.g. "Solution" in "Solution 2.3".
+    solution: String,
+}
+
+impl Default for Strings {
+    fn default() -> Self {
+        Strings {
+            unresolved: "**[??]**".to_string(),
+            of: "of".to_string(),
+            referenced_in: "Referenced in:".to_string(),
+            solution: "Solution".to_string(),
+        }
+    }
+}
+
+/// The form of links to environments of the same chapter.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum SamePageLinks {
+    /// Fragment-only links, e.g. `(#label)`.
+    #[default]
+    Fragment,
+    /// Links with the explicit file name, e.g. `(groups.md#label)`, which are robust to `<base href>`.
+    Explicit,
+}
+
+impl SamePageLinks {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "fragment" => Some(SamePageLinks::Fragment),
+            "explicit" => Some(SamePageLinks::Explicit),
+            _ => None,
+        }
+    }
+}
+
+/// The form of the path of a single reference, given by its `path` attribute, e.g. `{{ref path=site: label}}`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PathStyle {
+    /// The path relative to the chapter of the reference, e.g. `../groups.md`, as for other references.
+    Relative,
+    /// The absolute path of the page, e.g. `/algebra/groups.html`.
+    Absolute,
+    /// The URL of the page based on the `site-url` of the HTML renderer, e.g. `/book/algebra/groups.html`.
+    Site,
+    /// The path of the chapter from the root of the book, e.g. `algebra/groups.md`.
+    Root,
+}
+
+impl PathStyle {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "relative" => Some(PathStyle::Relative),
+            "absolute" => Some(PathStyle::Absolute),
+            "site" => Some(PathStyle::Site),
+            "root" => Some(PathStyle::Root),
+            _ => None,
+        }
+    }
+}
+
+/// How assets are made available to the rendered book.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum AssetInjection {
+    /// Assets are written to the source directory and linked from each chapter.
+    Auto,
+    /// Assets are set up by the user, e.g. with `additional-css` and `additional-js`.
+    #[default]
+    Manual,
+}
+
+impl AssetInjection {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "auto" => Some(AssetInjection::Auto),
+            "manual" => Some(AssetInjection::Manual),
+            _ => None,
+        }
+    }
+}
+
+/// The configuration of the CSS/JS assets.
+#[derive(Clone, Debug, Deserialize)]
+struct Assets {
+    /// The renderers for which assets are used.
+    renderers: Vec<String>,
+    /// How assets are made available.
+    inject: AssetInjection,
+    /// The directory of the assets, relative to the source directory.
+    dir: PathBuf,
+    /// Whether assets are used by the current renderer.
+    enabled: bool,
+}
+
+impl Default for Assets {
+    fn default() -> Self {
+        Assets {
+            renderers: vec!["html".to_string()],
+            inject: AssetInjection::default(),
+            dir: PathBuf::new(),
+            enabled: true,
+        }
+    }
+}
+
+/// Writes the builtin assets to `dir`, leaving files whose content is unchanged untouched
+/// so that `mdbook serve` does not rebuild the book in a loop.
+pub fn write_assets(dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    for (name, content) in ASSETS {
+        let path = dir.join(name);
+        if std::fs::read_to_string(&path).ok().as_deref() != Some(*content) {
+            std::fs::write(&path, content)?;
+        }
+    }
+    Ok(())
+}
+
+/// Searches the label database `database` exported by a previous build (see the `completions` option with the
+/// `json` format) for `query`, which must occur in the label, the numbered name, or the title of the results,
+/// ignoring case. Returns the matching results sorted by label, e.g.
+/// "thm:lagrange: Theorem 2.1 (Lagrange), algebra/groups.md".
+pub fn search_labels(database: &str, query: &str) -> Result<Vec<String>> {
+    let refs: BTreeMap<String, LabelInfo> = serde_json::from_str(database)?;
+    let query = query.to_lowercase();
+    Ok(refs
+        .iter()
+        .filter(|(label, info)| {
+            [
+                Some(label.as_str()),
+                Some(info.num_name.as_str()),
+                info.title.as_deref(),
+            ]
+            .into_iter()
+            .flatten()
+            .any(|field| field.to_lowercase().contains(&query))
+        })
+        .map(|(label, info)| {
+            let title = info
+                .title
+                .as_ref()
+                .map_or(String::new(), |title| format!(" ({title})"));
+            format!("{label}: {}{title}, {}", info.num_name, info.path.display())
+        })
+        .collect())
+}
+
+/// Checks the rendered HTML files of `dir` (e.g. the `book` directory), recursively: each link with a fragment
+/// towards a page of `dir` must point to an element of that page with the fragment as id or name, which may
+/// not be the case if another preprocessor or a theme strips or rewrites anchors.
+/// Returns the broken links, e.g. "groups.html: rings.html#thm:main", in the order of the files.
+pub fn check_anchors(dir: &Path) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    html_files(dir, &mut files)?;
+    files.sort();
+    let target: Regex = cached_regex(r#"\s(?:id|name)\s*=\s*(?:"(?P<dq>[^"]*)"|'(?P<sq>[^']*)')"#);
+    let link: Regex = cached_regex(r#"\shref\s*=\s*(?:"(?P<dq>[^"]*)"|'(?P<sq>[^']*)')"#);
+    let value = |caps: &regex::Captures| {
+        caps.name("dq")
+            .or_else(|| caps.name("sq"))
+            .map_or(String::new(), |m| m.as_str().to_string())
+    };
+
+    let mut pages: HashMap<PathBuf, (String, HashSet<String>)> = HashMap::new();
+    for file in files.iter() {
+        let content = std::fs::read_to_string(file)?;
+        let targets = target
+            .captures_iter(&content)
+            .map(|caps| value(&caps))
+            .collect();
+        pages.insert(file.clone(), (content, targets));
+    }
+
+    // links starting with a URL scheme (e.g. `https:`) or a host (`//`) are external
+    let external: Regex = cached_regex(r"^(?:[a-zA-Z][a-zA-Z0-9+.-]*:|//)");
+    let mut broken = Vec::new();
+    for file in files.iter() {
+        for caps in link.captures_iter(&pages[file].0) {
+            let href = value(&caps);
+            let Some((page, fragment)) = href.split_once('#') else {
+                continue;
+            };
+            // external links are not checked
+            if fragment.is_empty() || external.is_match(page) {
+                continue;
+            }
+            let page = page.split('?').next().unwrap_or_default();
+            let page = match page {
+                "" => file.clone(),
+                page if page.starts_with('/') => dir.join(page.trim_start_matches('/')),
+                page => file.parent().unwrap_or(dir).join(page),
+            };
+            let mut page = normalize_path(&page);
+            if page.is_dir() {
+                page = page.join("index.html");
+            }
+            let resolved = pages
+                .get(&page)
+                .is_some_and(|(_, targets)| targets.contains(&percent_decode(fragment)));
+            if !resolved {
+                let file = file.strip_prefix(dir).unwrap_or(file);
+                broken.push(format!("{}: {href}", file.display()));
+            }
+        }
+    }
+    Ok(broken)
+}
+
+/// Compares the processed markdown `current` of a chapter with its snapshot `baseline` line by line,
+/// and returns the removed and added lines with their line numbers in the snapshot and in the current output,
+/// e.g. "-3: **Theorem 1.2.**" and "+3: **Theorem 1.3.**", or nothing if they are identical.
+pub fn diff_snapshot(baseline: &str, current: &str) -> Vec<String> {
+    let old: Vec<&str> = baseline.lines().collect();
+    let new: Vec<&str> = current.lines().collect();
+    let mut matches = Vec::new();
+    common_lines(&old, &new, (0, 0), &mut matches);
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    // the lines between consecutive common lines are removed, then added
+    for (k, l) in matches.into_iter().chain([(old.len(), new.len())]) {
+        diff.extend((i..k).map(|i| format!("-{}: {}", i + 1, old[i])));
+        diff.extend((j..l).map(|j| format!("+{}: {}", j + 1, new[j])));
+        (i, j) = (k + 1, l + 1);
+    }
+    diff
+}
+
+/// Pushes the positions in `old` and `new`, shifted by `offset`, of the lines of a longest common subsequence
+/// of `old` and `new` to `matches`, in increasing order, using Hirschberg's algorithm so as to run in linear space.
+fn common_lines(
+    old: &[&str],
+    new: &[&str],
+    offset: (usize, usize),
+    matches: &mut Vec<(usize, usize)>,
+) {
+    if old.is_empty() || new.is_empty() {
+        return;
+    }
+    if old.len() == 1 {
+        if let Some(j) = new.iter().position(|line| *line == old[0]) {
+            matches.push((offset.0, offset.1 + j));
+        }
+        return;
+    }
+    // split `old` in halves, and `new` where the common subsequences of both halves are the longest
+    let mid = old.len() / 2;
+    let head = lcs_lengths(old[..mid].iter(), new.iter());
+    let tail = lcs_lengths(old[mid..].iter().rev(), new.iter().rev());
+    let split = (0..=new.len())
+        .max_by_key(|&j| (head[j] + tail[new.len() - j], std::cmp::Reverse(j)))
+        .unwrap_or(0);
+    common_lines(&old[..mid], &new[..split], offset, matches);
+    common_lines(
+        &old[mid..],
+        &new[split..],
+        (offset.0 + mid, offset.1 + split),
+        matches,
+    );
+}
+
+/// Returns the lengths of the longest common subsequences of `old` and the prefixes of `new`.
+fn lcs_lengths<'a>(
+    old: impl Iterator<Item = &'a &'a str>,
+    new: impl Iterator<Item = &'a &'a str> + Clone,
+) -> Vec<usize> {
+    let mut row = vec![0; new.clone().count() + 1];
+    for line in old {
+        // `diagonal` is the entry of the previous row left to the current one
+        let mut diagonal = 0;
+        for (j, other) in new.clone().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = match line == other {
+                true => diagonal + 1,
+                false => above.max(row[j]),
+            };
+            diagonal = above;
+        }
+    }
+    row
+}
+
+/// Collects the HTML files of `dir`, recursively, into `files`.
+fn html_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            html_files(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "html") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Translates the glob pattern `pattern` of a path into a regex matching the whole path:
+/// `**` matches any sequence of characters, `*` any sequence of characters but `/`, and `?` a single one.
+fn glob_regex(pattern: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// Removes the `.` and `..` components of `path`, without following symbolic links.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Decodes the percent-encoded bytes of the fragment `s` of a URL, e.g. "thm%3Amain" into "thm:main".
+fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = match tail {
+            [h, l, ..] if byte == b'%' => std::str::from_utf8(&[*h, *l])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// A preprocessor for automatically numbering theorems, lemmas, etc.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct NumThmPreprocessor {
+    /// The list of environments handled by the preprocessor.
+    environments: EnvMap,
+    /// Whether theorem numbers must be prefixed by the section number.
+    with_prefix: bool,
+    /// The scope of environment counters.
+    numbering_scope: NumberingScope,
+    /// The behavior of title references to labels without title.
+    tref_fallback: TrefFallback,
+    /// Whether to warn about title references to labels without title.
+    tref_warn_untitled: bool,
+    /// The replacement of references in image alt texts and in link texts and titles.
+    plain_refs: PlainRefs,
+    /// Whether to warn about plain markdown links pointing to labels.
+    lint_links: bool,
+    /// Whether LaTeX environments `\begin{theorem} ... \end{theorem}` and references `\Cref{label}` are
+    /// recognized in chapters, as their `{{thm}}` and `{{ref}}` counterparts.
+    latex_syntax: bool,
+    /// Whether processed chapters start with `PROCESSED_MARKER`, so as to be left as is when processed again.
+    processed_marker: bool,
+    /// Whether `run` leaves files as is, see `without_outputs`.
+    #[serde(skip)]
+    dry_run: bool,
+    /// Whether to warn about macros which look like environments but whose key is not configured.
+    lint_unknown_envs: bool,
+    /// Whether to warn about environments of the same type with identical titles.
+    warn_duplicate_titles: bool,
+    /// The titles which may be used by several environments of the same type, e.g. "Example".
+    duplicate_titles_ignore: HashSet<String>,
+    /// The glob patterns of the paths of chapters left as is, e.g. "appendix/*.md".
+    exclude: Vec<String>,
+    /// The glob patterns of the paths of the chapters which are processed, if not all, e.g. "chapters/**".
+    include: Vec<String>,
+    /// The names of the only macros which are recognized, e.g. "thm" and "ref", if restricted,
+    /// so that macros of other templating steps sharing the delimiters are left untouched.
+    macros: Option<HashSet<String>>,
+    /// The flavor of the generated markdown.
+    output: OutputFormat,
+    /// The placement of anchors of labeled environments in HTML output.
+    anchor_placement: AnchorPlacement,
+    /// The pieces of the headers of environments in order, if they differ from the default ones.
+    header_layout: Vec<HeaderPiece>,
+    /// The strategy used to resolve duplicate labels.
+    duplicate_labels: DuplicateLabels,
+    /// The path of the generated reference report chapter, if any.
+    reference_report: Option<PathBuf>,
+    /// The path of the label database of the last build without malformed macros, relative to the book root,
+    /// whose labels are used for the labels missing from chapters with malformed macros, if any.
+    label_cache: Option<PathBuf>,
+    /// The path of the completions file, relative to the book root, if any.
+    completions: Option<PathBuf>,
+    /// The format of the completions file.
+    completions_format: CompletionsFormat,
+    /// The path of the context file for custom renderers, relative to the build directory, if any.
+    context_file: Option<PathBuf>,
+    /// The path of the summary of reference integrity, relative to the build directory, if any.
+    integrity_file: Option<PathBuf>,
+    /// The path of the label database of a previous build to compare with, relative to the book root, if any.
+    diff_base: Option<PathBuf>,
+    /// The path of the report of changes since `diff_base`, relative to the book root.
+    diff_report: PathBuf,
+    /// The path of the redirect map of anchors changed since `diff_base`, relative to the book root, if any.
+    redirects: Option<PathBuf>,
+    /// The syntax of macros.
+    syntax: MacroSyntax,
+    /// The statement snippets, indexed by name.
+    snippets: HashMap<String, Snippet>,
+    /// The labels of other books, loaded from their label databases, with the base URL of their book.
+    #[serde(skip)]
+    external: HashMap<String, (LabelInfo, String)>,
+    /// Whether labels are private to their chapter unless exported.
+    private_labels: bool,
+    /// The configuration of the CSS/JS assets.
+    assets: Assets,
+    /// Whether environment numbers are isolated from the surrounding bidirectional text.
+    isolate_numbers: bool,
+    /// Whether to silence warnings about empty labels and titles, which are emitted by default.
+    quiet_empty: bool,
+    /// Whether to show labels next to headers, for proofreading.
+    draft: bool,
+    /// Whether to mark headers with the environment type.
+    mark_environments: bool,
+    /// Whether to list the chapters referring to each labeled environment beneath it.
+    backrefs: bool,
+    /// Whether the numbers of headers of labeled environments link to the environments themselves.
+    self_links: bool,
+    /// Whether references show a preview of the referenced environment on hover.
+    previews: bool,
+    /// Whether to recognize wiki-style links `[[label]]` as references.
+    wiki_links: bool,
+    /// Whether to replace bare mentions of labels in the text with references.
+    auto_link: bool,
+    /// Whether duplicate labels and unresolved references make the build fail.
+    strict: bool,
+    /// The environments counted in chapter titles, with the singular and plural templates of their counts,
+    /// e.g. ("exr", "{count} exercise", "{count} exercises").
+    sidebar_counts: Vec<(String, String, String)>,
+    /// The casing of environment names in each context.
+    name_case: NameCases,
+    /// The templates of the texts of references and list items.
+    templates: Templates,
+    /// The names of the fields which can be given as attributes of environments, e.g. `author` for
+    /// `{{thm author="Gauss"}}`, and used as variables of header templates, references, and list items.
+    fields: Vec<String>,
+    /// The language of the book, selecting translations of environment names and generated texts.
+    language: Option<String>,
+    /// The generated texts, in the language of the book.
+    strings: Strings,
+    /// Whether numbers are displayed without prefix in their chapter.
+    relative_numbers: bool,
+    /// The form of links to environments of the same chapter, for the current renderer.
+    same_page_links: SamePageLinks,
+    /// The URL at which the book is served, from the `site-url` option of the HTML renderer, ending with a slash.
+    site_url: String,
+    /// The markup of block environments.
+    blocks: Blocks,
+    /// The initial values of counters, indexed by counter key, for books continuing another one.
+    initial_counters: HashMap<String, u32>,
+    /// The number of parts preceding the book, for books continuing another one.
+    initial_part: u32,
+    /// The number added to the chapter number in prefixes, for books continuing another one.
+    chapter_offset: u32,
+    /// The source of chapter numbers in prefixes.
+    section_numbers: SectionNumbers,
+    /// The prefix of environments preceding the first heading, when prefixes are read from headings.
+    intro_environments: IntroEnvironments,
+    /// The prefix of environment numbers in front-matter chapters.
+    front_matter_prefix: FrontMatterPrefix,
+    /// The numbering of environments in other unnumbered chapters when numbers are prefixed.
+    unnumbered_chapters: UnnumberedChapters,
+    /// The prefix of environment numbers in unnumbered chapters with `unnumbered_chapters = "fallback"`.
+    fallback_prefix: String,
+    /// The name of the chapter after which top-level chapters are appendices, numbered with letters.
+    appendix_after: Option<String>,
+    /// The paths of the top-level chapters which are appendices, numbered with letters.
+    appendices: Vec<PathBuf>,
+    /// The letters of appendices, as numbers (e.g. 1 for "A"), indexed by the number of their chapter,
+    /// once appendices have been located in the book.
+    #[serde(skip)]
+    appendix_letters: Option<HashMap<u32, u32>>,
+    /// The labels defined in the book, which suffixed duplicate labels must differ from and wiki-style links
+    /// and mentions of labels may refer to, together with the chapter of their first definition and whether it
+    /// exports them, once they have been collected with `duplicate_labels = "suffix"`, `wiki_links`, or `auto_link`.
+    #[serde(skip)]
+    defined_labels: Option<HashMap<String, (PathBuf, bool)>>,
+    /// The labels of the other chapters of the book, taken from the label database of a previous build,
+    /// when a single chapter is processed (see `render_chapter`).
+    #[serde(skip)]
+    database_labels: Option<HashMap<String, LabelInfo>>,
+    /// The separator of the parts of composite numbers, if it differs from ".", e.g. "–" for "1–2–3".
+    number_separator: Option<String>,
+    /// The number of components of chapter numbers kept in prefixes, e.g. 1 for "3." instead of "3.2.1.".
+    prefix_depth: Option<usize>,
+    /// The number of heading levels below the chapter title (`##`, `###`, ...) whose numbers are added to prefixes.
+    prefix_headings: usize,
+    /// The maximum number of components of numbers, e.g. 3 for "1.2.3".
+    max_depth: Option<usize>,
+    /// The handling of numbers exceeding `max_depth`.
+    depth_overflow: DepthOverflow,
+    /// The hooks registered by library users.
+    #[serde(skip)]
+    hooks: HookList,
+}
+
+impl NumThmPreprocessor {
+    pub fn new(ctx: &PreprocessorContext) -> Self {
+        let mut config = Self::default();
+
+        let toml_config: &toml::value::Table = ctx.config.get_preprocessor("numthm").unwrap();
+
+        // Set language, which defaults to the language of the book.
+        config.language = toml_config
+            .get("language")
+            .and_then(toml::Value::as_str)
+            .map(String::from)
+            .or_else(|| ctx.config.book.language.clone());
+        let language = config.language.as_deref();
+
+        // Set use of prefix conf.
+        if let Some(b) = toml_config.get("prefix").and_then(toml::Value::as_bool) {
+            config.with_prefix = b;
+        }
+
+        // Set numbering scope.
+        if let Some(s) = toml_config
+            .get("numbering_scope")
+            .and_then(toml::Value::as_str)
+        {
+            match NumberingScope::parse(s) {
+                Some(scope) => config.numbering_scope = scope,
+                None => warn!("Unknown numbering scope `{s}', using `chapter' instead"),
+            }
+        }
+
+        // Set behavior of title references to labels without title.
+        if let Some(s) = toml_config
+            .get("tref_fallback")
+            .and_then(toml::Value::as_str)
+        {
+            match TrefFallback::parse(s) {
+                Some(fallback) => config.tref_fallback = fallback,
+                None => warn!("Unknown tref fallback `{s}', using `name' instead"),
+            }
+        }
+        if let Some(b) = toml_config
+            .get("tref_warn_untitled")
+            .and_then(toml::Value::as_bool)
+        {
+            config.tref_warn_untitled = b;
+        }
+
+        // Set replacement of references where links can't be nested.
+        if let Some(s) = toml_config.get("plain_refs").and_then(toml::Value::as_str) {
+            match PlainRefs::parse(s) {
+                Some(plain_refs) => config.plain_refs = plain_refs,
+                None => warn!("Unknown plain refs `{s}', using `text' instead"),
+            }
+        }
+
+        // Set output format, which defaults to LaTeX for LaTeX renderers.
+        if LATEX_RENDERERS.contains(&ctx.renderer.as_str()) {
+            config.output = OutputFormat::Latex;
+        }
+        if let Some(s) = toml_config.get("output").and_then(toml::Value::as_str) {
+            match OutputFormat::parse(s) {
+                Some(output) => config.output = output,
+                None => warn!("Unknown output format `{s}', using `html' instead"),
+            }
+        }
+
+        // Set placement of anchors.
+        if let Some(s) = toml_config
+            .get("anchor_placement")
+            .and_then(toml::Value::as_str)
+        {
+            match AnchorPlacement::parse(s) {
+                Some(placement) => config.anchor_placement = placement,
+                None => warn!("Unknown anchor placement `{s}', using `inline' instead"),
+            }
+        }
+
+        // Set layout of headers, where the anchor and the header can be moved but not left out.
+        if let Some(pieces) = toml_config
+            .get("header_layout")
+            .and_then(toml::Value::as_array)
+        {
+            let mut layout = Vec::new();
+            for s in pieces.iter().filter_map(toml::Value::as_str) {
+                match HeaderPiece::parse(s) {
+                    Some(piece) => layout.push(piece),
+                    None if ["name", "number", "name-number", "title"].contains(&s) => warn!(
+                        "Header piece `{s}' is part of `header', whose content is set by the `format' \
+                         of environments, ignoring it"
+                    ),
+                    None => warn!("Unknown header piece `{s}', ignoring it"),
+                }
+            }
+            if !layout.contains(&HeaderPiece::Anchor) {
+                layout.insert(0, HeaderPiece::Anchor);
+            }
+            if !layout.contains(&HeaderPiece::Header) {
+                let anchor = layout
+                    .iter()
+                    .position(|piece| *piece == HeaderPiece::Anchor);
+                layout.insert(anchor.unwrap() + 1, HeaderPiece::Header);
+            }
+            config.header_layout = layout;
+        }
+
+        // Set strategy for duplicate labels.
+        if let Some(s) = toml_config
+            .get("duplicate_labels")
+            .and_then(toml::Value::as_str)
+        {
+            match DuplicateLabels::parse(s) {
+                Some(strategy) => config.duplicate_labels = strategy,
+                None => warn!("Unknown duplicate label strategy `{s}', using `keep-first' instead"),
+            }
+        }
+
+        // Set path of the reference report.
+        if let Some(s) = toml_config
+            .get("reference_report")
+            .and_then(toml::Value::as_str)
+        {
+            config.reference_report = Some(PathBuf::from(s));
+        }
+
+        // Set path of the context file for custom renderers.
+        if let Some(s) = toml_config
+            .get("context_file")
+            .and_then(toml::Value::as_str)
+        {
+            config.context_file = Some(PathBuf::from(s));
+        }
+
+        // Set path of the summary of reference integrity, e.g. for badges.
+        if let Some(s) = toml_config
+            .get("integrity_file")
+            .and_then(toml::Value::as_str)
+        {
+            config.integrity_file = Some(PathBuf::from(s));
+        }
+
+        // Set path of the label cache.
+        if let Some(s) = toml_config.get("label_cache").and_then(toml::Value::as_str) {
+            config.label_cache = Some(PathBuf::from(s));
+        }
+
+        // Set path and format of the completions file.
+        if let Some(s) = toml_config.get("completions").and_then(toml::Value::as_str) {
+            config.completions = Some(PathBuf::from(s));
+        }
+        if let Some(s) = toml_config
+            .get("completions_format")
+            .and_then(toml::Value::as_str)
+        {
+            match CompletionsFormat::parse(s) {
+                Some(format) => config.completions_format = format,
+                None => warn!("Unknown completions format `{s}', using `json' instead"),
+            }
+        }
+
+        // Set paths of the label database to compare with and of the report of changes.
+        if let Some(s) = toml_config.get("diff_base").and_then(toml::Value::as_str) {
+            config.diff_base = Some(PathBuf::from(s));
+        }
+        config.diff_report = PathBuf::from(
+            toml_config
+                .get("diff_report")
+                .and_then(toml::Value::as_str)
+                .unwrap_or("numthm-diff.md"),
+        );
+        if let Some(s) = toml_config.get("redirects").and_then(toml::Value::as_str) {
+            if config.diff_base.is_none() {
+                warn!("`redirects' requires `diff_base', no redirect map will be written");
+            }
+            config.redirects = Some(PathBuf::from(s));
+        }
+
+        // Set macro delimiters.
+        if let Some(delimiters) = toml_config
+            .get("delimiters")
+            .and_then(toml::Value::as_array)
+        {
+            match delimiters.as_slice() {
+                [toml::Value::String(open), toml::Value::String(close)]
+                    if !open.is_empty() && !close.is_empty() =>
+                {
+                    config.syntax.open = open.clone();
+                    config.syntax.close = close.clone();
+                }
+                _ => {
+                    warn!("Delimiters must be a pair of non-empty strings, using the default ones")
+                }
+            }
+        }
+
+        // Set maximal length of labels and titles.
+        if let Some(n) = toml_config
+            .get("max_capture")
+            .and_then(toml::Value::as_integer)
+        {
+            let max = MacroSyntax::MAX_MAX_CAPTURE;
+            config.syntax.max_capture = n.clamp(1, max as i64) as usize;
+            if config.syntax.max_capture as i64 != n {
+                warn!(
+                    "`max_capture' must be between 1 and {max}, using {} instead",
+                    config.syntax.max_capture
+                );
+            }
+        }
+
+        // Set namespace requirement of macros.
+        if let Some(s) = toml_config.get("namespace").and_then(toml::Value::as_str) {
+            match Namespace::parse(s) {
+                Some(namespace) => config.syntax.namespace = namespace,
+                None => warn!("Unknown namespace requirement `{s}', using `off' instead"),
+            }
+        }
+
+        // Load statement snippets.
+        if let Some(s) = toml_config.get("snippets").and_then(toml::Value::as_str) {
+            let snippets_path = ctx.root.join(s);
+            match std::fs::read_to_string(&snippets_path)
+                .map_err(Error::from)
+                .and_then(|content| toml::from_str(&content).map_err(Error::from))
+            {
+                Ok(snippets) => config.snippets = snippets,
+                Err(e) => warn!(
+                    "Could not load snippets file `{}': {e}",
+                    snippets_path.display()
+                ),
+            }
+        }
+
+        // Load label databases of other books, whose labels are resolved if undefined in this book.
+        if let Some(books) = toml_config.get("external").and_then(toml::Value::as_array) {
+            for book in books {
+                let (Some(database), Some(url)) = (
+                    book.get("database").and_then(toml::Value::as_str),
+                    book.get("url").and_then(toml::Value::as_str),
+                ) else {
+                    warn!("External books must have a `database' and a `url', ignoring {book}");
+                    continue;
+                };
+                let database_path = ctx.root.join(database);
+                match std::fs::read_to_string(&database_path)
+                    .map_err(Error::from)
+                    .and_then(|content| {
+                        serde_json::from_str::<HashMap<String, LabelInfo>>(&content)
+                            .map_err(Error::from)
+                    }) {
+                    Ok(labels) => {
+                        let url = match url.ends_with('/') {
+                            true => url.to_string(),
+                            false => format!("{url}/"),
+                        };
+                        for (label, info) in labels {
+                            // books listed first take precedence
+                            config
+                                .external
+                                .entry(label)
+                                .or_insert_with(|| (info, url.clone()));
+                        }
+                    }
+                    Err(e) => warn!(
+                        "Could not load label database `{}': {e}",
+                        database_path.display()
+                    ),
+                }
+            }
+        }
+
+        // Set visibility of labels.
+        if let Some(b) = toml_config
+            .get("private_labels")
+            .and_then(toml::Value::as_bool)
+        {
+            config.private_labels = b;
+        }
+
+        // Get assets table
+        if let Some(assets) = toml_config.get("assets").and_then(toml::Value::as_table) {
+            if let Some(renderers) = assets.get("renderers").and_then(toml::Value::as_array) {
+                config.assets.renderers = renderers
+                    .iter()
+                    .filter_map(toml::Value::as_str)
+                    .map(String::from)
+                    .collect();
+            }
+            if let Some(s) = assets.get("inject").and_then(toml::Value::as_str) {
+                match AssetInjection::parse(s) {
+                    Some(inject) => config.assets.inject = inject,
+                    None => warn!("Unknown asset injection `{s}', using `manual' instead"),
+                }
+            }
+            if let Some(s) = assets.get("dir").and_then(toml::Value::as_str) {
+                config.assets.dir = PathBuf::from(s);
+            }
+        }
+        config.assets.enabled = config.assets.renderers.contains(&ctx.renderer);
+
+        // Set isolation of numbers, needed by right-to-left books.
+        config.isolate_numbers =
+            ctx.config.book.realized_text_direction() == TextDirection::RightToLeft;
+        if let Some(b) = toml_config
+            .get("isolate_numbers")
+            .and_then(toml::Value::as_bool)
+        {
+            config.isolate_numbers = b;
+        }
+
+        // Set warnings about empty labels and titles.
+        if let Some(b) = toml_config.get("warn_empty").and_then(toml::Value::as_bool) {
+            config.quiet_empty = !b;
+        }
+
+        // Set draft mode.
+        if let Some(b) = toml_config.get("draft").and_then(toml::Value::as_bool) {
+            config.draft = b;
+        }
+
+        // Set casing of environment names in each context.
+        if let Some(cases) = toml_config.get("name_case").and_then(toml::Value::as_table) {
+            for (context, value) in cases.iter() {
+                let case = match value.as_str().map(|s| (s, NameCase::parse(s))) {
+                    Some((_, Some(case))) => case,
+                    Some((s, None)) => {
+                        warn!("Unknown name case `{s}', using `as-is' instead");
+                        continue;
+                    }
+                    None => continue,
+                };
+                match context.as_str() {
+                    "header" => config.name_case.header = case,
+                    "ref" => config.name_case.reference = case,
+                    "list" => config.name_case.list = case,
+                    _ => warn!("Unknown name case context `{context}'"),
+                }
+            }
+        }
+
+        // Set templates of the texts of references and list items.
+        if let Some(templates) = toml_config.get("templates").and_then(toml::Value::as_table) {
+            for (context, value) in templates.iter() {
+                let Some(template) = value.as_str().map(String::from) else {
+                    continue;
+                };
+                match context.as_str() {
+                    "ref" => config.templates.reference = template,
+                    "pref" => config.templates.prose = template,
+                    "list" => config.templates.list = template,
+                    _ => warn!("Unknown template `{context}'"),
+                }
+            }
+        }
+
+        // Set fields given as attributes of environments.
+        if let Some(fields) = toml_config.get("fields").and_then(toml::Value::as_array) {
+            for field in fields.iter().filter_map(toml::Value::as_str) {
+                match RESERVED_FIELDS.contains(&field) {
+                    true => warn!("Field `{field}' is reserved, ignoring it"),
+                    false => config.fields.push(field.to_string()),
+                }
+            }
+        }
+
+        // Set translations of generated texts and templates for the language of the book.
+        if let Some(translations) = toml_config
+            .get("translations")
+            .and_then(|translations| translations.get(language?))
+            .and_then(toml::Value::as_table)
+        {
+            for (key, value) in translations.iter() {
+                let Some(text) = value.as_str().map(String::from) else {
+                    continue;
+                };
+                match key.as_str() {
+                    "unresolved" => config.strings.unresolved = text,
+                    "of" => config.strings.of = text,
+                    "referenced_in" => config.strings.referenced_in = text,
+                    "solution" => config.strings.solution = text,
+                    "ref" => config.templates.reference = text,
+                    "pref" => config.templates.prose = text,
+                    "list" => config.templates.list = text,
+                    _ => warn!("Unknown translation `{key}'"),
+                }
+            }
+        }
+
+        // Set display of chapter-relative numbers.
+        if let Some(b) = toml_config
+            .get("relative_numbers")
+            .and_then(toml::Value::as_bool)
+        {
+            config.relative_numbers = b;
+        }
+
+        // Set URL of the book, for references with `path=site`.
+        let site_url = ctx
+            .config
+            .get("output.html.site-url")
+            .and_then(toml::Value::as_str)
+            .unwrap_or("/");
+        config.site_url = match site_url.ends_with('/') {
+            true => site_url.to_string(),
+            false => format!("{site_url}/"),
+        };
+
+        // Set form of same-page links, either for all renderers or for each renderer.
+        let same_page_links = match toml_config.get("same_page_links") {
+            Some(toml::Value::Table(renderers)) => renderers.get(&ctx.renderer),
+            value => value,
+        };
+        if let Some(s) = same_page_links.and_then(toml::Value::as_str) {
+            match SamePageLinks::parse(s) {
+                Some(links) => config.same_page_links = links,
+                None => warn!("Unknown same-page link form `{s}', using `fragment' instead"),
+            }
+        }
+
+        // Set source of chapter numbers.
+        if let Some(s) = toml_config
+            .get("section_numbers")
+            .and_then(toml::Value::as_str)
+        {
+            match SectionNumbers::parse(s) {
+                Some(source) => config.section_numbers = source,
+                None => warn!("Unknown source of section numbers `{s}', using `summary' instead"),
+            }
+        }
+        if let Some(s) = toml_config
+            .get("intro_environments")
+            .and_then(toml::Value::as_str)
+        {
+            match IntroEnvironments::parse(s) {
+                Some(intro) => config.intro_environments = intro,
+                None => {
+                    warn!("Unknown handling of intro environments `{s}', using `section' instead")
+                }
+            }
+        }
+
+        // Set markup of block environments.
+        if let Some(s) = toml_config.get("block_class").and_then(toml::Value::as_str) {
+            config.blocks.class = s.to_string();
+        }
+        if let Some(s) = toml_config.get("qed_symbol").and_then(toml::Value::as_str) {
+            config.blocks.qed_symbol = s.to_string();
+        }
+
+        // Set prefix of front-matter chapters.
+        if let Some(s) = toml_config
+            .get("front_matter_prefix")
+            .and_then(toml::Value::as_str)
+        {
+            match FrontMatterPrefix::parse(s) {
+                Some(prefix) => config.front_matter_prefix = prefix,
+                None => warn!("Unknown front matter prefix `{s}', using `none' instead"),
+            }
+        }
+
+        // Set numbering of environments in other unnumbered chapters.
+        if let Some(s) = toml_config
+            .get("unnumbered_chapters")
+            .and_then(toml::Value::as_str)
+        {
+            match UnnumberedChapters::parse(s) {
+                Some(numbering) => config.unnumbered_chapters = numbering,
+                None => {
+                    warn!("Unknown numbering of unnumbered chapters `{s}', using `none' instead")
+                }
+            }
+        }
+        config.fallback_prefix = toml_config
+            .get("fallback_prefix")
+            .and_then(toml::Value::as_str)
+            .unwrap_or("0.")
+            .to_string();
+
+        // Set appendices, either all chapters after a given one or a list of chapters.
+        config.appendix_after = toml_config
+            .get("appendix_after")
+            .and_then(toml::Value::as_str)
+            .map(String::from);
+        if let Some(paths) = toml_config
+            .get("appendices")
+            .and_then(toml::Value::as_array)
+        {
+            config.appendices = paths
+                .iter()
+                .filter_map(toml::Value::as_str)
+                .map(PathBuf::from)
+                .collect();
+        }
+
+        // Set separator of composite numbers.
+        if let Some(s) = toml_config
+            .get("number_separator")
+            .and_then(toml::Value::as_str)
+        {
+            config.number_separator = Some(s.to_string());
+        }
+
+        // Set depth of chapter numbers in prefixes.
+        if let Some(depth) = toml_config
+            .get("prefix_depth")
+            .and_then(toml::Value::as_integer)
+        {
+            if depth > 0 {
+                config.prefix_depth = Some(depth as usize);
+            } else {
+                warn!("Invalid prefix depth `{depth}', should be positive");
+            }
+        }
+
+        // Set maximum depth of numbers.
+        if let Some(depth) = toml_config
+            .get("max_depth")
+            .and_then(toml::Value::as_integer)
+        {
+            if depth > 0 {
+                config.max_depth = Some(depth as usize);
+            } else {
+                warn!("Invalid maximum depth `{depth}', should be positive");
+            }
+        }
+        if let Some(s) = toml_config
+            .get("depth_overflow")
+            .and_then(toml::Value::as_str)
+        {
+            match DepthOverflow::parse(s) {
+                Some(overflow) => config.depth_overflow = overflow,
+                None => warn!("Unknown depth overflow `{s}', using `warn' instead"),
+            }
+        }
+
+        // Set heading levels numbered in prefixes.
+        if let Some(levels) = toml_config
+            .get("prefix_headings")
+            .and_then(toml::Value::as_integer)
+        {
+            if (0..=5).contains(&levels) {
+                config.prefix_headings = levels as usize;
+            } else {
+                warn!("Invalid number of prefix headings `{levels}', should be between 0 and 5");
+            }
+        }
+
+        // Set marking of headers with the environment type.
+        if let Some(b) = toml_config
+            .get("mark_environments")
+            .and_then(toml::Value::as_bool)
+        {
+            config.mark_environments = b;
+        }
+
+        // Set lists of referring chapters.
+        if let Some(b) = toml_config.get("backrefs").and_then(toml::Value::as_bool) {
+            config.backrefs = b;
+        }
+
+        // Set self-links of headers.
+        if let Some(b) = toml_config.get("self_links").and_then(toml::Value::as_bool) {
+            config.self_links = b;
+        }
+
+        // Set previews of referenced environments.
+        if let Some(b) = toml_config.get("previews").and_then(toml::Value::as_bool) {
+            config.previews = b;
+        }
+
+        // Set recognition of wiki-style links.
+        if let Some(b) = toml_config.get("wiki_links").and_then(toml::Value::as_bool) {
+            config.wiki_links = b;
+        }
+
+        // Set auto-linking of bare label mentions.
+        if let Some(b) = toml_config.get("auto_link").and_then(toml::Value::as_bool) {
+            config.auto_link = b;
+        }
+
+        // Set strict mode.
+        if let Some(b) = toml_config.get("strict").and_then(toml::Value::as_bool) {
+            config.strict = b;
+        }
+
+        // Set recognition of LaTeX environments and references.
+        if let Some(b) = toml_config
+            .get("latex_syntax")
+            .and_then(toml::Value::as_bool)
+        {
+            config.latex_syntax = b;
+        }
+
+        // Set marking of processed chapters.
+        if let Some(b) = toml_config
+            .get("processed_marker")
+            .and_then(toml::Value::as_bool)
+        {
+            config.processed_marker = b;
+        }
+
+        // Set linting of plain markdown links.
+        if let Some(b) = toml_config.get("lint_links").and_then(toml::Value::as_bool) {
+            config.lint_links = b;
+        }
+
+        // Set linting of unknown environments.
+        if let Some(b) = toml_config
+            .get("lint_unknown_envs")
+            .and_then(toml::Value::as_bool)
+        {
+            config.lint_unknown_envs = b;
+        }
+
+        // Set warnings about duplicate titles.
+        if let Some(b) = toml_config
+            .get("warn_duplicate_titles")
+            .and_then(toml::Value::as_bool)
+        {
+            config.warn_duplicate_titles = b;
+        }
+        if let Some(titles) = toml_config
+            .get("duplicate_titles_ignore")
+            .and_then(toml::Value::as_array)
+        {
+            config.duplicate_titles_ignore = titles
+                .iter()
+                .filter_map(toml::Value::as_str)
+                .map(String::from)
+                .collect();
+        }
+
+        // Set chapters which are not processed at all.
+        if let Some(patterns) = toml_config.get("exclude").and_then(toml::Value::as_array) {
+            config.exclude = patterns
+                .iter()
+                .filter_map(toml::Value::as_str)
+                .map(String::from)
+                .collect();
+        }
+        if let Some(patterns) = toml_config.get("include").and_then(toml::Value::as_array) {
+            config.include = patterns
+                .iter()
+                .filter_map(toml::Value::as_str)
+                .map(String::from)
+                .collect();
+        }
+
+        // Set macros which are recognized, all others being left as is.
+        if let Some(names) = toml_config.get("macros").and_then(toml::Value::as_array) {
+            config.macros = Some(
+                names
+                    .iter()
+                    .filter_map(toml::Value::as_str)
+                    .map(String::from)
+                    .collect(),
+            );
+        }
+
+        // Set padding of counters, which can be overridden for each environment.
+        let pad = toml_config
+            .get("pad")
+            .and_then(toml::Value::as_integer)
+            .map_or(0, |p| p.max(0) as usize);
+        for env in config.environments.values_mut() {
+            env.pad = pad;
+        }
+
+        // Get environments table
+        let envs = toml_config
+            .get("environments")
+            .and_then(toml::Value::as_table);
+        let families = toml_config.get("families").and_then(toml::Value::as_table);
+        // the entries of family members are applied after the settings of their family, which they override
+        let is_member = |key: &str| {
+            families.is_some_and(|families| {
+                families.values().any(|family| match family.get("members") {
+                    Some(toml::Value::Array(keys)) => keys.iter().any(|k| k.as_str() == Some(key)),
+                    Some(toml::Value::Table(names)) => names.contains_key(key),
+                    _ => false,
+                })
+            })
+        };
+        // environments extending another environment, in the order in which they are resolved
+        let mut extensions: Vec<(String, String)> = Vec::new();
+        // entries extending another environment are processed once their base is defined
+        let mut pending: Vec<(&String, &toml::value::Table, &str)> = Vec::new();
+        for (key, value) in envs.into_iter().flatten() {
+            // Update from entries, but only if data is available
+            if let Some(entry) = toml::Value::as_table(value) {
+                // Allow removal of entry
+                if let Some(ignore) = entry.get("ignore").and_then(toml::Value::as_bool) {
+                    if ignore {
+                        config.environments.remove(key);
+                        continue;
+                    }
+                }
+
+                if let Some(base) = entry.get("extends").and_then(toml::Value::as_str) {
+                    pending.push((key, entry, base));
+                    continue;
+                }
+
+                if is_member(key) {
+                    continue;
+                }
+
+                match config.environments.get_mut(key) {
+                    Some(env) => env.update(entry, &ctx.renderer, language),
+                    None => {
+                        let mut env = Env::create(&Env::name_default(), &Env::emph_default());
+                        env.pad = pad;
+                        env.update(entry, &ctx.renderer, language);
+                        config.environments.insert(String::from(key), env);
+                    }
+                }
+            }
+        }
+
+        // Get families table, defining environments with shared settings, which can be overridden
+        // for each member in the environments table
+        for (family, value) in families.into_iter().flatten() {
+            let Some(entry) = value.as_table() else {
+                warn!("Family `{family}' must be a table, ignoring it");
+                continue;
+            };
+            // members are given as an array of keys, or as a table mapping keys to names
+            let members: Vec<(&str, Option<&str>)> = match entry.get("members") {
+                Some(toml::Value::Array(keys)) => keys
+                    .iter()
+                    .filter_map(toml::Value::as_str)
+                    .map(|key| (key, None))
+                    .collect(),
+                Some(toml::Value::Table(names)) => names
+                    .iter()
+                    .map(|(key, name)| (key.as_str(), name.as_str()))
+                    .collect(),
+                _ => {
+                    warn!(
+                        "Family `{family}' must have an array or a table of members, ignoring it"
+                    );
+                    continue;
+                }
+            };
+            for (key, name) in members {
+                let own = envs
+                    .and_then(|envs| envs.get(key))
+                    .and_then(toml::Value::as_table)
+                    .filter(|own| !own.contains_key("extends"));
+                if own.is_some_and(|own| {
+                    own.get("ignore").and_then(toml::Value::as_bool) == Some(true)
+                }) {
+                    continue;
+                }
+                let env = match config.environments.get_mut(key) {
+                    Some(env) => env,
+                    None if name.is_some() || own.is_some() => {
+                        let mut env = Env::create(&Env::name_default(), &Env::emph_default());
+                        env.pad = pad;
+                        config.environments.entry(key.to_string()).or_insert(env)
+                    }
+                    None => {
+                        warn!("Family `{family}': unknown environment `{key}' without name");
+                        continue;
+                    }
+                };
+                // members share the counter of the family unless another one is given
+                env.counter = Some(family.clone());
+                env.update(entry, &ctx.renderer, language);
+                if let Some(name) = name {
+                    env.name = name.to_string();
+                }
+                if let Some(own) = own {
+                    env.update(own, &ctx.renderer, language);
+                }
+            }
+        }
+
+        while !pending.is_empty() {
+            let resolvable = pending
+                .iter()
+                .position(|(_, _, base)| !pending.iter().any(|(key, _, _)| key == base));
+            let Some(i) = resolvable else {
+                for (key, _, base) in pending {
+                    warn!("Environment `{key}' extends `{base}' cyclically, ignoring it");
+                }
+                break;
+            };
+            let (key, entry, base) = pending.remove(i);
+            match config.environments.get(base) {
+                Some(base_env) => {
+                    let mut env = base_env.clone();
+                    env.counter = None;
+                    env.update(entry, &ctx.renderer, language);
+                    config.environments.insert(key.clone(), env);
+                    extensions.push((key.clone(), base.to_string()));
+                }
+                None => warn!("Environment `{key}' extends unknown environment `{base}'"),
+            }
+        }
+
+        // Get counter groups table
+        if let Some(groups) = toml_config.get("counters").and_then(toml::Value::as_table) {
+            for (group, value) in groups.iter() {
+                let members = value
+                    .get("members")
+                    .and_then(toml::Value::as_array)
+                    .map_or(&[][..], Vec::as_slice);
+                // the name of a group is the key of its counter, which must not be the counter of equations,
+                // of a family, or of an environment outside of the group
+                let outsider = config.environments.contains_key(group)
+                    && !members.iter().any(|m| m.as_str() == Some(group));
+                if group == EQ_COUNTER
+                    || families.is_some_and(|families| families.contains_key(group))
+                    || outsider
+                {
+                    warn!(
+                        "Counter group `{group}' would share the counter of equations, a family \
+                         or an environment of the same name, ignoring it"
+                    );
+                    continue;
+                }
+                for member in members.iter().filter_map(toml::Value::as_str) {
+                    match config.environments.get_mut(member) {
+                        Some(env) => match &env.counter {
+                            Some(other) => warn!(
+                                "Environment `{member}' already belongs to counter group `{other}', \
+                                 ignoring its membership in group `{group}'"
+                            ),
+                            None => env.counter = Some(group.clone()),
+                        },
+                        None => warn!("Counter group `{group}': unknown environment `{member}'"),
+                    }
+                }
+            }
+        }
+
+        // Set counts of environments appended to chapter titles
+        if let Some(counts) = toml_config
+            .get("sidebar_counts")
+            .and_then(toml::Value::as_table)
+        {
+            for (key, value) in counts.iter() {
+                let templates = match value {
+                    toml::Value::String(t) => Some((t.clone(), t.clone())),
+                    toml::Value::Array(a) => match a.as_slice() {
+                        [toml::Value::String(singular), toml::Value::String(plural)] => {
+                            Some((singular.clone(), plural.clone()))
+                        }
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                match (config.environments.contains_key(key), templates) {
+                    (false, _) => warn!("Sidebar counts: unknown environment `{key}'"),
+                    (true, None) => warn!(
+                        "Sidebar counts of environment `{key}' must be a template \
+                         or an array of a singular and a plural template"
+                    ),
+                    (true, Some((singular, plural))) => {
+                        config.sidebar_counts.push((key.clone(), singular, plural))
+                    }
+                }
+            }
+        }
+
+        // Environments extending another one share its counter unless they belong to a counter group
+        for (key, base) in extensions.iter() {
+            let base_counter = config.environments[base].counter_key(base).to_string();
+            let env = config.environments.get_mut(key).unwrap();
+            if env.counter.is_none() {
+                env.counter = Some(base_counter);
+            }
+        }
+
+        // Environments numbered within another one must not be numbered within themselves
+        let keys: Vec<String> = config.environments.keys().cloned().collect();
+        for key in keys.iter() {
+            let Some(parent) = config.environments[key].within.clone() else {
+                continue;
+            };
+            // the chain of parents must end without coming back to an environment
+            let mut ancestor = Some(parent.clone());
+            for _ in 0..keys.len() {
+                match ancestor.as_deref() {
+                    Some(a) if a != key => {
+                        ancestor = config
+                            .environments
+                            .get(a)
+                            .and_then(|env| env.within.clone())
+                    }
+                    _ => break,
+                }
+            }
+            let error = match config.environments.get(&parent) {
+                None => Some("unknown environment"),
+                Some(_) if ancestor.is_some() => Some("cyclic numbering"),
+                Some(_) => None,
+            };
+            if let Some(error) = error {
+                warn!("Environment `{key}' numbered within `{parent}': {error}, ignoring it");
+                config.environments.get_mut(key).unwrap().within = None;
+            }
+        }
+
+        // Set initial state of counters and prefixes, which can also be set with environment variables
+        // such as `MDBOOK_PREPROCESSOR__NUMTHM__INITIAL_PART`, mdbook turning the key into `initial-part`
+        let continuation = |key: &str| {
+            toml_config
+                .get(key)
+                .or_else(|| toml_config.get(&key.replace('_', "-")))
+        };
+        if let Some(initial) = continuation("initial_counters").and_then(toml::Value::as_table) {
+            let counter_keys: HashSet<&str> = config
+                .environments
+                .iter()
+                .map(|(key, env)| env.counter_key(key))
+                .chain([EQ_COUNTER])
+                .collect();
+            for (key, value) in initial.iter() {
+                match value.as_integer().map(u32::try_from) {
+                    Some(Ok(n)) if counter_keys.contains(key.as_str()) => {
+                        config.initial_counters.insert(key.clone(), n);
+                    }
+                    Some(Ok(_)) => warn!("Initial value of unknown counter `{key}'"),
+                    Some(Err(_)) => warn!(
+                        "Initial value of counter `{key}' must be a non-negative integer at most {}",
+                        u32::MAX
+                    ),
+                    None => warn!("Initial value of counter `{key}' must be a non-negative integer"),
+                }
+            }
+        }
+        if let Some(n) = continuation("initial_part").and_then(toml::Value::as_integer) {
+            config.initial_part = n.max(0) as u32;
+        }
+        if let Some(n) = continuation("chapter_offset").and_then(toml::Value::as_integer) {
+            config.chapter_offset = n.max(0) as u32;
+        }
+
+        // Environments expect labels prefixed by their key unless configured otherwise
+        if let Some(true) = toml_config
+            .get("lint_label_prefixes")
+            .and_then(toml::Value::as_bool)
+        {
+            for (key, env) in config.environments.iter_mut() {
+                if env.label_prefix.is_none() {
+                    env.label_prefix = Some(format!("{key}:"));
+                }
+            }
+        }
+
+        config
+    }
+
+    /// Returns the numbered name `num_name` of an environment with key `key`, with the name (in any of its
+    /// languages) cased as `case`.
+    fn cased(&self, num_name: &str, key: &str, case: NameCase) -> String {
+        let Some(env) = self.environments.get(key) else {
+            return num_name.to_string();
+        };
+        std::iter::once(&env.name)
+            .chain(env.names.values())
+            .filter(|name| !name.is_empty())
+            .find_map(|name| {
+                num_name
+                    .strip_prefix(name.as_str())
+                    .map(|rest| case.apply(name) + rest)
+            })
+            .unwrap_or_else(|| num_name.to_string())
+    }
+
+    /// Returns the numbered name `num_name` of the environment described by `info` as displayed in references,
+    /// according to the reference style of the environment, i.e., with its name or reference name cased
+    /// as configured, e.g. "Thm. 1.2" for "Theorem 1.2", or with its number only, as forced by `number_only`.
+    fn ref_num_name(&self, num_name: &str, info: &LabelInfo, number_only: bool) -> String {
+        let Some(env) = self.environments.get(&info.env) else {
+            return num_name.to_string();
+        };
+        // an environment named in another language has no short name in that language
+        let (name, ref_name) = match &info.name {
+            Some(name) => (name.as_str(), name.as_str()),
+            None => (env.name.as_str(), env.ref_name()),
+        };
+        match num_name.strip_prefix(name) {
+            Some(rest) if !name.is_empty() => match (env.ref_style, number_only) {
+                (_, true) | (RefStyle::Number, _) => rest.trim_start().to_string(),
+                (RefStyle::Full, _) => self.name_case.reference.apply(name) + rest,
+                (RefStyle::Short, _) => self.name_case.reference.apply(ref_name) + rest,
+            },
+            _ => num_name.to_string(),
+        }
+    }
+
+    /// Returns the regex matching environments `{{key}}{label}[title]` as well as `{{selfnum}}` and `{{selfref}}`.
+    fn env_regex(&self) -> Regex {
+        let keys = self
+            .environments
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<&str>>()
+            .join("|");
+        let pattern = format!(
+            r"{}|{}(\{{(?P<label>{capture})\}})?(\[(?P<title>{capture})\])?",
+            self.syntax.pattern("(?P<selfref>selfnum|selfref)"),
+            self.syntax.pattern(&format!(
+                r"(?P<block>#)?(?P<key>{keys})(?P<star>\*)?(?:\s+(?P<attrs>{}))?",
+                self.syntax.capture()
+            )),
+            capture = self.syntax.capture()
+        );
+        // see https://regex101.com/ for an explanation of the regex "\{\{(?P<selfref>selfnum|selfref)\}\}|\{\{(?P<key>key1|key2)(?:\s+(?P<attrs>.*?))?\}\}(\{(?P<label>.*?)\})?(\[(?P<title>.*?)\])?"
+        // matches {{selfnum}}, {{selfref}}, and {{key attrs}}{label}[title] where attrs, {label}, and [title] are optional,
+        // as well as the beginning {{#key attrs}}{label}[title] of block environments, and their unnumbered variants
+        // {{key* attrs}}
+        cached_regex(&pattern)
+    }
+
+    /// Returns whether macros named `name` (an environment key or a macro name such as "ref") are processed,
+    /// i.e. whether the name is known and, if the `macros` option restricts them, listed.
+    fn is_recognized(&self, name: &str) -> bool {
+        (self.environments.contains_key(name) || MACRO_NAMES.contains(&name))
+            && match &self.macros {
+                Some(names) => names.contains(name),
+                None => true,
+            }
+    }
+
+    /// Finds all fragments of `s` starting with the opening delimiter, possibly escaped, up to the closing one
+    /// on the same line (or the opening delimiter alone if there is none), and returns their byte ranges
+    /// together with whether they are escaped and whether their name is recognized.
+    fn macro_fragments(&self, s: &str) -> Vec<(Range<usize>, bool, bool)> {
+        let syntax = &self.syntax;
+        let re: Regex = cached_regex(&format!(
+            r"(?P<escape>\\)?{}(?:\s*{}[#/]?(?P<name>[A-Za-z][\w-]*))?",
+            regex::escape(&syntax.open),
+            syntax.namespace_pattern()
+        ));
+        re.captures_iter(s)
+            .map(|caps| {
+                let m = caps.get(0).unwrap();
+                let line_end = s[m.end()..].find('\n').map_or(s.len(), |i| m.end() + i);
+                let end = s[m.end()..line_end]
+                    .find(&syntax.close)
+                    .map_or(m.end(), |i| m.end() + i + syntax.close.len());
+                let recognized = caps
+                    .name("name")
+                    .is_some_and(|name| self.is_recognized(name.as_str()));
+                (m.start()..end, caps.name("escape").is_some(), recognized)
+            })
+            .collect()
+    }
+
+    /// Checks the labels and references of the chapters of `book`, without processing them, and returns
+    /// the duplicate labels, labels whose prefix doesn't match the `label_prefix` of their environment,
+    /// unresolved references, and unused labels, in this order.
+    /// Statement snippets are expanded, but other preprocessors (e.g. `{{#include}}`) must have been run on `book`.
+    pub fn check(&self, book: &Book) -> Vec<CheckIssue> {
+        let is_error = |kind| match kind {
+            CheckKind::UnusedLabel => false,
+            CheckKind::DuplicateLabel => self.duplicate_labels != DuplicateLabels::Suffix,
+            CheckKind::UnresolvedRef | CheckKind::LabelPrefix => true,
+        };
+        if (self.wiki_links || self.auto_link) && self.defined_labels.is_none() {
+            // wiki-style links and mentions of labels are references once labels are known,
+            // as when processing the book
+            return self.with_defined_labels(book).check(book);
+        }
+        let mut definitions: Vec<(PathBuf, usize, String, String)> = Vec::new();
+        let mut exports: Vec<(String, PathBuf)> = Vec::new();
+        let mut occurrences: Vec<RefOccurrence> = Vec::new();
+        self.scan_labels(book, &mut definitions, &mut exports, Some(&mut occurrences));
+
+        let mut issues = Vec::new();
+        let mut first: HashMap<&str, (&PathBuf, usize)> = HashMap::new();
+        let mut defined = Vec::new();
+        // the labels given to duplicate labels with `duplicate_labels = "suffix"`
+        let mut renamed: HashSet<String> = HashSet::new();
+        let labels: HashSet<&str> = definitions
+            .iter()
+            .map(|(_, _, label, _)| label.as_str())
+            .collect();
+        for (path, line, label, key) in &definitions {
+            let mut issue = |kind, message| {
+                issues.push(CheckIssue {
+                    kind,
+                    error: is_error(kind),
+                    path: path.clone(),
+                    line: *line,
+                    label: label.clone(),
+                    message,
+                })
+            };
+            match first.get(label.as_str()) {
+                Some((first_path, first_line)) => {
+                    let mut message = format!(
+                        "Label `{label}' already defined at {}:{first_line}",
+                        first_path.display()
+                    );
+                    if self.duplicate_labels == DuplicateLabels::Suffix {
+                        let new_label =
+                            suffixed_label(label, |l| labels.contains(l) || renamed.contains(l));
+                        message += &format!(", renamed to `{new_label}'");
+                        renamed.insert(new_label);
+                    }
+                    issue(CheckKind::DuplicateLabel, message)
+                }
+                None => {
+                    first.insert(label, (path, *line));
+                    defined.push((path, *line, label));
+                }
+            }
+            // labels are expected to start with the `label_prefix` of their environment, if any
+            let expected = self
+                .environments
+                .get(key)
+                .and_then(|env| env.label_prefix.as_ref())
+                .filter(|prefix| !label.starts_with(prefix.as_str()));
+            if let Some(expected) = expected {
+                issue(
+                    CheckKind::LabelPrefix,
+                    format!(
+                        "Label `{label}' of a `{key}' environment should start with `{expected}'"
+                    ),
+                );
+            }
+        }
+        let exports: HashSet<(&str, &PathBuf)> = exports
+            .iter()
+            .map(|(label, path)| (label.as_str(), path))
+            .collect();
+        for occurrence in &occurrences {
+            let label = occurrence.label.as_str();
+            let message = match first.get(label) {
+                None if !self.external.contains_key(label) => {
+                    format!("Reference to undefined label `{label}'")
+                }
+                Some((path, _))
+                    if self.private_labels
+                        && *path != &occurrence.path
+                        && !exports.contains(&(label, path)) =>
+                {
+                    format!(
+                        "Reference to label `{label}' private to chapter `{}'",
+                        path.display()
+                    )
+                }
+                _ => continue,
+            };
+            issues.push(CheckIssue {
+                kind: CheckKind::UnresolvedRef,
+                error: is_error(CheckKind::UnresolvedRef),
+                path: occurrence.path.clone(),
+                line: occurrence.line,
+                label: occurrence.label.clone(),
+                message,
+            });
+        }
+        let referenced: HashSet<&str> = occurrences
+            .iter()
+            .map(|occurrence| occurrence.label.as_str())
+            .collect();
+        for (path, line, label) in defined {
+            if !referenced.contains(label.as_str()) {
+                issues.push(CheckIssue {
+                    kind: CheckKind::UnusedLabel,
+                    error: is_error(CheckKind::UnusedLabel),
+                    path: path.clone(),
+                    line,
+                    label: label.clone(),
+                    message: format!("Label `{label}' is never referenced"),
+                });
+            }
+        }
+        issues
+    }
+
+    /// Returns the pieces of the header of an environment in the order of `header_layout`, separated by spaces,
+    /// except after the anchor, which ends with a newline unless it is empty.
+    fn layout_header(&self, anchor: &str, header: &str, permalink: &str, label: &str) -> String {
+        let layout = match self.header_layout.is_empty() {
+            true => HeaderPiece::DEFAULT_LAYOUT,
+            false => &self.header_layout,
+        };
+        let mut output = String::new();
+        let mut separate = false;
+        for piece in layout {
+            let s = match piece {
+                HeaderPiece::Anchor => anchor,
+                HeaderPiece::Header => header,
+                HeaderPiece::Permalink => permalink,
+                HeaderPiece::Label => label,
+            };
+            if s.is_empty() {
+                continue;
+            }
+            if separate && *piece != HeaderPiece::Anchor {
+                output.push(' ');
+            }
+            output += s;
+            separate = *piece != HeaderPiece::Anchor;
+        }
+        output
+    }
+
+    /// Processes `book` and writes the processed chapters to `dest`, with the same paths as in the source
+    /// directory, so that the sources in `dest` render correctly without the preprocessor.
+    /// Included files are expanded first, as by `mdbook build`, and no other file is written.
+    pub fn bake(&self, ctx: &PreprocessorContext, book: Book, dest: &Path) -> Result<()> {
+        let book = LinkPreprocessor::new().run(ctx, book)?;
+        let baked_book = self.clone().without_outputs().run(ctx, book)?;
+        for item in baked_book.iter() {
+            if let BookItem::Chapter(chapter) = item {
+                if let Some(path) = &chapter.source_path {
+                    let path = dest.join(path);
+                    if let Some(parent) = path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    std::fs::write(path, &chapter.content)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the macro-like fragments of the chapters of `book` (outside code), without processing them,
+    /// e.g. "algebra/groups.md:3: `{{#if draft}}' left as is" or "algebra/groups.md:5: `{{thm}}' processed",
+    /// to check which ones are recognized (see the `macros` option).
+    pub fn macro_report(&self, book: &Book) -> Vec<String> {
+        let mut report = Vec::new();
+        for item in book.iter() {
+            let BookItem::Chapter(chapter) = item else {
+                continue;
+            };
+            let Some(path) = &chapter.path else {
+                continue;
+            };
+            if self.is_excluded(chapter) {
+                continue;
+            }
+            let s = &chapter.content;
+            let code = code_regions(s);
+            let breaks = line_breaks(s);
+            for (range, escaped, recognized) in self.macro_fragments(s) {
+                if code.iter().any(|region| region.contains(&range.start)) {
+                    continue;
+                }
+                report.push(format!(
+                    "{}:{}: `{}' {}",
+                    path.display(),
+                    line_number(&breaks, range.start),
+                    &s[range],
+                    if recognized && !escaped {
+                        "processed"
+                    } else {
+                        "left as is"
+                    }
+                ));
+            }
+        }
+        report
+    }
+
+    /// Renames label `old` to `new` in the sources `s` of a chapter, i.e., in environment headers,
+    /// references, and exports, using the same parsing as the preprocessor,
+    /// and returns the resulting string and the number of renamed occurrences.
+    pub fn rename_label(&self, s: &str, old: &str, new: &str) -> (String, usize) {
+        let mut spans: Vec<std::ops::Range<usize>> = Vec::new();
+        for re in [
+            self.env_regex(),
+            self.syntax.ref_regex(),
+            self.syntax.export_regex(),
+            self.syntax.eq_regex(),
+            self.syntax.restate_regex(),
+            self.syntax.solution_regex(),
+        ] {
+            for caps in re.captures_iter(s) {
+                if let Some(label) = caps.name("label") {
+                    if normalize_label(label.as_str()) == old {
+                        // surrounding spaces are kept
+                        let start = label.start() + label.len() - label.as_str().trim_start().len();
+                        let end = label.start() + label.as_str().trim_end().len();
+                        spans.push(start..end);
+                    }
+                }
+                if let Some(attrs) = caps.name("attrs") {
+                    for (name, value) in parse_attributes(attrs.as_str()) {
+                        if name == "of" && value.as_str() == old {
+                            spans.push(attrs.start() + value.start()..attrs.start() + value.end());
+                        }
+                    }
+                }
+            }
+        }
+        spans.sort_by_key(|span| span.start);
+
+        let mut output = String::with_capacity(s.len());
+        let mut last = 0;
+        for span in spans.iter() {
+            output.push_str(&s[last..span.start]);
+            output.push_str(new);
+            last = span.end;
+        }
+        output.push_str(&s[last..]);
+        (output, spans.len())
+    }
+
+    /// Returns the anchor of the environment with key `key` and label `label`, i.e., the slugified label
+    /// with its anchor prefix, or the label itself with LaTeX output.
+    /// Anchors of headers and targets of links are all built here, so that they always agree.
+    fn anchor(&self, key: &str, label: &str) -> String {
+        if self.output == OutputFormat::Latex {
+            return label.to_string();
+        }
+        match self.environments.get(key) {
+            Some(env) => slugify(&format!("{}{label}", env.anchor_prefix)),
+            None => slugify(label),
+        }
+    }
+
+    /// Returns the link with text `text` to the element with anchor `anchor` of the page at `rel_path`,
+    /// i.e., a markdown link, or a `\hyperref` with LaTeX output.
+    fn link(&self, text: &str, rel_path: &str, anchor: &str) -> String {
+        match self.output {
+            OutputFormat::Latex => format!("\\hyperref[{anchor}]{{{text}}}"),
+            _ => format!("[{text}]({rel_path}#{anchor})"),
+        }
+    }
+
+    /// Returns the URL of the environment with label `label` of another book published at `url`,
+    /// described by `info` in the label database of that book.
+    fn external_url(&self, url: &str, label: &str, info: &LabelInfo) -> String {
+        let page = info.path.with_extension("html");
+        // the anchor prefixes of the other book are assumed to be those of this book
+        format!(
+            "{url}{}#{}",
+            page.to_string_lossy().replace('\\', "/"),
+            self.anchor(&info.env, label)
+        )
+    }
+
+    /// Returns the path of the link from chapter `chap_path` to an environment of chapter `path_to_ref`
+    /// in the form `style`.
+    fn styled_path(&self, style: PathStyle, chap_path: &PathBuf, path_to_ref: &Path) -> String {
+        let page = path_to_ref
+            .with_extension("html")
+            .to_string_lossy()
+            .replace('\\', "/");
+        match style {
+            PathStyle::Relative => self.link_path(chap_path, &path_to_ref.to_path_buf()),
+            PathStyle::Absolute => format!("/{page}"),
+            PathStyle::Site => format!("{}{page}", self.site_url),
+            PathStyle::Root => path_to_ref.to_string_lossy().replace('\\', "/"),
+        }
+    }
+
+    /// Returns the path of the link from chapter `chap_path` to an environment of chapter `path_to_ref`,
+    /// relative to the former.
+    fn link_path(&self, chap_path: &PathBuf, path_to_ref: &PathBuf) -> String {
+        match self.same_page_links {
+            SamePageLinks::Explicit if chap_path == path_to_ref => chap_path
+                .file_name()
+                .map_or_else(String::new, |name| name.to_string_lossy().into_owned()),
+            _ => compute_rel_path(chap_path, path_to_ref),
+        }
+    }
+
+    /// Registers `hooks`, which are invoked for each environment and each reference.
+    pub fn with_hooks(mut self, hooks: Arc<dyn Hooks>) -> Self {
+        self.hooks.0.push(hooks);
+        self
+    }
+
+    /// Disables the files written by `run`, i.e., the label cache, the diff report and redirect map, and the
+    /// completions, context and integrity files, e.g. to compare the processed chapters with their snapshots.
+    pub fn without_outputs(mut self) -> Self {
+        self.dry_run = true;
+        self.diff_base = None;
+        self.completions = None;
+        self.context_file = None;
+        self.integrity_file = None;
+        self
+    }
+
+    /// Returns the marker starting processed chapters, if they are marked.
+    fn marker(&self) -> &'static str {
+        match self.processed_marker {
+            true => PROCESSED_MARKER,
+            false => "",
+        }
+    }
+
+    /// Returns whether `chapter` is excluded from processing, i.e. whether its path matches a pattern of `exclude`
+    /// (or none of `include`), or whether it was already processed.
+    fn is_excluded(&self, chapter: &Chapter) -> bool {
+        chapter.path.as_ref().is_some_and(|path| {
+            let path = path.to_string_lossy().replace('\\', "/");
+            let matches = |patterns: &[String]| {
+                patterns
+                    .iter()
+                    .any(|pattern| cached_regex(&glob_regex(pattern)).is_match(&path))
+            };
+            matches(&self.exclude) || (!self.include.is_empty() && !matches(&self.include))
+        }) || chapter.content.starts_with(PROCESSED_MARKER)
+    }
+
+    /// Collects the paths and contents of the chapters of `items` (and their sub-chapters) which are processed,
+    /// i.e. which are neither drafts nor excluded, in the order in which they appear in the summary.
+    fn processed_chapters<'a>(
+        &self,
+        items: &'a mut [BookItem],
+        chapters: &mut Vec<(&'a PathBuf, &'a mut String)>,
+    ) {
+        for item in items {
+            if let BookItem::Chapter(chapter) = item {
+                let processed = !chapter.is_draft_chapter() && !self.is_excluded(chapter);
+                let Chapter {
+                    content,
+                    path,
+                    sub_items,
+                    ..
+                } = chapter;
+                if let (true, Some(path)) = (processed, path) {
+                    chapters.push((path, content));
+                }
+                self.processed_chapters(sub_items, chapters);
+            }
+        }
+    }
+
+    /// Returns the number of `chapter` shifted by `chapter_offset`, if the chapter is numbered,
+    /// or the number of its first heading (outside code), depending on `section_numbers`.
+    fn chapter_number(&self, chapter: &Chapter) -> Option<SectionNumber> {
+        match self.section_numbers {
+            SectionNumbers::Summary => chapter.number.as_ref().map(|sn| {
+                let mut sn = sn.clone();
+                if let Some(first) = sn.first_mut() {
+                    *first += self.chapter_offset;
+                }
+                sn
+            }),
+            SectionNumbers::Headings => heading_number(&freeze_regions(&chapter.content, self).0),
+        }
+    }
+
+    /// Splits the content `s` of `chapter` into the parts numbered with the same prefix, together with that prefix,
+    /// `prefix` being the prefix of the chapter: with `intro_environments = "chapter"`, environments preceding
+    /// the first heading get the chapter part of the prefix read from headings.
+    fn split_intro<'a>(
+        &self,
+        chapter: &Chapter,
+        s: &'a str,
+        prefix: String,
+    ) -> Vec<(&'a str, String)> {
+        let intro_applies = self.with_prefix
+            && self.section_numbers == SectionNumbers::Headings
+            && self.intro_environments == IntroEnvironments::Chapter
+            && self.numbering_scope == NumberingScope::Chapter;
+        let intro_prefix = self
+            .chapter_number(chapter)
+            .and_then(|sn| {
+                sn.first()
+                    .map(|n| self.section_number(&SectionNumber(vec![*n])) + self.separator())
+            })
+            .filter(|_| intro_applies);
+        match (intro_prefix, heading_start(s)) {
+            (Some(intro_prefix), Some(start)) if start > 0 => {
+                vec![(&s[..start], intro_prefix), (&s[start..], prefix)]
+            }
+            _ => vec![(s, prefix)],
+        }
+    }
+
+    /// Splits the part `s` of a chapter with prefix `prefix` at its headings of the levels numbered in prefixes,
+    /// together with the prefix of each resulting part, e.g. "3.2." for the second `##` section of chapter 3.
+    /// Headings inside fenced code blocks are ignored, and the numbers of skipped levels are 1, e.g. "3.1.1." for a
+    /// `###` section before any `##` section.
+    fn split_sections<'a>(&self, s: &'a str, prefix: String) -> Vec<(&'a str, String)> {
+        if self.prefix_headings == 0
+            || prefix.is_empty()
+            || self.numbering_scope != NumberingScope::Chapter
+        {
+            return vec![(s, prefix)];
+        }
+        let heading: Regex = cached_regex(r"(?m)^#{2,6}[ \t]");
+        let code = code_regions(s);
+        let mut parts = Vec::new();
+        // the numbers of the current heading at each tracked level
+        let mut numbers: Vec<u32> = Vec::new();
+        let mut start = 0;
+        let mut current = prefix.clone();
+        for m in heading.find_iter(s) {
+            let pos = m.start();
+            if code.iter().any(|range| range.contains(&pos)) {
+                continue;
+            }
+            let level = m.as_str().trim_end().len() - 1;
+            if level > self.prefix_headings {
+                continue;
+            }
+            numbers.resize(level, 0);
+            for n in &mut numbers[..level - 1] {
+                *n = (*n).max(1);
+            }
+            numbers[level - 1] += 1;
+            parts.push((&s[start..pos], current));
+            current = prefix.clone()
+                + &numbers
+                    .iter()
+                    .map(u32::to_string)
+                    .collect::<Vec<String>>()
+                    .join(self.separator())
+                + self.separator();
+            start = pos;
+        }
+        parts.push((&s[start..], current));
+        parts
+    }
+
+    /// Returns the number made of the prefix `prefix` and of the number `ctr` of environment `name` within the
+    /// chapter, e.g. "1.2.3", applying the maximum depth of numbers.
+    fn limit_depth(&self, prefix: &str, ctr: &str, name: &str, path: &Path) -> String {
+        let number = format!("{prefix}{ctr}");
+        let Some(max_depth) = self.max_depth else {
+            return number;
+        };
+        let separator = self.separator();
+        let prefix: Vec<&str> = prefix.split_terminator(separator).collect();
+        let own: Vec<&str> = ctr.split(separator).collect();
+        let depth = prefix.len() + own.len();
+        if depth <= max_depth {
+            return number;
+        }
+        match self.depth_overflow {
+            DepthOverflow::Warn => {
+                warn!(
+                    "{}: {name} {number}: Number of depth {depth} exceeds the maximum depth {max_depth}",
+                    path.display()
+                );
+                number
+            }
+            DepthOverflow::Truncate => {
+                // the number within the chapter is kept as long as possible, so that numbers remain distinct
+                let own = &own[own.len().saturating_sub(max_depth)..];
+                let prefix = &prefix[..max_depth - own.len()];
+                [prefix, own].concat().join(separator)
+            }
+        }
+    }
+
+    /// Returns the separator of the parts of composite numbers, e.g. "." in "1.2.3".
+    fn separator(&self) -> &str {
+        self.number_separator.as_deref().unwrap_or(".")
+    }
+
+    /// Returns the section number `sn` with the configured separator and without trailing separator, e.g. "1.2",
+    /// or "A.2" if chapter 1 is the first appendix.
+    fn section_number(&self, sn: &SectionNumber) -> String {
+        let letter = sn
+            .first()
+            .and_then(|n| self.appendix_letters.as_ref()?.get(n))
+            .map(|letter| to_letters(*letter));
+        let skip = letter.is_some() as usize;
+        letter
+            .into_iter()
+            .chain(sn.iter().skip(skip).map(u32::to_string))
+            .collect::<Vec<String>>()
+            .join(self.separator())
+    }
+
+    /// Returns a copy of the configuration with the labels defined in `book`, see `defined_labels`.
+    fn with_defined_labels(&self, book: &Book) -> Self {
+        let mut definitions = Vec::new();
+        let mut exports = Vec::new();
+        self.scan_labels(book, &mut definitions, &mut exports, None);
+        let exports: HashSet<(String, PathBuf)> = exports.into_iter().collect();
+        let mut defined = HashMap::new();
+        for (label, info) in self.database_labels.iter().flatten() {
+            defined.insert(label.clone(), (info.path.clone(), info.exported));
+        }
+        for (path, _, label, _) in definitions {
+            // references point to the first definition of a label
+            let exported = exports.contains(&(label.clone(), path.clone()));
+            defined.entry(label).or_insert((path, exported));
+        }
+        let mut config = self.clone();
+        config.defined_labels = Some(defined);
+        config
+    }
+
+    /// Turns the wiki-style links, with `wiki_links`, and the mentions of labels, with `auto_link`, of the chapter `s`
+    /// at `path` into references, once the labels of the book are known (see `defined_labels`).
+    fn link_labels(&self, s: &str, path: &PathBuf) -> String {
+        let Some(defined) = &self.defined_labels else {
+            return s.to_string();
+        };
+        let mut output = s.to_string();
+        if self.wiki_links {
+            output = find_and_replace_wiki_links(&output, &self.syntax, |label| {
+                defined.contains_key(label)
+            });
+        }
+        if self.auto_link {
+            output = auto_link_labels(&output, path, self, |label| {
+                defined.get(label).is_some_and(|(label_path, exported)| {
+                    !self.private_labels || *exported || label_path == path
+                })
+            });
+        }
+        output
+    }
+
+    /// Collects the definitions of labels in `book`, as tuples of the path of their chapter, their line,
+    /// the label and the key of their environment, into `definitions`, the exported labels together with the path
+    /// of the chapter exporting them into `exports`, and the references into `occurrences` if given.
+    /// Snippets are expanded, wiki-style links are references once labels are known, and code and math are skipped,
+    /// as when processing the book.
+    fn scan_labels(
+        &self,
+        book: &Book,
+        definitions: &mut Vec<(PathBuf, usize, String, String)>,
+        exports: &mut Vec<(String, PathBuf)>,
+        mut occurrences: Option<&mut Vec<RefOccurrence>>,
+    ) {
+        let mut instantiated = HashSet::new();
+        for item in book.iter() {
+            let BookItem::Chapter(chapter) = item else {
+                continue;
+            };
+            let Some(path) = &chapter.path else {
+                continue;
+            };
+            if self.is_excluded(chapter) {
+                continue;
+            }
+            let (mut content, mut frozen) = freeze_regions(&chapter.content, self);
+            if !self.snippets.is_empty() {
+                content = expand_snippets(&content, path, self, &mut instantiated, &mut frozen);
+            }
+            let content = self.link_labels(&content, path);
+            for (line, label, key) in find_labels(&content, self) {
+                definitions.push((path.clone(), line, label, key));
+            }
+            let (_, exported) = find_and_remove_exports(&content, &self.syntax);
+            exports.extend(exported.into_iter().map(|label| (label, path.clone())));
+            if let Some(occurrences) = occurrences.as_mut() {
+                for (line, label) in find_refs(&content, &self.syntax) {
+                    occurrences.push(RefOccurrence {
+                        path: path.clone(),
+                        line,
+                        label,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Locates the appendices in `book` and returns their letters as numbers (e.g. 1 for "A"),
+    /// indexed by the number of their chapter.
+    fn find_appendices(&self, book: &Book) -> HashMap<u32, u32> {
+        let mut letters = HashMap::new();
+        let mut after = false;
+        for item in book.sections.iter() {
+            let BookItem::Chapter(chapter) = item else {
+                continue;
+            };
+            let listed = chapter
+                .path
+                .as_ref()
+                .is_some_and(|path| self.appendices.contains(path));
+            if after || listed {
+                if let Some(&n) = self
+                    .chapter_number(chapter)
+                    .as_ref()
+                    .and_then(|sn| sn.first())
+                {
+                    letters.insert(n, letters.len() as u32 + 1);
+                }
+            }
+            if self.appendix_after.as_ref() == Some(&chapter.name) {
+                after = true;
+            }
+        }
+        if let Some(name) = self.appendix_after.as_ref().filter(|_| !after) {
+            warn!(
+                "Chapter `{name}' given by `appendix_after' not found among the top-level chapters"
+            );
+        }
+        letters
+    }
+
+    /// Returns the current number of the parent of the environment with key `key` among `counters`, without prefix,
+    /// e.g. "2" (or "2.1" if the parent is itself numbered within another environment), if the environment is
+    /// numbered within another one, together with whether the outermost parent is numbered across the book.
+    fn parent_number(&self, key: &str, counters: &HashMap<String, u32>) -> Option<(String, bool)> {
+        let parent_key = self.environments.get(key)?.within.as_deref()?;
+        let parent = &self.environments[parent_key];
+        let ctr = counters
+            .get(parent.counter_key(parent_key))
+            .copied()
+            .unwrap_or(0);
+        let ctr = parent.numbering.apply(ctr, parent.pad);
+        Some(match self.parent_number(parent_key, counters) {
+            Some((number, book_wide)) => (format!("{number}{}{ctr}", self.separator()), book_wide),
+            None => (ctr, parent.book_wide),
+        })
+    }
+
+    /// Resets the counters among `counters` of the environments numbered within an environment whose counter
+    /// `counter_key` was incremented, and recursively those of their own children.
+    fn reset_children(&self, counter_key: &str, counters: &mut HashMap<String, u32>) {
+        for (key, env) in self.environments.iter() {
+            let Some(parent) = env.within.as_deref() else {
+                continue;
+            };
+            if self.environments[parent].counter_key(parent) == counter_key
+                && counters.remove(env.counter_key(key)).is_some()
+            {
+                self.reset_children(env.counter_key(key), counters);
+            }
+        }
+    }
+
+    /// Returns the prefix of environment numbers in `chapter` when counters are reset in each chapter.
+    fn chapter_prefix(&self, chapter: &Chapter) -> String {
+        match self.chapter_number(chapter) {
+            Some(mut sn) if self.with_prefix => {
+                if let Some(depth) = self.prefix_depth {
+                    sn.truncate(depth);
+                }
+                self.section_number(&sn) + self.separator()
+            }
+            None if self.with_prefix
+                && self.unnumbered_chapters == UnnumberedChapters::Fallback =>
+            {
+                self.fallback_prefix.clone()
+            }
+            _ => String::new(),
+        }
+    }
+
+    /// Returns a copy of the configuration where no environment is numbered.
+    fn without_numbers(&self) -> Self {
+        let mut config = self.clone();
+        for env in config.environments.values_mut() {
+            env.numbered = false;
+        }
+        config
+    }
+
+    /// Returns the path of the chapter containing the solution `{{solution: label}}` of each label of `book`,
+    /// outside of frozen regions, warning about labels with several solutions.
+    fn find_solutions(&self, book: &Book) -> HashMap<String, PathBuf> {
+        let re: Regex = self.syntax.solution_regex();
+        let mut solutions: HashMap<String, PathBuf> = HashMap::new();
+        for item in book.iter() {
+            let BookItem::Chapter(chapter) = item else {
+                continue;
+            };
+            let Some(path) = chapter.path.as_ref().filter(|_| !self.is_excluded(chapter)) else {
+                continue;
+            };
+            let (content, _) = freeze_regions(&chapter.content, self);
+            for caps in re.captures_iter(&content) {
+                let label = normalize_label(&caps["label"]);
+                match solutions.get(&label) {
+                    Some(first) => warn!(
+                        "{}: Solution of `{label}' already given in `{}'",
+                        path.display(),
+                        first.display()
+                    ),
+                    None => {
+                        solutions.insert(label, path.clone());
+                    }
+                }
+            }
+        }
+        solutions
+    }
+
+    /// Returns whether references show previews, which require the assets of the HTML renderer.
+    fn with_previews(&self) -> bool {
+        self.previews && self.assets.enabled && self.output == OutputFormat::Html
+    }
+
+    /// Returns the number of the environment described by `info`, e.g. "1.2" for "Theorem 1.2",
+    /// or its numbered name if it isn't made of the name of the environment, e.g. "(3)" for equations.
+    fn number<'a>(&self, info: &'a LabelInfo) -> &'a str {
+        self.environments
+            .get(&info.env)
+            .and_then(|env| {
+                let name = info.name.as_deref().unwrap_or(&env.name);
+                info.num_name.strip_prefix(name)
+            })
+            .map_or(info.num_name.as_str(), str::trim)
+    }
+
+    /// Returns the title and number of `chapter`.
+    fn chapter_info(&self, chapter: &Chapter) -> ChapterInfo {
+        ChapterInfo {
+            title: chapter.name.clone(),
+            number: self
+                .chapter_number(chapter)
+                .map(|sn| self.section_number(&sn)),
+        }
+    }
+
+    /// Records the title and number of `chapter` in its environments `new_entries` and in their labels in `refs`.
+    fn record_chapter(
+        &self,
+        chapter: &Chapter,
+        new_entries: &mut [EnvEntry],
+        refs: &mut HashMap<String, LabelInfo>,
+    ) {
+        let info = self.chapter_info(chapter);
+        for entry in new_entries.iter_mut() {
+            entry.chapter = Some(info.clone());
+            if let Some(label_info) = entry.label.as_ref().and_then(|label| refs.get_mut(label)) {
+                if label_info.path == entry.path {
+                    label_info.chapter = Some(info.clone());
+                }
+            }
+        }
+    }
+
+    /// Returns the name `name` of a chapter with the counts of the environments of `sidebar_counts`
+    /// among its environments `entries` appended, e.g. "Groups (12 exercises)", which shows in the sidebar.
+    fn counted_name(&self, name: &str, entries: &[EnvEntry]) -> String {
+        let counts: Vec<String> = self
+            .sidebar_counts
+            .iter()
+            .filter_map(|(key, singular, plural)| {
+                let count = entries
+                    .iter()
+                    .filter(|entry| entry.numbered && &entry.env == key)
+                    .count();
+                let template = match count {
+                    0 => return None,
+                    1 => singular,
+                    _ => plural,
+                };
+                Some(template.replace("{count}", &count.to_string()))
+            })
+            .collect();
+        match counts.is_empty() {
+            true => name.to_string(),
+            false => format!("{name} ({})", counts.join(", ")),
+        }
+    }
+
+    /// Warns about the malformed macros and the unknown environments of `s`, the content of chapter `path`,
+    /// and returns whether there are malformed macros.
+    fn lint_macros(&self, s: &str, path: &Path) -> bool {
+        let malformed = find_malformed_macros(s, self);
+        for (line, fragment) in malformed.iter() {
+            warn!(
+                "{}:{line}: Possible malformed numthm macro `{fragment}'",
+                path.display()
+            );
+        }
+        if self.lint_unknown_envs {
+            for (line, key) in find_unknown_environments(s, self) {
+                warn!("{}:{line}: Unknown environment `{key}'", path.display());
+            }
+        }
+        !malformed.is_empty()
+    }
+
+    /// Processes a single chapter, resolving references to other chapters with the label database `database`
+    /// exported by a previous build (see the `completions` option with the `json` format).
+    /// This allows re-rendering a modified chapter without processing the whole book.
+    /// The chapter is processed as `run` does, as if it were the only chapter of the book, hence counters
+    /// start from their initial values, and no file is written.
+    pub fn render_chapter(
+        &self,
+        ctx: &PreprocessorContext,
+        chapter: &Chapter,
+        database: &str,
+    ) -> Result<String> {
+        let mut refs: HashMap<String, LabelInfo> = serde_json::from_str(database)?;
+        let path = chapter
+            .path
+            .as_ref()
+            .ok_or_else(|| Error::msg(format!("Chapter `{}' is a draft", chapter.name)))?;
+        // labels of the chapter are registered again as they may have changed
+        refs.retain(|_, info| &info.path != path);
+        let mut config = self.clone().without_outputs();
+        config.database_labels = Some(refs);
+        config.reference_report = None;
+        let mut book = Book::new();
+        book.push_item(Chapter {
+            sub_items: Vec::new(),
+            ..chapter.clone()
+        });
+        let book = config.run(ctx, book)?;
+        match book.sections.into_iter().next() {
+            Some(BookItem::Chapter(chapter)) => Ok(chapter.content),
+            _ => unreachable!("the book has a single chapter"),
+        }
+    }
+}
+
+impl Preprocessor for NumThmPreprocessor {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
+        if (self.appendix_after.is_some() || !self.appendices.is_empty())
+            && self.appendix_letters.is_none()
+        {
+            // appendices are located first, as their letters replace the numbers of their chapters
+            let mut config = self.clone();
+            config.appendix_letters = Some(self.find_appendices(&book));
+            return config.run(ctx, book);
+        }
+        if (self.duplicate_labels == DuplicateLabels::Suffix || self.wiki_links || self.auto_link)
+            && self.defined_labels.is_none()
+        {
+            // labels are collected first, so that duplicate labels are not renamed to labels defined later,
+            // and wiki-style links and mentions of labels defined later are recognized
+            return self.with_defined_labels(&book).run(ctx, book);
+        }
+        // a hashmap mapping labels to `LabelInfo` structs, starting from the labels of the label database
+        // when a single chapter is processed
+        let mut refs: HashMap<String, LabelInfo> = self.database_labels.clone().unwrap_or_default();
+        // the environment counters, reset according to the numbering scope
+        let mut counters: HashMap<String, u32> = self.initial_counters.clone();
+        // whether counters still have their initial values, in which case they are not reset
+        let mut pristine = true;
+        // the number of the current part, `initial_part` before the first part title
+        let mut part: u32 = self.initial_part;
+        // the number of front-matter chapters processed so far
+        let mut front_matter: u32 = 0;
+        // all references of the book, only collected for the reference report, the integrity file, and in strict mode
+        let mut occurrences: Vec<RefOccurrence> = Vec::new();
+        // the chapters with malformed macros, whose labels may be missing
+        let mut broken_chapters: HashSet<PathBuf> = HashSet::new();
+        // the title and number of each chapter, only collected with `backrefs`
+        let mut chapter_infos: HashMap<PathBuf, ChapterInfo> = HashMap::new();
+        // the location of the first definition of each label, only collected in strict mode
+        let mut definitions: HashMap<String, (PathBuf, usize)> = HashMap::new();
+        // the duplicate labels and unresolved references, with `strict = true`
+        let mut strict_errors: Vec<String> = Vec::new();
+        // the number of the last numbered chapter, to check that chapters are processed in order
+        let mut last_number: Option<SectionNumber> = None;
+        // the prefix of the last chapter, with `numbering_scope = "chapter"`
+        let mut last_prefix: Option<String> = None;
+        // the names of snippets which have already been instantiated
+        let mut instantiated: HashSet<String> = HashSet::new();
+        // the exported labels together with the path of the chapter exporting them
+        let mut exports: Vec<(String, PathBuf)> = Vec::new();
+        // all numbered environments of the book, in the order in which they appear
+        let mut entries: Vec<EnvEntry> = Vec::new();
+        // the bodies of labeled block environments, which are repeated when restated
+        let mut bodies: HashMap<String, BlockBody> = HashMap::new();
+        // the frozen regions of each chapter, restored once all macros have been replaced
+        let mut frozen_regions: HashMap<PathBuf, Vec<String>> = HashMap::new();
+        // the counters of unnumbered chapters with `unnumbered_chapters = "global"`, which are never reset
+        let mut global_counters: HashMap<String, u32> = HashMap::new();
+        // the configuration used in unnumbered chapters with `unnumbered_chapters = "skip"`
+        let skip_config =
+            (self.unnumbered_chapters == UnnumberedChapters::Skip).then(|| self.without_numbers());
+        // the unnumbered chapters, with `unnumbered_chapters = "error"`
+        let mut unnumbered_errors: Vec<String> = Vec::new();
+        // the counters of book-wide environments, which are never reset
+        let book_counters: HashSet<&str> = self
+            .environments
+            .iter()
+            .filter(|(_, env)| env.book_wide)
+            .map(|(key, env)| env.counter_key(key))
+            .collect();
+        // the chapter of the solution of each environment, located beforehand so that environments can link
+        // to the solutions of later chapters
+        let solutions: HashMap<String, PathBuf> = self.find_solutions(&book);
+
+        // environments must be numbered in the order in which chapters appear in the summary,
+        // hence we don't use `Book::for_each_mut` which visits sub-chapters before their parent
+        for_each_item_in_order(&mut book.sections, &mut |item: &mut BookItem| match item {
+            BookItem::PartTitle(_) => {
+                part += 1;
+                if self.numbering_scope == NumberingScope::Part && !pristine {
+                    counters.retain(|key, _| book_counters.contains(key.as_str()));
+                }
+            }
+            BookItem::Chapter(chapter)
+                if !chapter.is_draft_chapter() && !self.is_excluded(chapter) =>
+            {
+                if let Some(number) = &chapter.number {
+                    if let Some(last) = &last_number {
+                        if !chapter_follows(last, number) {
+                            warn!(
+                                "Chapter {number} {} is processed after chapter {last}, \
+                                 environments are numbered in the order in which chapters are processed",
+                                chapter.name
+                            );
+                        }
+                    }
+                    last_number = Some(number.clone());
+                }
+                // the numbering of the chapter if it is unnumbered while numbers are prefixed
+                let mut unnumbered = None;
+                let prefix = match self.numbering_scope {
+                    NumberingScope::Chapter => {
+                        // unnumbered chapters before the first numbered one form the front matter
+                        let in_front_matter = chapter.number.is_none() && last_number.is_none();
+                        if in_front_matter {
+                            front_matter += 1;
+                        }
+                        let prefix = match self.front_matter_prefix {
+                            FrontMatterPrefix::Roman if self.with_prefix && in_front_matter => {
+                                to_roman(front_matter).to_lowercase() + self.separator()
+                            }
+                            _ => {
+                                if self.with_prefix && self.chapter_number(chapter).is_none() {
+                                    unnumbered = Some(self.unnumbered_chapters);
+                                }
+                                self.chapter_prefix(chapter)
+                            }
+                        };
+                        // chapters sharing a prefix truncated by `prefix_depth` also share counters
+                        let truncated = self.prefix_depth.is_some_and(|depth| {
+                            self.chapter_number(chapter)
+                                .is_some_and(|sn| sn.len() > depth)
+                        });
+                        let shared =
+                            self.with_prefix && truncated && last_prefix.as_ref() == Some(&prefix);
+                        if !pristine && !shared {
+                            counters.retain(|key, _| book_counters.contains(key.as_str()));
+                        }
+                        last_prefix = Some(prefix.clone());
+                        prefix
+                    }
+                    NumberingScope::Part => {
+                        if part > 0 {
+                            to_roman(part) + self.separator()
+                        } else {
+                            String::new()
+                        }
+                    }
+                    NumberingScope::Book => String::new(),
+                };
+                pristine = false;
+                // one can safely unwrap chapter.path which must be Some(...)
+                let path = chapter.path.as_ref().unwrap();
+                let (content, mut frozen) = freeze_regions(&chapter.content, self);
+                chapter.content = content;
+                if !self.snippets.is_empty() {
+                    // snippets are expanded before labels and references are located
+                    chapter.content = expand_snippets(
+                        &chapter.content,
+                        path,
+                        self,
+                        &mut instantiated,
+                        &mut frozen,
+                    );
+                }
+                frozen_regions.insert(path.clone(), frozen);
+                // wiki-style links and mentions of labels are turned into references before references are located
+                chapter.content = self.link_labels(&chapter.content, path);
+                if self.lint_macros(&chapter.content, path) {
+                    broken_chapters.insert(path.clone());
+                }
+                if self.strict {
+                    // labels are located before the content is modified
+                    for (line, label, _) in find_labels(&chapter.content, self) {
+                        match definitions.get(&label) {
+                            Some((first_path, first_line)) => strict_errors.push(format!(
+                                "{}:{line}: Label `{label}' already defined at {}:{first_line}",
+                                path.display(),
+                                first_path.display()
+                            )),
+                            None => {
+                                definitions.insert(label, (path.clone(), line));
+                            }
+                        }
+                    }
+                }
+                if self.reference_report.is_some()
+                    || self.integrity_file.is_some()
+                    || self.strict
+                    || self.backrefs
+                {
+                    // references are located before the content is modified
+                    for (line, label) in find_refs(&chapter.content, &self.syntax) {
+                        occurrences.push(RefOccurrence {
+                            path: path.clone(),
+                            line,
+                            label,
+                        });
+                    }
+                }
+                let (content, exported) = find_and_remove_exports(&chapter.content, &self.syntax);
+                exports.extend(exported.into_iter().map(|label| (label, path.clone())));
+                let content = match self.backrefs && self.output != OutputFormat::Latex {
+                    true => {
+                        chapter_infos.insert(path.clone(), self.chapter_info(chapter));
+                        insert_end_markers(&content, self, BACKREF_MARKERS, |_| true)
+                    }
+                    false => content,
+                };
+                let content = match solutions.is_empty() {
+                    true => content,
+                    false => insert_end_markers(&content, self, SOLUTION_MARKERS, |label| {
+                        solutions.contains_key(label)
+                    }),
+                };
+                if unnumbered == Some(UnnumberedChapters::Error) {
+                    unnumbered_errors.push(format!(
+                        "{}: Chapter `{}' has no number while environment numbers are prefixed",
+                        path.display(),
+                        chapter.name
+                    ));
+                }
+                let counters = match unnumbered {
+                    Some(UnnumberedChapters::Global) => &mut global_counters,
+                    _ => &mut counters,
+                };
+                let config = match (unnumbered, &skip_config) {
+                    (Some(UnnumberedChapters::Skip), Some(skip_config)) => skip_config,
+                    _ => self,
+                };
+                let content = mark_block_bodies(&content, self);
+                let first_entry = entries.len();
+                let mut envs = ChapterEnvs::default();
+                let mut numbered = String::with_capacity(content.len());
+                for (i, (part, prefix)) in self
+                    .split_intro(chapter, &content, prefix)
+                    .into_iter()
+                    .flat_map(|(part, prefix)| self.split_sections(part, prefix))
+                    .enumerate()
+                {
+                    if i > 0 {
+                        // environments following the intro or a numbered heading are numbered from scratch
+                        counters.retain(|key, _| book_counters.contains(key.as_str()));
+                    }
+                    let part = number_envs(
+                        part,
+                        &prefix,
+                        path,
+                        config,
+                        counters,
+                        &mut refs,
+                        &mut entries,
+                        &mut envs,
+                    );
+                    numbered += &find_and_replace_equations(
+                        &part, &prefix, path, config, counters, &mut refs,
+                    );
+                }
+                let numbered = close_envs(&content, &numbered, path, config, &envs);
+                // restatements may appear in other chapters, with their own frozen regions
+                let (numbered, found) =
+                    extract_block_bodies(&numbered, &frozen_regions[path], self);
+                bodies.extend(found);
+                chapter.content = numbered;
+                // titles may contain code spans, which must be restored as titles are shown in other chapters
+                for entry in &mut entries[first_entry..] {
+                    let Some(title) = &mut entry.title else {
+                        continue;
+                    };
+                    *title = thaw_regions(title, &frozen_regions[path]);
+                    if let Some(info) = entry.label.as_ref().and_then(|label| refs.get_mut(label)) {
+                        if &info.path == path {
+                            info.title = Some(title.clone());
+                        }
+                    }
+                }
+                self.record_chapter(chapter, &mut entries[first_entry..], &mut refs);
+                if !self.sidebar_counts.is_empty() {
+                    chapter.name = self.counted_name(&chapter.name, &entries[first_entry..]);
+                }
+            }
+            _ => {}
+        });
+        if !unnumbered_errors.is_empty() {
+            return Err(Error::msg(unnumbered_errors.join("\n")));
+        }
+
+        for (label, path) in exports {
+            match refs.get_mut(&label) {
+                Some(info) if info.path == path => info.exported = true,
+                _ => warn!(
+                    "{}: Exported label `{label}' is not defined in this chapter",
+                    path.display()
+                ),
+            }
+        }
+
+        // distinct labels may be turned into the same anchor, in which case links to the second one
+        // point to the first one
+        let sorted_labels: BTreeMap<&String, &LabelInfo> = refs.iter().collect();
+        let mut anchors: HashMap<(&PathBuf, String), &String> = HashMap::new();
+        for (label, info) in sorted_labels {
+            let anchor = self.anchor(&info.env, label);
+            match anchors.get(&(&info.path, anchor.clone())) {
+                Some(other) => warn!(
+                    "{}: Labels `{other}' and `{label}' have the same anchor `{anchor}', rename one of them",
+                    info.path.display()
+                ),
+                None => {
+                    anchors.insert((&info.path, anchor), label);
+                }
+            }
+        }
+
+        if let Some(cache_path) = &self.label_cache {
+            let cache_path = ctx.root.join(cache_path);
+            if broken_chapters.is_empty() && !self.dry_run {
+                // labels are sorted so that the file is only rewritten when labels change
+                let sorted_refs: BTreeMap<&String, &LabelInfo> = refs.iter().collect();
+                let content = serde_json::to_string_pretty(&sorted_refs)?;
+                if std::fs::read_to_string(&cache_path).ok().as_deref() != Some(content.as_str()) {
+                    if let Some(parent) = cache_path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    std::fs::write(&cache_path, content)?;
+                }
+            } else if let Ok(content) = std::fs::read_to_string(&cache_path) {
+                match serde_json::from_str::<HashMap<String, LabelInfo>>(&content) {
+                    Ok(cached) => {
+                        for (label, info) in cached {
+                            if broken_chapters.contains(&info.path) && !refs.contains_key(&label) {
+                                info!(
+                                    "{}: Label `{label}' taken from the last complete build",
+                                    info.path.display()
+                                );
+                                refs.insert(label, info);
+                            }
+                        }
+                    }
+                    Err(e) => warn!("Could not load label cache `{}': {e}", cache_path.display()),
+                }
+            }
+        }
+
+        if self.strict {
+            for occurrence in &occurrences {
+                let resolved = refs
+                    .get(&occurrence.label)
+                    .map_or(self.external.contains_key(&occurrence.label), |info| {
+                        !self.private_labels || info.exported || info.path == occurrence.path
+                    });
+                if !resolved && !occurrence.label.is_empty() {
+                    strict_errors.push(format!(
+                        "{}:{}: Unresolved reference to label `{}'",
+                        occurrence.path.display(),
+                        occurrence.line,
+                        occurrence.label
+                    ));
+                }
+            }
+            if !strict_errors.is_empty() {
+                return Err(Error::msg(strict_errors.join("\n")));
+            }
+        }
+
+        if self.warn_duplicate_titles {
+            for (entry, first) in find_duplicate_titles(&entries, &self.duplicate_titles_ignore) {
+                warn!(
+                    "{}: {}: Title `{}' already used by {} in `{}'",
+                    entry.path.display(),
+                    entry.num_name,
+                    entry.title.as_deref().unwrap_or_default(),
+                    first.num_name,
+                    first.path.display()
+                );
+            }
+        }
+
+        // labels are all known at this point, hence chapters can be processed in parallel
+        // the chapters referring to each label, other than the chapter of the label, in the order of the book
+        let mut backrefs: HashMap<&str, Vec<&PathBuf>> = HashMap::new();
+        if self.backrefs {
+            for occurrence in &occurrences {
+                let Some(info) = refs.get(&occurrence.label) else {
+                    continue;
+                };
+                let paths = backrefs.entry(occurrence.label.as_str()).or_default();
+                if info.path != occurrence.path && !paths.contains(&&occurrence.path) {
+                    paths.push(&occurrence.path);
+                }
+            }
+        }
+        let mut chapters = Vec::new();
+        self.processed_chapters(&mut book.sections, &mut chapters);
+        chapters
+            .into_par_iter()
+            .map(|(path, content)| {
+                if self.lint_links {
+                    for (line, label) in find_plain_links(content, &refs) {
+                        warn!(
+                            "{}:{line}: Plain link to label `{label}', consider using `{}' instead",
+                            path.display(),
+                            self.syntax.wrap(&format!("ref: {label}"))
+                        );
+                    }
+                }
+                // restated bodies bring their frozen regions along
+                let mut frozen = frozen_regions[path].clone();
+                let mut output = find_and_replace_lists(content, path, &entries, self);
+                output = find_and_replace_restatements(
+                    &output,
+                    path,
+                    &entries,
+                    &bodies,
+                    &mut frozen,
+                    self,
+                );
+                if !solutions.is_empty() {
+                    output = find_and_replace_solutions(&output, path, &entries, self);
+                    output = replace_solution_markers(&output, path, &entries, &solutions, self);
+                }
+                if self.backrefs {
+                    output =
+                        replace_backref_markers(&output, path, &backrefs, &chapter_infos, self);
+                }
+                if self.with_previews() {
+                    output = insert_previews(&output, path, &refs, &bodies, &mut frozen, self);
+                }
+                let output = find_and_replace_refs(&output, path, &refs, self)?;
+                *content = format!("{}{}", self.marker(), thaw_regions(&output, &frozen));
+                Ok(())
+            })
+            // results are collected in order, so that the error of the first failing chapter is returned
+            .collect::<Vec<Result<()>>>()
+            .into_iter()
+            .collect::<Result<()>>()?;
+
+        if self.assets.enabled && self.assets.inject == AssetInjection::Auto {
+            // the assets are only written for the HTML renderer, which copies them to the rendered book
+            if ctx.renderer == "html" && !self.dry_run {
+                write_assets(&ctx.root.join(&ctx.config.book.src).join(&self.assets.dir))?;
+            }
+            book.for_each_mut(|item: &mut BookItem| {
+                if let BookItem::Chapter(chapter) = item {
+                    if let Some(path) = &chapter.path {
+                        // the tags follow the processed marker, and are not inserted twice into chapters
+                        // processed by a previous run
+                        let tags = asset_tags(&path_to_root(path), &self.assets.dir);
+                        let (marker, rest) = match chapter.content.strip_prefix(PROCESSED_MARKER) {
+                            Some(rest) => (PROCESSED_MARKER, rest),
+                            None => ("", chapter.content.as_str()),
+                        };
+                        if !rest.starts_with(&tags) {
+                            chapter.content = format!("{marker}{tags}{rest}");
+                        }
+                    }
+                }
+            });
+        }
+
+        if let Some(base_path) = &self.diff_base {
+            // the database is read before the completions file, which may be the same file, is overwritten
+            let base_path = ctx.root.join(base_path);
+            match std::fs::read_to_string(&base_path)
+                .map_err(Error::from)
+                .and_then(|content| serde_json::from_str(&content).map_err(Error::from))
+            {
+                Ok(previous) => {
+                    let report_path = ctx.root.join(&self.diff_report);
+                    if let Some(parent) = report_path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    std::fs::write(report_path, diff_report(&previous, &refs))?;
+                    if let Some(redirects_path) = &self.redirects {
+                        let redirects_path = ctx.root.join(redirects_path);
+                        if let Some(parent) = redirects_path.parent() {
+                            std::fs::create_dir_all(parent)?;
+                        }
+                        std::fs::write(redirects_path, redirect_map(&previous, &refs, self))?;
+                    }
+                }
+                Err(e) => warn!(
+                    "Could not load label database `{}': {e}",
+                    base_path.display()
+                ),
+            }
+        }
+
+        if let Some(completions_path) = &self.completions {
+            let completions_path = ctx.root.join(completions_path);
+            if let Some(parent) = completions_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(completions_path, completions(&refs, self)?)?;
+        }
+
+        if let Some(context_path) = &self.context_file {
+            let context_path = ctx
+                .root
+                .join(&ctx.config.build.build_dir)
+                .join(context_path);
+            if let Some(parent) = context_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(context_path, render_context(&entries, self)?)?;
+        }
+
+        if let Some(integrity_path) = &self.integrity_file {
+            let integrity_path = ctx
+                .root
+                .join(&ctx.config.build.build_dir)
+                .join(integrity_path);
+            if let Some(parent) = integrity_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(
+                integrity_path,
+                integrity_summary(&occurrences, &refs, self)?,
+            )?;
+        }
+
+        if let Some(report_path) = &self.reference_report {
+            let content = reference_report(&occurrences, report_path, &refs, self);
+            book.push_item(Chapter::new(
+                "Reference Report",
+                content,
+                report_path,
+                vec![],
+            ));
+        }
+
+        Ok(book)
+    }
+}
+
+/// Returns the HTML tags linking the builtin assets located in `dir` from a chapter
+/// whose path to the root of the book is `path_to_root`.
+fn asset_tags(path_to_root: &str, dir: &Path) -> String {
+    let mut tags = String::new();
+    for (name, _) in ASSETS {
+        let href = format!("{path_to_root}{}", dir.join(name).display());
+        match Path::new(name).extension().and_then(|ext| ext.to_str()) {
+            Some("css") => tags += &format!("<link rel=\"stylesheet\" href=\"{href}\">\n"),
+            Some("js") => tags += &format!("<script src=\"{href}\"></script>\n"),
+            _ => {}
+        }
+    }
+    tags
+}
+
+/// Applies `func` to all items of the book in the order in which they appear in the summary,
+/// i.e., each chapter is visited before its sub-chapters.
+fn for_each_item_in_order<F>(items: &mut [BookItem], func: &mut F)
+where
+    F: FnMut(&mut BookItem),
+{
+    for item in items {
+        func(item);
+        if let BookItem::Chapter(chapter) = item {
+            for_each_item_in_order(&mut chapter.sub_items, func);
+        }
+    }
+}
+
+/// Returns the number at the beginning of the first heading of `s`, e.g. 3.2 for `# 3.2. Groups`,
+/// if there is a heading and it is numbered.
+fn heading_number(s: &str) -> Option<SectionNumber> {
+    let re: Regex =
+        cached_regex(r"(?m)^#{1,6}[ \t]+(?:(?P<number>[0-9]+(?:\.[0-9]+)*)\.?(?:[ \t]|$))?");
+    let number = re.captures(s)?.name("number")?.as_str();
+    Some(SectionNumber(
+        number.split('.').map(|n| n.parse().unwrap_or(0)).collect(),
+    ))
+}
+
+/// Returns the offset of the first heading of `s`, if any.
+fn heading_start(s: &str) -> Option<usize> {
+    let re: Regex = cached_regex(r"(?m)^#{1,6}[ \t]");
+    re.find(s).map(|m| m.start())
+}
+
+/// Checks whether the chapter with number `number` may follow the chapter with number `last` in the summary.
+fn chapter_follows(last: &SectionNumber, number: &SectionNumber) -> bool {
+    // section numbers are compared lexicographically, e.g. 1.2 < 1.2.1 < 1.3 < 2
+    last.as_slice() < number.as_slice()
+}
+
+/// Converts a positive integer into uppercase roman numerals.
+fn to_roman(mut n: u32) -> String {
+    const NUMERALS: [(u32, &str); 13] = [
+        (1000, "M"),
+        (900, "CM"),
+        (500, "D"),
+        (400, "CD"),
+        (100, "C"),
+        (90, "XC"),
+        (50, "L"),
+        (40, "XL"),
+        (10, "X"),
+        (9, "IX"),
+        (5, "V"),
+        (4, "IV"),
+        (1, "I"),
+    ];
+    let mut roman = String::new();
+    for &(value, numeral) in NUMERALS.iter() {
+        while n >= value {
+            roman.push_str(numeral);
+            n -= value;
+        }
+    }
+    roman
+}
+
+/// Converts a positive integer into uppercase letters, e.g. "C" for 3 and "AB" for 28.
+fn to_letters(mut n: u32) -> String {
+    let mut letters = Vec::new();
+    while n > 0 {
+        n -= 1;
+        letters.push((b'A' + (n % 26) as u8) as char);
+        n /= 26;
+    }
+    letters.into_iter().rev().collect()
+}
+
+/// Parses the attributes `name=value` or `name="value"` of an environment, e.g. `of=thm:main`,
+/// and returns their names and values; fragments which are not attributes are returned with an empty name.
+fn parse_attributes(s: &str) -> Vec<(&str, regex::Match<'_>)> {
+    let re: Regex = cached_regex(
+        r#"(?:(?P<name>[\w-]+)\s*=\s*(?:"(?P<quoted>[^"]*)"|(?P<value>\S+)))|(?P<other>\S+)"#,
+    );
+    re.captures_iter(s)
+        .map(|caps| match caps.name("name") {
+            Some(name) => (
+                name.as_str(),
+                caps.name("quoted").or(caps.name("value")).unwrap(),
+            ),
+            None => ("", caps.name("other").unwrap()),
+        })
+        .collect()
+}
+
+/// Replaces the regions of `s` enclosed in `{{numthm-freeze}}` and `{{numthm-unfreeze}}` (or extending to the end
+/// of `s` if the latter is missing) with placeholders, so that they don't advance counters nor register labels,
+/// and returns the resulting string and the contents of the regions, to be restored with `thaw_regions`.
+/// With `{{numthm-freeze: render}}`, environment headers of the region are rendered without number.
+/// Fenced code blocks, code spans and HTML comments are frozen as well, so that they can show macros,
+/// as are the regions between comments `<!-- numthm: off -->` and `<!-- numthm: on -->`,
+/// and macros escaped with a backslash, e.g. `\{{thm}}`, are frozen without their backslash.
+/// Private-use characters U+E000 to U+E00F, which mark placeholders and environments, are frozen as well.
+fn freeze_regions(s: &str, config: &NumThmPreprocessor) -> (String, Vec<String>) {
+    let syntax = &config.syntax;
+    let mut frozen = Vec::new();
+    // the placeholder spans as many lines as the region, so that line numbers of diagnostics are preserved
+    let mut placeholder = |region: &str, body: String| {
+        let newlines = "\n".repeat(region.matches('\n').count());
+        frozen.push(body);
+        format!("\u{E000}{}{newlines}\u{E001}", frozen.len() - 1)
+    };
+
+    // the private-use characters marking placeholders and environments are frozen if they occur in `s`,
+    // so that they are restored as is
+    let markers: Regex = cached_regex(r"[\x{E000}-\x{E00F}]");
+    let unmarked;
+    let s = match markers.is_match(s) {
+        true => {
+            unmarked = markers
+                .replace_all(s, |caps: &regex::Captures| {
+                    placeholder("", caps[0].to_string())
+                })
+                .to_string();
+            unmarked.as_str()
+        }
+        false => s,
+    };
+
+    // the regions between comments `<!-- numthm: off -->` and `<!-- numthm: on -->` (or the end of `s`)
+    // are frozen as a whole, together with the comments
+    let directive: Regex = cached_regex(r"^<!--\s*numthm:\s*(?P<state>on|off)\s*-->$");
+    let mut regions = Vec::new();
+    let mut off = None;
+    for range in code_regions(s) {
+        let state = directive
+            .captures(&s[range.clone()])
+            .map(|caps| caps.name("state").unwrap().as_str());
+        match (state, off) {
+            (Some("off"), None) => off = Some(range.start),
+            (Some("on"), Some(start)) => {
+                regions.push(start..range.end);
+                off = None;
+            }
+            (_, Some(_)) => {}
+            (_, None) => regions.push(range),
+        }
+    }
+    if let Some(start) = off {
+        regions.push(start..s.len());
+    }
+
+    let mut unfrozen = String::with_capacity(s.len());
+    let mut last = 0;
+    for range in regions {
+        unfrozen += &s[last..range.start];
+        unfrozen += &placeholder(&s[range.clone()], s[range.clone()].to_string());
+        last = range.end;
+    }
+    unfrozen += &s[last..];
+    if config.macros.is_some() {
+        // macros which are not recognized are left untouched, escaping backslash included
+        let s = unfrozen;
+        unfrozen = String::with_capacity(s.len());
+        let mut last = 0;
+        // (escaped recognized macros are unescaped below)
+        for (range, _, _) in config
+            .macro_fragments(&s)
+            .into_iter()
+            .filter(|(_, _, recognized)| !recognized)
+        {
+            unfrozen += &s[last..range.start];
+            unfrozen += &placeholder(&s[range.clone()], s[range.clone()].to_string());
+            last = range.end;
+        }
+        unfrozen += &s[last..];
+    }
+    let escaped: Regex = cached_regex(&format!(r"\\{}", regex::escape(&syntax.open)));
+    let s = escaped
+        .replace_all(&unfrozen, |_: &regex::Captures| {
+            placeholder("", syntax.open.clone())
+        })
+        .to_string();
+
+    let re: Regex = cached_regex(&format!(
+        r"{}(?s:(?P<body>.*?))(?:{}|\z)",
+        syntax.pattern(r"numthm-freeze(?::\s*(?P<mode>\S+?))?\s*"),
+        syntax.pattern(r"numthm-unfreeze\s*")
+    ));
+
+    let output = re
+        .replace_all(&s, |caps: &regex::Captures| {
+            let body = caps.name("body").unwrap().as_str();
+            let body = match caps.name("mode").map(|m| m.as_str()) {
+                None => body.to_string(),
+                Some("render") => render_unnumbered(body, config),
+                Some(mode) => {
+                    warn!("Unknown freeze mode `{mode}'");
+                    body.to_string()
+                }
+            };
+            placeholder(&caps[0], body)
+        })
+        .to_string();
+    let output = match config.latex_syntax {
+        true => convert_latex_syntax(&output, config),
+        false => output,
+    };
+    (output, frozen)
+}
+
+/// Converts the LaTeX environments `\begin{theorem}[Title]\label{label} ... \end{theorem}` of `s` into
+/// blocks `{{#thm}}{label}[Title] ... {{/thm}}`, the other labels `\label{label}` (of equations) into
+/// equation markers `{{eq}}{label}`, and the references `\ref{label}` and `\Cref{label}`
+/// (or `\cref`, `\autoref`, `\eqref`) into `{{nref: label}}` and `{{ref: label}}`.
+/// LaTeX environments are matched by the `latex` field of environments, and other ones are left as is.
+fn convert_latex_syntax(s: &str, config: &NumThmPreprocessor) -> String {
+    let syntax = &config.syntax;
+    let mut keys: Vec<&String> = config.environments.keys().collect();
+    keys.sort();
+    // the first key in alphabetical order wins if several environments share a LaTeX environment
+    let mut names: HashMap<String, (&str, bool)> = HashMap::new();
+    for key in keys {
+        let env = &config.environments[key];
+        names.entry(env.latex_name()).or_insert((key, false));
+        names.entry(env.starred_latex_name()).or_insert((key, true));
+    }
+
+    let begin: Regex = cached_regex(
+        r"\\begin\{(?P<name>[\w*]+)\}(?:[ \t]*\[(?P<title>[^\]\n]*)\])?(?:\s*\\label\{(?P<label>[^}\n]*)\})?",
+    );
+    let s = begin.replace_all(s, |caps: &regex::Captures| match names.get(&caps["name"]) {
+        Some((key, starred)) => {
+            let star = if *starred { "*" } else { "" };
+            let label = caps
+                .name("label")
+                .map(|m| format!("{{{}}}", m.as_str().trim()))
+                .unwrap_or_default();
+            let title = caps
+                .name("title")
+                .map(|m| format!("[{}]", m.as_str()))
+                .unwrap_or_default();
+            format!("{}{label}{title}", syntax.wrap(&format!("#{key}{star}")))
+        }
+        None => caps[0].to_string(),
+    });
+    let label: Regex = cached_regex(r"\\label\{(?P<label>[^}\n]*)\}");
+    let s = label.replace_all(&s, |caps: &regex::Captures| {
+        format!("{}{{{}}}", syntax.wrap("eq"), caps["label"].trim())
+    });
+    let end: Regex = cached_regex(r"\\end\{(?P<name>[\w*]+)\}");
+    let s = end.replace_all(&s, |caps: &regex::Captures| {
+        match names.get(&caps["name"]) {
+            Some((key, _)) => syntax.wrap(&format!("/{key}")),
+            None => caps[0].to_string(),
+        }
+    });
+    let reference: Regex =
+        cached_regex(r"\\(?P<command>ref|[cC]ref|autoref|eqref)\{(?P<label>[^}\n]*)\}");
+    reference
+        .replace_all(&s, |caps: &regex::Captures| {
+            let reftype = match &caps["command"] {
+                "ref" => "nref",
+                _ => "ref",
+            };
+            syntax.wrap(&format!("{reftype}: {}", caps["label"].trim()))
+        })
+        .to_string()
+}
+
+/// Returns the byte ranges of the fenced code blocks, code spans and HTML comments of `s`, in order.
+/// Unterminated code blocks extend to the end of `s`, while unmatched backticks are literal.
+fn code_regions(s: &str) -> Vec<Range<usize>> {
+    let start: Regex =
+        cached_regex(r"(?m)^[ \t]{0,3}(?P<fence>`{3,}|~{3,})|(?P<ticks>`+)|(?s:<!--.*?-->)");
+    let run: Regex = cached_regex("`+");
+    let mut regions = Vec::new();
+    let mut pos = 0;
+    while let Some(caps) = start.captures_at(s, pos) {
+        let m = caps.get(0).unwrap();
+        let end = if let Some(fence) = caps.name("fence") {
+            // the block ends with a line made of a fence of the same kind, at least as long as the opening one
+            let closing: Regex = cached_regex(&format!(
+                r"(?m)^[ \t]{{0,3}}{}{{{},}}[ \t]*$",
+                regex::escape(&fence.as_str()[..1]),
+                fence.len()
+            ));
+            let line_end = s[m.end()..].find('\n').map_or(s.len(), |i| m.end() + i);
+            closing.find_at(s, line_end).map_or(s.len(), |c| c.end())
+        } else if let Some(ticks) = caps.name("ticks") {
+            // the span ends with a run of as many backticks
+            match run
+                .find_iter(&s[m.end()..])
+                .find(|r| r.len() == ticks.len())
+            {
+                Some(r) => m.end() + r.end(),
+                None => {
+                    pos = m.end();
+                    continue;
+                }
+            }
+        } else {
+            m.end()
+        };
+        regions.push(m.start()..end);
+        pos = end;
+    }
+    regions
+}
+
+/// Freezes the regions of `s` as `freeze_regions` does, appending their contents to the regions `frozen` of the
+/// string `s` is inserted into, and returns the resulting string.
+fn freeze_regions_into(s: &str, config: &NumThmPreprocessor, frozen: &mut Vec<String>) -> String {
+    let (s, regions) = freeze_regions(s, config);
+    let placeholder: Regex = cached_regex("\u{E000}(?P<index>[0-9]+)(?P<newlines>\n*)\u{E001}");
+    // the placeholders, also found in regions containing other regions, are shifted past the existing regions
+    let offset = frozen.len();
+    let shift = |s: &str| {
+        placeholder
+            .replace_all(s, |caps: &regex::Captures| {
+                let index = caps["index"].parse::<usize>().unwrap() + offset;
+                format!("\u{E000}{index}{}\u{E001}", &caps["newlines"])
+            })
+            .to_string()
+    };
+    frozen.extend(regions.iter().map(|region| shift(region)));
+    shift(&s)
+}
+
+/// Restores the regions replaced with placeholders by `freeze_regions`.
+fn thaw_regions(s: &str, frozen: &[String]) -> String {
+    if frozen.is_empty() {
+        return s.to_string();
+    }
+    let re: Regex = cached_regex("\u{E000}(?P<index>[0-9]+)\n*\u{E001}");
+    // frozen regions may contain other frozen regions, e.g. code spans, which were frozen before them
+    re.replace_all(s, |caps: &regex::Captures| {
+        thaw_regions(&frozen[caps["index"].parse::<usize>().unwrap()], frozen)
+    })
+    .to_string()
+}
+
+/// Replaces all environments of `s` with their header without number, leaving other macros as is.
+fn render_unnumbered(s: &str, config: &NumThmPreprocessor) -> String {
+    let output = config.env_regex().replace_all(s, |caps: &regex::Captures| {
+        let Some(key) = caps.name("key") else {
+            return caps[0].to_string();
+        };
+        let env = &config.environments[key.as_str()];
+        let name = config.name_case.header.apply(env.header_name());
+        let start = match caps.name("block") {
+            Some(_) => block_start(key.as_str(), None, config),
+            None => String::new(),
+        };
+        let caption = caps.name("title").map_or("", |title| title.as_str());
+        let title = match caption {
+            "" => String::new(),
+            caption => format!(" ({caption})"),
+        };
+        let label = caps.name("label").map_or("", |label| label.as_str().trim());
+        start + &format_header(env, &name, "", &title, caption, label, None)
+    });
+    let output = replace_block_ends(&output, config, &check_blocks(s, config));
+    replace_qed(&output, config)
+}
+
+/// Finds and removes all patterns `{{export: label}}` and returns the resulting string and the exported labels.
+fn find_and_remove_exports(s: &str, syntax: &MacroSyntax) -> (String, Vec<String>) {
+    let re: Regex = syntax.export_regex();
+    let mut exported = Vec::new();
+
+    let output = re
+        .replace_all(s, |caps: &regex::Captures| {
+            exported.push(normalize_label(caps.name("label").unwrap().as_str()));
+            ""
+        })
+        .to_string();
+    (output, exported)
+}
+
+/// Finds all patterns `{{snippet: name}}` and `{{snippet-quote: name}}` where `name` is the name of a snippet
+/// and replaces them with the statement of the snippet.
+/// The first instantiation of a snippet with `{{snippet: name}}` is replaced with the numbered environment,
+/// which is added to `instantiated`, while quotations (and subsequent instantiations) are replaced
+/// with an unnumbered blockquote referring to the original.
+/// As `s` is frozen, the regions of the bodies of snippets are frozen as well and appended to `frozen`.
+fn expand_snippets(
+    s: &str,
+    path: &Path,
+    config: &NumThmPreprocessor,
+    instantiated: &mut HashSet<String>,
+    frozen: &mut Vec<String>,
+) -> String {
+    let syntax = &config.syntax;
+    let re: Regex =
+        cached_regex(&syntax.pattern(r"snippet(?P<quote>-quote)?:\s*(?P<name>\S+?)\s*"));
+
+    re.replace_all(s, |caps: &regex::Captures| {
+        let name = caps.name("name").unwrap().as_str();
+        let Some(snippet) = config.snippets.get(name) else {
+            warn!("{}: Unknown snippet `{name}'", path.display());
+            return config.strings.unresolved.clone();
+        };
+        let Some(env) = config.environments.get(&snippet.env) else {
+            warn!(
+                "{}: Snippet `{name}': unknown environment `{}'",
+                path.display(),
+                snippet.env
+            );
+            return config.strings.unresolved.clone();
+        };
+        let label = snippet.label.as_deref().unwrap_or(name);
+        let title = snippet.title.as_deref();
+
+        if caps.name("quote").is_none() {
+            if instantiated.insert(name.to_string()) {
+                let title = title.map(|t| format!("[{t}]")).unwrap_or_default();
+                return format!(
+                    "{}{{{label}}}{title} {}",
+                    syntax.wrap(&snippet.env),
+                    freeze_regions_into(snippet.body.trim(), config, frozen)
+                );
+            }
+            warn!(
+                "{}: Snippet `{name}' already instantiated, quoting it instead",
+                path.display()
+            );
+        }
+
+        let (open, close) = (&env.emph, &env.emph_close);
+        let reference = syntax.wrap(&format!("ref: {label}"));
+        let title = title.map(|t| format!(" ({t})")).unwrap_or_default();
+        let body = snippet.body.trim().lines().collect::<Vec<_>>().join("\n> ");
+        let body = freeze_regions_into(&body, config, frozen);
+        format!("> {open}{reference}{title}.{close} {body}")
+    })
+    .to_string()
+}
+
+/// Returns the numbered name of an environment named `name` with number `number`,
+/// e.g. "Theorem 1.2", or only its name if it is unnumbered.
+fn numbered_name(name: &str, number: &str) -> String {
+    match number.is_empty() {
+        true => name.to_string(),
+        false => format!("{name} {number}"),
+    }
+}
+
+/// Returns the header of environment `env` with name `name` and number `number` (empty if unnumbered),
+/// e.g. "**Theorem 1.2 (Lagrange).**", where `title` is the parenthesized title following the number, if any,
+/// e.g. " (Lagrange)", and `caption` the bare title, e.g. "Lagrange". The header is built from the template
+/// `env.format`, if any, by replacing the variables `{name}`, `{number}`, `{title}`, `{caption}`, `{label}`,
+/// and `{emph}`, whose first occurrence is replaced with the opening
+/// delimiter of the emphasis and the following ones with the closing delimiter; other patterns are left as is.
+/// With `self_link`, the number (or the name if unnumbered) links to that anchor, e.g. "**Theorem [1.2](#thm:x).**".
+fn format_header(
+    env: &Env,
+    name: &str,
+    number: &str,
+    title: &str,
+    caption: &str,
+    label: &str,
+    self_link: Option<&str>,
+) -> String {
+    let (name, number) = match self_link {
+        Some(anchor) if !number.is_empty() => (name.to_string(), format!("[{number}](#{anchor})")),
+        Some(anchor) if !name.is_empty() => (format!("[{name}](#{anchor})"), String::new()),
+        _ => (name.to_string(), number.to_string()),
+    };
+    let (name, number) = (name.as_str(), number.as_str());
+    let Some(format) = &env.format else {
+        return format!(
+            "{}{}{title}.{}",
+            env.emph,
+            numbered_name(name, number),
+            env.emph_close
+        );
+    };
+    let re: Regex = cached_regex(r"\{(?P<var>\w+)\}");
+    let mut emph = 0;
+    re.replace_all(format, |caps: &regex::Captures| {
+        match caps.name("var").unwrap().as_str() {
+            "emph" => {
+                emph += 1;
+                match emph {
+                    1 => &env.emph,
+                    _ => &env.emph_close,
+                }
+            }
+            "name" => name,
+            "number" => number,
+            "title" => title,
+            "caption" => caption,
+            "label" => label,
+            _ => &caps[0],
+        }
+        .to_string()
+    })
+    // e.g. figures without caption
+    .trim_end()
+    .to_string()
+}
+
+/// Inserts `label` with information `label_info` into `refs`, resolving duplicate labels according to `config`,
+/// and returns the label under which the environment is registered (which differs from `label` if it was renamed).
+fn insert_label(
+    refs: &mut HashMap<String, LabelInfo>,
+    label: String,
+    label_info: LabelInfo,
+    config: &NumThmPreprocessor,
+) -> String {
+    let num_name = label_info.num_name.clone();
+    if !refs.contains_key(&label) {
+        refs.insert(label.clone(), label_info);
+        return label;
+    }
+    match config.duplicate_labels {
+        DuplicateLabels::KeepFirst => {
+            // we emit a warning and don't update the hashmap
+            warn!("{num_name}: Label `{label}' already used");
+            label
+        }
+        DuplicateLabels::KeepLast => {
+            warn!("{num_name}: Label `{label}' already used, overriding it");
+            refs.insert(label.clone(), label_info);
+            label
+        }
+        DuplicateLabels::Suffix => {
+            let new_label = suffixed_label(&label, |l| {
+                refs.contains_key(l)
+                    || config
+                        .defined_labels
+                        .as_ref()
+                        .is_some_and(|d| d.contains_key(l))
+            });
+            warn!(
+                "{}: {num_name}: Label `{label}' already used, renamed to `{new_label}'",
+                label_info.path.display()
+            );
+            refs.insert(new_label.clone(), label_info);
+            new_label
+        }
+    }
+}
+
+/// Returns the label `label` with the first numeric suffix, from 2 on, giving a label which is not `taken`,
+/// e.g. `thm:main-3` if `thm:main-2` is taken.
+fn suffixed_label(label: &str, taken: impl Fn(&str) -> bool) -> String {
+    (2..)
+        .map(|i| format!("{label}-{i}"))
+        .find(|l| !taken(l))
+        .unwrap()
+}
+
+/// Returns the markup opening a block environment with key `key`, e.g. `<div class="numthm numthm-thm">`,
+/// with id `anchor` if given.
+fn block_start(key: &str, anchor: Option<&str>, config: &NumThmPreprocessor) -> String {
+    let id = anchor.map_or(String::new(), |anchor| format!(" id=\"{anchor}\""));
+    match config.output {
+        // the blank line lets markdown be rendered inside the block
+        OutputFormat::Html => format!(
+            "<div class=\"{}\"{id}>\n\n",
+            config.blocks.class.replace("{key}", key)
+        ),
+        // the environment is opened by its header
+        OutputFormat::Markdown | OutputFormat::Latex => String::new(),
+    }
+}
+
+/// Returns the markup closing a block environment `env`, e.g. `</div>`.
+fn block_end(env: &Env, config: &NumThmPreprocessor) -> String {
+    match config.output {
+        OutputFormat::Html => "\n\n</div>".to_string(),
+        OutputFormat::Markdown => String::new(),
+        OutputFormat::Latex => format!("\n\\end{{{}}}", env.latex_name()),
+    }
+}
+
+/// Closes the LaTeX environments which are not written as blocks at the end of their paragraph, i.e., at the next
+/// blank line or at the beginning of the next such environment, their header being preceded by the name of the
+/// environment between U+E002 and U+E003.
+fn close_paragraph_envs(s: &str) -> String {
+    let mut output = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('\u{E002}') {
+        let Some((name, body)) = rest[start + '\u{E002}'.len_utf8()..].split_once('\u{E003}')
+        else {
+            break;
+        };
+        output.push_str(&rest[..start]);
+        let end = [body.find("\n\n"), body.find('\u{E002}')]
+            .into_iter()
+            .flatten()
+            .min()
+            .unwrap_or(body.len());
+        // the environment ends before the spaces preceding the next one
+        let end = body[..end].trim_end().len();
+        output.push_str(&format!("{}\n\\end{{{name}}}", &body[..end]));
+        rest = &body[end..];
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Replaces all patterns `{{/key}}` ending block environments with the markup closing them,
+/// except the unmatched ones, given by their index among the patterns `{{/key}}` of `s` in `blocks`
+/// (see `check_blocks`), which also gives the ones closing unnumbered variants `{{#key*}}`.
+/// Blocks which are never closed are closed at the end of `s`.
+fn replace_block_ends(s: &str, config: &NumThmPreprocessor, blocks: &BlockNesting) -> String {
+    let end = |env: &Env, starred: bool| match config.output {
+        OutputFormat::Latex if starred => format!("\n\\end{{{}}}", env.starred_latex_name()),
+        _ => block_end(env, config),
+    };
+    let re: Regex = cached_regex(&config.syntax.pattern(r"/(?P<key>[\w-]+)\s*"));
+    let mut index = 0;
+    let mut output = re
+        .replace_all(s, |caps: &regex::Captures| {
+            let Some(env) = config.environments.get(&caps["key"]) else {
+                return caps[0].to_string();
+            };
+            index += 1;
+            let end = end(env, blocks.starred.contains(&(index - 1)));
+            match (blocks.unmatched.contains(&(index - 1)), env.qed) {
+                (true, _) => caps[0].to_string(),
+                (false, true) => qed_marker(config) + &end,
+                (false, false) => end,
+            }
+        })
+        .to_string();
+    for (key, starred) in &blocks.unclosed {
+        output += &end(&config.environments[key], *starred);
+    }
+    output
+}
+
+/// Returns the QED symbol marking the end of proofs, right-aligned in HTML.
+fn qed_marker(config: &NumThmPreprocessor) -> String {
+    match config.output {
+        OutputFormat::Html => format!(
+            "<span class=\"numthm-qed\" style=\"float: right\">{}</span>",
+            config.blocks.qed_symbol
+        ),
+        OutputFormat::Markdown => format!(" {}", config.blocks.qed_symbol),
+        // the QED symbol is added by the proof environment
+        OutputFormat::Latex => String::new(),
+    }
+}
+
+/// Replaces all patterns `{{qed}}` with the QED symbol.
+fn replace_qed(s: &str, config: &NumThmPreprocessor) -> String {
+    let re: Regex = cached_regex(&config.syntax.pattern(r"qed\s*"));
+    re.replace_all(s, qed_marker(config).as_str()).to_string()
+}
+
+/// The nesting of the block environments `{{#key}} ... {{/key}}` of a chapter, see `check_blocks`.
+#[derive(Debug, Default, PartialEq)]
+struct BlockNesting {
+    /// The line numbers and messages of the errors.
+    errors: Vec<(usize, String)>,
+    /// The indices of the unmatched patterns `{{/key}}` among all of them.
+    unmatched: HashSet<usize>,
+    /// The indices of the patterns `{{/key}}` ending unnumbered variants `{{#key*}}`.
+    starred: HashSet<usize>,
+    /// The keys of the blocks which are never closed, innermost first, and whether they are unnumbered variants.
+    unclosed: Vec<(String, bool)>,
+}
+
+/// Checks that block environments `{{#key}} ... {{/key}}` of `s` are properly nested.
+fn check_blocks(s: &str, config: &NumThmPreprocessor) -> BlockNesting {
+    let re: Regex = cached_regex(&config.syntax.pattern(&format!(
+        r"(?P<kind>[#/])(?P<key>[\w-]+)(?P<star>\*)?(?:\s+{})?\s*",
+        config.syntax.capture()
+    )));
+    let breaks = line_breaks(s);
+    let mut open: Vec<(&str, usize, bool)> = Vec::new();
+    let mut errors = Vec::new();
+    let mut unmatched = HashSet::new();
+    let mut starred = HashSet::new();
+    let mut index = 0;
+
+    for caps in re.captures_iter(s) {
+        let key = caps.name("key").unwrap().as_str();
+        if !config.environments.contains_key(key) {
+            continue;
+        }
+        let line = line_number(&breaks, caps.get(0).unwrap().start());
+        if &caps["kind"] == "#" {
+            open.push((key, line, caps.name("star").is_some()));
+            continue;
+        }
+        index += 1;
+        match open.last() {
+            Some((k, _, star)) if *k == key => {
+                if *star {
+                    starred.insert(index - 1);
+                }
+                open.pop();
+            }
+            Some((k, l, _)) => {
+                errors.push((
+                    line,
+                    format!("`{}' closes block `{k}' opened on line {l}", &caps[0]),
+                ));
+                unmatched.insert(index - 1);
+            }
+            None => {
+                errors.push((line, format!("`{}' without matching block", &caps[0])));
+                unmatched.insert(index - 1);
+            }
+        }
+    }
+    let mut unclosed = Vec::new();
+    for (key, line, star) in open.into_iter().rev() {
+        errors.push((line, format!("Block `{key}' is never closed")));
+        unclosed.push((key.to_string(), star));
+    }
+    BlockNesting {
+        errors,
+        unmatched,
+        starred,
+        unclosed,
+    }
+}
+
+/// The environments of a chapter seen so far, carried over between the parts of the chapter numbered with
+/// different prefixes (see `NumThmPreprocessor::split_sections`).
+#[derive(Default)]
+struct ChapterEnvs {
+    /// The number of occurrences and the last assigned number of each environment.
+    stats: HashMap<String, (u32, String)>,
+    /// The number, numbered name, and anchor of the last numbered environment.
+    current: Option<(String, String, Option<String>)>,
+}
+
+/// Finds all patterns `{{key}}{mylabel}[mytitle]` where `key` is the key of an environment of `config` (e.g. `thm`)
+/// and replaces them with a header (including the title if a title `mytitle` is provided)
+/// and potentially an anchor if a label `mylabel` is provided;
+/// if a label is provided, it updates the hashmap `refs` with an entry (label, LabelInfo)
+/// allowing to format links to the theorem, and it records the environment in `entries`.
+/// Environments are numbered by incrementing the relevant entry of `counter`.
+/// Block environments `{{#key}}{mylabel}[mytitle] ... {{/key}}` are handled likewise,
+/// their body being wrapped in a `div` of class `blocks.class`.
+/// Patterns `{{selfnum}}` and `{{selfref}}` are replaced with the number of the last environment preceding them
+/// and a link to it, respectively.
+/// As `s` may be a part of a chapter with prefix `prefix`, the environments of the chapter seen so far are recorded
+/// in `chapter`, and the ends of environments are left to `close_envs`, once all parts of the chapter are numbered.
+#[allow(clippy::too_many_arguments)]
+fn number_envs(
+    s: &str,
+    prefix: &str,
+    path: &Path,
+    config: &NumThmPreprocessor,
+    counter: &mut HashMap<String, u32>,
+    refs: &mut HashMap<String, LabelInfo>,
+    entries: &mut Vec<EnvEntry>,
+    chapter: &mut ChapterEnvs,
+) -> String {
+    let envs = &config.environments;
+    let re: Regex = config.env_regex();
+    let ChapterEnvs {
+        stats: chapter_stats,
+        current,
+    } = chapter;
+    let breaks = line_breaks(s);
+    // the counters set by markers `{{set-counter: key=N}}`, applied before the following environments
+    let set_counter: Regex = cached_regex(&config.syntax.pattern(SET_COUNTER_PATTERN));
+    let set_counters: Vec<(usize, Vec<CounterAssignment>)> = set_counter
+        .captures_iter(s)
+        .map(|caps| {
+            let line = line_number(&breaks, caps.get(0).unwrap().start());
+            let assignments = parse_counter_assignments(&caps["assignments"], config)
+                .unwrap_or_else(|message| {
+                    warn!("{}:{line}: {message}", path.display());
+                    vec![]
+                });
+            (caps.get(0).unwrap().start(), assignments)
+        })
+        .collect();
+    let mut next_set_counter = 0;
+
+    let output = re.replace_all(s, |caps: &regex::Captures| {
+        // {{selfnum}} and {{selfref}} refer to the last environment before them
+        if let Some(selfref) = caps.name("selfref") {
+            return match (&*current, selfref.as_str()) {
+                (Some((number, _, _)), "selfnum") => number.clone(),
+                (Some((_, _, Some(anchor))), _) if config.output == OutputFormat::Latex => {
+                    format!("\\Cref{{{anchor}}}")
+                }
+                (Some((_, num_name, Some(anchor))), _) => {
+                    let link_path = config.link_path(&path.to_path_buf(), &path.to_path_buf());
+                    config.link(num_name, &link_path, anchor)
+                }
+                (Some((_, num_name, None)), _) => num_name.clone(),
+                (None, _) => {
+                    warn!(
+                        "{}: `{}' outside of an environment",
+                        path.display(),
+                        selfref.as_str()
+                    );
+                    config.strings.unresolved.clone()
+                }
+            };
+        }
+
+        // otherwise key must have been matched
+        let key = caps.name("key").unwrap().as_str();
+
+        // key is absolutely part of env, so unwrap should be ok
+        let env = envs.get(key).unwrap();
+        let attrs = caps.name("attrs").map_or(vec![], |a| parse_attributes(a.as_str()));
+        // `lang=fr` names the environment in another language, in its header and in references to it,
+        // falling back to the name in the language of the book
+        let localized;
+        let localized_name = attrs
+            .iter()
+            .find(|(attr, _)| *attr == "lang")
+            .and_then(|(_, lang)| env.names.get(lang.as_str()))
+            .cloned();
+        let env = match &localized_name {
+            Some(name) => {
+                localized = Env {
+                    name: name.clone(),
+                    ref_name: None,
+                    header_name: None,
+                    ..env.clone()
+                };
+                &localized
+            }
+            None => env,
+        };
+        let name = &env.name;
+        // the unnumbered variant {{key*}} of a numbered environment, e.g. for named theorems
+        let starred = caps.name("star").is_some();
+        let numbered = env.numbered && !starred;
+        for (pos, assignments) in &set_counters[next_set_counter..] {
+            if *pos > caps.get(0).unwrap().start() {
+                break;
+            }
+            for a in assignments {
+                if let Err(message) = a.apply(counter) {
+                    warn!("{}:{}: {message}", path.display(), line_number(&breaks, *pos));
+                }
+            }
+            next_set_counter += 1;
+        }
+        // `number=previous` reuses the number of the previous environment, `number=N` sets it
+        let number_attr = attrs
+            .iter()
+            .find(|(attr, _)| *attr == "number")
+            .map(|(_, value)| value.as_str());
+        // unnumbered environments (e.g. proofs) have an empty number and don't advance counters
+        let (mut number, mut local_number) = match numbered {
+            true => {
+                let counter_key = env.counter_key(key);
+                // counters which were never set or were reset start from the start of the environment
+                let previous = counter.get(counter_key).copied();
+                let next = match previous.map(|n| n.checked_add(1)) {
+                    None => env.start,
+                    Some(Some(next)) => next,
+                    Some(None) => {
+                        warn!(
+                            "{}: {name}: Counter `{counter_key}' overflows, numbering it {}",
+                            path.display(),
+                            u32::MAX
+                        );
+                        u32::MAX
+                    }
+                };
+                let (ctr, reset) = match (number_attr, previous) {
+                    (None, _) => (next, true),
+                    (Some("previous"), Some(n)) => (n, false),
+                    (Some("previous"), None) => {
+                        warn!(
+                            "{}: {name}: No previous number to reuse, numbering it {next}",
+                            path.display()
+                        );
+                        (next, true)
+                    }
+                    (Some(value), _) => match value.parse() {
+                        Ok(n) => (n, true),
+                        Err(_) => {
+                            warn!(
+                                "{}: {name}: Invalid number `{value}', expected an integer or `previous'",
+                                path.display()
+                            );
+                            (next, true)
+                        }
+                    },
+                };
+                counter.insert(counter_key.to_string(), ctr);
+                let ctr = env.numbering.apply(ctr, env.pad);
+                // the environments numbered within a continued one keep counting
+                if reset {
+                    config.reset_children(counter_key, counter);
+                }
+                let (ctr, book_wide) = match config.parent_number(key, counter) {
+                    Some((parent, book_wide)) => {
+                        (format!("{parent}{}{ctr}", config.separator()), book_wide)
+                    }
+                    None => (ctr, env.book_wide),
+                };
+                let number = match book_wide {
+                    true => config.limit_depth("", &ctr, name, path),
+                    false => config.limit_depth(prefix, &ctr, name, path),
+                };
+                // the number displayed in the chapter, without prefix for chapter-relative numbers
+                let local_number = match config.relative_numbers {
+                    true => ctr,
+                    false => number.clone(),
+                };
+                (number, local_number)
+            }
+            false => (String::new(), String::new()),
+        };
+        if config.isolate_numbers && numbered {
+            // U+2066 LEFT-TO-RIGHT ISOLATE and U+2069 POP DIRECTIONAL ISOLATE keep the dots
+            // of the number in place in right-to-left text, both in HTML and in plain markdown
+            number = format!("\u{2066}{number}\u{2069}");
+            local_number = format!("\u{2066}{local_number}\u{2069}");
+        }
+        let local_name = config
+            .relative_numbers
+            .then(|| numbered_name(name, &local_number));
+        if numbered {
+            let stats = chapter_stats.entry(key.to_string()).or_default();
+            stats.0 += 1;
+            stats.1 = local_number.clone();
+        }
+
+        // empty labels and titles are ignored
+        let nonempty = |group: &str| {
+            let m = caps.name(group)?;
+            if m.as_str().trim().is_empty() {
+                if !config.quiet_empty {
+                    let line = line_number(&breaks, caps.get(0).unwrap().start());
+                    warn!(
+                        "{}:{line}: {name} {number}: Empty {group}, ignoring it",
+                        path.display()
+                    );
+                }
+                None
+            } else {
+                Some(m.as_str())
+            }
+        };
+        let match_label = nonempty("label");
+        let match_title = nonempty("title");
+
+        let mut of = None;
+        let mut fields = BTreeMap::new();
+        for (attr, value) in attrs {
+            match attr {
+                "of" => of = Some(normalize_label(value.as_str())),
+                _ if config.fields.iter().any(|field| field == attr) => {
+                    fields.insert(attr.to_string(), value.as_str().to_string());
+                }
+                "number" if numbered => {}
+                "lang" => {}
+                "number" => warn!(
+                    "{}: {name}: Ignoring the number of an unnumbered environment",
+                    path.display()
+                ),
+                "" => warn!(
+                    "{}: {name} {number}: Malformed attribute `{}'",
+                    path.display(),
+                    value.as_str()
+                ),
+                _ => warn!(
+                    "{}: {name} {number}: Unknown attribute `{attr}'",
+                    path.display()
+                ),
+            }
+        }
+
+        // an opening brace or bracket which is not matched means that the label or title is unterminated
+        // or longer than allowed, in which case it is left as is
+        let end = caps.get(0).unwrap().end();
+        let unmatched = match s[end..].chars().next() {
+            Some('{') if caps.name("label").is_none() => Some("label"),
+            Some('[') if caps.name("title").is_none() => Some("title"),
+            _ => None,
+        };
+        if let Some(group) = unmatched {
+            let line = line_number(&breaks, caps.get(0).unwrap().start());
+            warn!(
+                "{}:{line}: {name} {number}: Unterminated {group} or {group} longer than {} characters",
+                path.display(),
+                config.syntax.max_capture
+            );
+        }
+
+        let mut final_label = None;
+        let anchor = match match_label {
+            Some(match_label) => {
+                // if a label is given, we must update the hashmap
+                let mut label = normalize_label(match_label);
+                if let Some(label_prefix) = &env.label_prefix {
+                    if !label.starts_with(label_prefix) {
+                        warn!(
+                            "{}: {name} {number}: Label `{label}' should start with `{label_prefix}'",
+                            path.display()
+                        );
+                    }
+                }
+                let label_info = LabelInfo {
+                    env: key.to_string(),
+                    num_name: numbered_name(name, &number),
+                    name: localized_name.clone(),
+                    path: path.to_path_buf(),
+                    title: match_title.map(String::from),
+                    exported: false,
+                    local_name: local_name.clone(),
+                    of: of.clone(),
+                    chapter: None,
+                    fields: fields.clone(),
+                };
+                label = insert_label(refs, label, label_info, config);
+                final_label = Some(label.clone());
+                let anchor = config.anchor(key, &label);
+                match config.output {
+                    OutputFormat::Html => match (config.anchor_placement, caps.name("block")) {
+                        (AnchorPlacement::Inline, _) => format!("<a id=\"{anchor}\"></a>\n"),
+                        // the anchor is the id of the block
+                        (AnchorPlacement::Wrapper, Some(_)) => String::new(),
+                        (AnchorPlacement::Wrapper, None) => format!("<div id=\"{anchor}\"></div>\n\n"),
+                    },
+                    // headings get the anchor generated by the renderer from their text, others get no anchor
+                    OutputFormat::Markdown => String::new(),
+                    OutputFormat::Latex => format!("\\label{{{anchor}}}"),
+                }
+            }
+            None => String::new(),
+        };
+        // in draft mode, the label is shown next to the header
+        let badge = match (&final_label, config.draft) {
+            (Some(label), true) => match config.output {
+                OutputFormat::Html => format!("<small class=\"numthm-label\">⟨{label}⟩</small>"),
+                OutputFormat::Markdown => format!("⟨{label}⟩"),
+                OutputFormat::Latex => String::new(),
+            },
+            _ => String::new(),
+        };
+        // {{selfnum}} and {{selfref}} in unnumbered environments (e.g. proofs) refer to the last numbered one
+        if numbered {
+            *current = Some((
+                local_number.clone(),
+                format!(
+                    "{} {local_number}",
+                    config.name_case.reference.apply(env.ref_name())
+                ),
+                final_label.as_ref().map(|label| config.anchor(key, label)),
+            ));
+        }
+        // the environment this one derives from is referred to with a reference resolved in the second pass
+        let of_ref = of
+            .as_ref()
+            .map(|label| {
+                format!(
+                    "{} {}",
+                    config.strings.of,
+                    config.syntax.wrap(&format!("ref: {label}"))
+                )
+            });
+        let title = match (match_title, of_ref.as_deref()) {
+            (Some(title), Some(of_ref)) => format!(" ({title}, {of_ref})"),
+            (Some(title), None) | (None, Some(title)) => format!(" ({title})"),
+            (None, None) => String::new(),
+        };
+        // the header links to the environment itself, so that readers can copy its URL
+        let self_link = final_label
+            .as_ref()
+            .filter(|_| config.output == OutputFormat::Html && config.self_links)
+            .map(|label| config.anchor(key, label));
+        // fields are substituted in the header template, those which are not given being empty
+        let with_fields;
+        let env = match &env.format {
+            Some(format) if !config.fields.is_empty() => {
+                with_fields = Env {
+                    format: Some(fill_fields(format, &fields, &config.fields)),
+                    ..env.clone()
+                };
+                &with_fields
+            }
+            _ => env,
+        };
+        let header = format_header(
+            env,
+            &config.name_case.header.apply(env.header_name()),
+            &local_number,
+            &title,
+            match_title.unwrap_or_default(),
+            final_label.as_deref().unwrap_or_default(),
+            self_link.as_deref(),
+        );
+        // headers carry the environment type so that themes and plugins can recognize them
+        let header = match config.output {
+            OutputFormat::Html if config.mark_environments => format!(
+                "<span class=\"numthm-header numthm-{key}\" data-numthm-env=\"{key}\">{header}</span>"
+            ),
+            _ => header,
+        };
+        // the permalink is a link to the environment itself, which doesn't exist in LaTeX
+        let permalink = final_label
+            .as_ref()
+            .filter(|_| config.header_layout.contains(&HeaderPiece::Permalink))
+            .map(|label| match config.output {
+                OutputFormat::Html => format!(
+                    "<a class=\"numthm-permalink\" href=\"#{}\">¶</a>",
+                    config.anchor(key, label)
+                ),
+                // there is no anchor to link to
+                OutputFormat::Markdown | OutputFormat::Latex => String::new(),
+            })
+            .unwrap_or_default();
+        let output = match config.output {
+            OutputFormat::Markdown if final_label.is_some() => match env.heading {
+                Some(level) => format!(
+                    "{} {}",
+                    "#".repeat(level),
+                    config.layout_header("", &header, &permalink, &badge)
+                ),
+                None => config.layout_header(&anchor, &header, &permalink, &badge),
+            },
+            // LaTeX numbers the environment, whose end is marked by `close_paragraph_envs` outside of blocks
+            OutputFormat::Latex => {
+                let title = match (match_title, of_ref.as_deref()) {
+                    (Some(title), Some(of_ref)) => format!("[{title}, {of_ref}]"),
+                    (Some(title), None) | (None, Some(title)) => format!("[{title}]"),
+                    (None, None) => String::new(),
+                };
+                let name = match starred {
+                    true => env.starred_latex_name(),
+                    false => env.latex_name(),
+                };
+                match caps.name("block") {
+                    Some(_) => format!("\\begin{{{name}}}{title}{anchor}"),
+                    None => format!("\u{E002}{name}\u{E003}\\begin{{{name}}}{title}{anchor}"),
+                }
+            }
+            _ => config.layout_header(&anchor, &header, &permalink, &badge),
+        };
+        let num_name = numbered_name(name, &number);
+        let env = Environment {
+            key,
+            number: &number,
+            num_name: &num_name,
+            label: final_label.as_deref(),
+            title: match_title,
+            path,
+            output: "",
+        };
+        let output = config.hooks.environment(env, output);
+        let id = final_label
+            .as_ref()
+            .filter(|_| config.anchor_placement == AnchorPlacement::Wrapper)
+            .map(|label| config.anchor(key, label));
+        let output = match caps.name("block") {
+            Some(_) => block_start(key, id.as_deref(), config) + &output,
+            None => output,
+        };
+        entries.push(EnvEntry {
+            env: key.to_string(),
+            number,
+            num_name,
+            path: path.to_path_buf(),
+            label: final_label,
+            title: match_title.map(String::from),
+            local_name,
+            chapter: None,
+            numbered,
+            fields,
+        });
+        output
+    });
+
+    // the counters set after the last environment carry over to the rest of the chapter
+    for (pos, assignments) in &set_counters[next_set_counter..] {
+        for a in assignments {
+            if let Err(message) = a.apply(counter) {
+                warn!(
+                    "{}:{}: {message}",
+                    path.display(),
+                    line_number(&breaks, *pos)
+                );
+            }
+        }
+    }
+    set_counter.replace_all(&output, "").to_string()
+}
+
+/// Closes the environments of `output`, the chapter `s` whose parts are numbered by `number_envs`.
+/// Finally, it replaces all patterns `{{numthm-count: key}}` and `{{numthm-last: key}}`
+/// with the number of environments `key` in `chapter` and the number of the last one, respectively.
+fn close_envs(
+    s: &str,
+    output: &str,
+    path: &Path,
+    config: &NumThmPreprocessor,
+    chapter: &ChapterEnvs,
+) -> String {
+    let envs = &config.environments;
+    let blocks = check_blocks(s, config);
+    for (line, message) in &blocks.errors {
+        warn!("{}:{line}: {message}", path.display());
+    }
+    let output = close_paragraph_envs(output);
+    let output = replace_block_ends(&output, config, &blocks);
+    let output = replace_qed(&output, config);
+
+    // matches {{numthm-count: key}} and {{numthm-last: key}}
+    let re: Regex = cached_regex(
+        &config
+            .syntax
+            .pattern(r"numthm-(?P<stat>count|last):\s*(?P<key>\S+?)\s*"),
+    );
+    re.replace_all(&output, |caps: &regex::Captures| {
+        let key = caps.name("key").unwrap().as_str();
+        if !envs.contains_key(key) {
+            warn!("{}: Unknown environment `{key}'", path.display());
+            return caps[0].to_string();
+        }
+        match (caps.name("stat").unwrap().as_str(), chapter.stats.get(key)) {
+            ("count", Some((count, _))) => count.to_string(),
+            ("count", None) => "0".to_string(),
+            (_, Some((_, last))) => last.clone(),
+            (_, None) => {
+                warn!("{}: No environment `{key}' in this chapter", path.display());
+                config.strings.unresolved.clone()
+            }
+        }
+    })
+    .to_string()
+}
+
+/// Matches the assignments of markers `{{set-counter: key=N}}`, e.g. `thm=10 lem=+2`.
+const SET_COUNTER_PATTERN: &str = r"set-counter:\s*(?P<assignments>(?:[\w-]+\s*=\s*\+?[0-9]+\s*)+)";
+
+/// An assignment `key=N` or `key=+N` of a marker `{{set-counter: ...}}`.
+struct CounterAssignment {
+    /// The key of the counter.
+    counter: String,
+    /// The value of the counter, or the number of skipped numbers with an offset.
+    value: u32,
+    /// Whether the value is an offset `+N`.
+    offset: bool,
+    /// The number of the first environment of the counter, see `Env::start`.
+    start: u32,
+}
+
+impl CounterAssignment {
+    /// Sets the counter among `counters` to `value`, like `\setcounter` in LaTeX, so that the next environment is
+    /// numbered `value + 1`, or makes the next environment skip `value` numbers with an offset.
+    /// Returns an error message if the counter overflows, leaving it as is.
+    fn apply(&self, counters: &mut HashMap<String, u32>) -> Result<(), String> {
+        let overflow = || format!("Counter `{}' overflows, leaving it as is", self.counter);
+        let value = match (self.offset, counters.get(&self.counter)) {
+            (false, _) => self.value,
+            (true, Some(n)) => n.checked_add(self.value).ok_or_else(overflow)?,
+            // the next environment would have been numbered `start`
+            (true, None) => match self.start.checked_add(self.value).ok_or_else(overflow)? {
+                0 => return Ok(()),
+                next => next - 1,
+            },
+        };
+        counters.insert(self.counter.clone(), value);
+        Ok(())
+    }
+}
+
+/// Parses the assignments `key=N` or `key=+N` of a marker `{{set-counter: ...}}`, where `key` is the key of
+/// an environment or the name of a counter group, or returns an error message.
+fn parse_counter_assignments(
+    s: &str,
+    config: &NumThmPreprocessor,
+) -> Result<Vec<CounterAssignment>, String> {
+    parse_attributes(s)
+        .into_iter()
+        .map(|(key, value)| {
+            let (counter, start) = match config.environments.get(key) {
+                Some(env) => (env.counter_key(key), env.start),
+                None => config
+                    .environments
+                    .iter()
+                    .find(|(k, env)| env.counter_key(k) == key)
+                    .map(|(k, env)| (env.counter_key(k), env.start))
+                    .ok_or_else(|| format!("Unknown counter `{key}'"))?,
+            };
+            let (offset, value) = match value.as_str().strip_prefix('+') {
+                Some(value) => (true, value),
+                None => (false, value.as_str()),
+            };
+            Ok(CounterAssignment {
+                counter: counter.to_string(),
+                value: value
+                    .parse()
+                    .map_err(|_| format!("Invalid value `{value}' of counter `{key}'"))?,
+                offset,
+                start,
+            })
+        })
+        .collect()
+}
+
+/// The key of the counter of equations, which are numbered with the same prefix as environments.
+const EQ_COUNTER: &str = "eq";
+
+/// Finds all display equations `$$ ... $$` and `\\[ ... \\]` of `s` containing equation markers `{{eq}}{label}`
+/// and replaces the markers with `\tag{number}`, which KaTeX and MathJax display as "(number)" next to the equation.
+/// Equations are numbered by incrementing the entry `EQ_COUNTER` of `counter`;
+/// if a label is provided, an anchor is inserted before the equation and the hashmap `refs` is updated,
+/// so that `{{ref: label}}` is replaced with a link with text "(number)".
+/// Markers outside of display equations are left as is.
+fn find_and_replace_equations(
+    s: &str,
+    prefix: &str,
+    path: &Path,
+    config: &NumThmPreprocessor,
+    counter: &mut HashMap<String, u32>,
+    refs: &mut HashMap<String, LabelInfo>,
+) -> String {
+    let display: Regex = cached_regex(r"(?s)\$\$.*?\$\$|\\\\\[.*?\\\\\]");
+    let marker: Regex = config.syntax.eq_regex();
+
+    let output = display.replace_all(s, |caps: &regex::Captures| {
+        let mut anchors = String::new();
+        let body = marker.replace_all(&caps[0], |caps: &regex::Captures| {
+            // counters which were never set or were reset start from 1, like those of environments
+            let ctr = match counter.get(EQ_COUNTER).map(|n| n.checked_add(1)) {
+                None => 1,
+                Some(Some(next)) => next,
+                Some(None) => {
+                    warn!(
+                        "{}: Equation: Counter `{EQ_COUNTER}' overflows, numbering it {}",
+                        path.display(),
+                        u32::MAX
+                    );
+                    u32::MAX
+                }
+            };
+            counter.insert(EQ_COUNTER.to_string(), ctr);
+            let number = config.limit_depth(prefix, &ctr.to_string(), "Equation", path);
+            let local_number = match config.relative_numbers {
+                true => ctr.to_string(),
+                false => number.clone(),
+            };
+            let label = caps.name("label").map(|l| normalize_label(l.as_str()));
+            if let Some(label) = label.filter(|l| !l.is_empty()) {
+                let label_info = LabelInfo {
+                    env: EQ_COUNTER.to_string(),
+                    num_name: format!("({number})"),
+                    name: None,
+                    path: path.to_path_buf(),
+                    title: None,
+                    exported: false,
+                    local_name: config.relative_numbers.then(|| format!("({local_number})")),
+                    of: None,
+                    chapter: None,
+                    fields: BTreeMap::new(),
+                };
+                let label = insert_label(refs, label, label_info, config);
+                // the anchor can't be set inside the equation, which is rendered by KaTeX or MathJax
+                match config.output {
+                    OutputFormat::Html => {
+                        anchors += &format!("<a id=\"{}\"></a>", config.anchor(EQ_COUNTER, &label));
+                    }
+                    OutputFormat::Latex => return format!("\\label{{{label}}}"),
+                    OutputFormat::Markdown => {}
+                }
+            }
+            if config.output == OutputFormat::Latex {
+                return String::new();
+            }
+            format!("\\tag{{{local_number}}}")
+        });
+        anchors + &body
+    });
+
+    let breaks = line_breaks(&output);
+    for m in marker.find_iter(&output) {
+        warn!(
+            "{}:{}: Equation marker `{}' outside of a display equation",
+            path.display(),
+            line_number(&breaks, m.start()),
+            m.as_str()
+        );
+    }
+    output.to_string()
+}
+
+/// Finds and replaces all patterns {{ref: label}} where label is an existing key in hashmap `refs`
+/// with a link towards the relevant theorem.
+/// Returns an error if a title reference to a label without title is found and `config` requires it.
+fn find_and_replace_refs(
+    s: &str,
+    chap_path: &PathBuf,
+    refs: &HashMap<String, LabelInfo>,
+    config: &NumThmPreprocessor,
+) -> Result<String> {
+    let re: Regex = config.syntax.ref_regex();
+    let mut errors: Vec<String> = Vec::new();
+    let plain_contexts = match config.plain_refs {
+        PlainRefs::Link => vec![],
+        _ => plain_contexts(s),
+    };
+
+    // returns the markdown a single reference is replaced with, before hooks are run
+    let mut resolve = |caps: &regex::Captures| {
+        let raw_label = caps.name("label").unwrap().as_str().trim();
+        if raw_label.is_empty() {
+            // already reported as a malformed macro
+            return config.strings.unresolved.clone();
+        }
+        let label = normalize_label(raw_label);
+        if label != raw_label && refs.contains_key(&label) {
+            warn!(
+                "{}: Reference to `{raw_label}' contains extra whitespace, resolved to label `{label}'",
+                chap_path.display()
+            );
+        }
+        // labels of this book shadow labels of other books
+        let external = match refs.contains_key(&label) {
+            true => None,
+            false => config.external.get(&label),
+        };
+        if let Some(info) = refs.get(&label) {
+            if config.private_labels && !info.exported && &info.path != chap_path {
+                warn!(
+                    "{}: Label `{label}' is private to chapter `{}', export it with `{}'",
+                    chap_path.display(),
+                    info.path.display(),
+                    config.syntax.wrap(&format!("export: {label}"))
+                );
+                return config.strings.unresolved.clone();
+            }
+        }
+        if let Some(info) = refs.get(&label).or(external.map(|(info, _)| info)) {
+            let num_name = match &info.local_name {
+                Some(local_name) if &info.path == chap_path => local_name,
+                _ => &info.num_name,
+            };
+            let reftype = caps.name("reftype").unwrap().as_str();
+            let num_name = config.ref_num_name(num_name, info, reftype == "nref");
+            // the text given by the caller, e.g. {{ref: label | the key lemma}}, which is used as is
+            let custom_text = caps
+                .name("text")
+                .map(|text| text.as_str().trim())
+                .filter(|text| !text.is_empty());
+            let prose;
+            let text = match reftype {
+                _ if custom_text.is_some() => &num_name,
+                "ref" | "nref" => &num_name,
+                "pref" => {
+                    prose = prose_text(&num_name, info.chapter.as_ref());
+                    &prose
+                }
+                _ => {
+                    // this must be tref if there is a match
+                    match &info.title {
+                        Some(t) => t,
+                        // the label does not have an associated title
+                        None => {
+                            if config.tref_warn_untitled {
+                                warn!(
+                                    "{}: Title reference to label `{label}' which has no title",
+                                    chap_path.display()
+                                );
+                            }
+                            match config.tref_fallback {
+                                TrefFallback::Name => &num_name,
+                                TrefFallback::Label => &label,
+                                TrefFallback::Error => {
+                                    errors.push(format!(
+                                        "{}: Title reference to label `{label}' which has no title",
+                                        chap_path.display()
+                                    ));
+                                    &num_name
+                                }
+                            }
+                        }
+                    }
+                }
+            };
+            let template = match reftype {
+                "pref" => &config.templates.prose,
+                _ => &config.templates.reference,
+            };
+            let text = match custom_text {
+                Some(text) => text.to_string(),
+                None => fill_template(
+                    &fill_fields(template, &info.fields, &config.fields),
+                    text,
+                    &num_name,
+                    info.title.as_deref(),
+                    &label,
+                    info.chapter.as_ref(),
+                ),
+            };
+            // links can't be nested in image alt texts and in link texts and titles
+            let start = caps.get(0).unwrap().start();
+            if plain_contexts.iter().any(|range| range.contains(&start)) {
+                return match config.plain_refs {
+                    PlainRefs::Number if custom_text.is_none() => config.number(info).to_string(),
+                    _ => text,
+                };
+            }
+            if let Some((_, url)) = external {
+                return format!("[{text}]({})", config.external_url(url, &label, info));
+            }
+            let mut style = PathStyle::Relative;
+            for (attr, value) in caps
+                .name("attrs")
+                .map_or(vec![], |a| parse_attributes(a.as_str()))
+            {
+                match (attr, PathStyle::parse(value.as_str())) {
+                    ("path", Some(path_style)) => style = path_style,
+                    ("path", None) => warn!(
+                        "{}: Reference to `{label}': Unknown path style `{}'",
+                        chap_path.display(),
+                        value.as_str()
+                    ),
+                    _ => warn!(
+                        "{}: Reference to `{label}': Unknown attribute `{}'",
+                        chap_path.display(),
+                        value.as_str()
+                    ),
+                }
+            }
+            let rel_path = config.styled_path(style, chap_path, &info.path);
+            let anchor = config.anchor(&info.env, &label);
+            match reftype {
+                "ref" if config.output == OutputFormat::Latex && custom_text.is_none() => {
+                    format!("\\Cref{{{anchor}}}")
+                }
+                "nref" if config.output == OutputFormat::Latex && custom_text.is_none() => {
+                    format!("\\ref{{{anchor}}}")
+                }
+                _ => config.link(&text, &rel_path, &anchor),
+            }
+        } else {
+            warn!("Unknown reference: {}", label);
+            config.strings.unresolved.clone()
+        }
+    };
+
+    let output = re
+        .replace_all(s, |caps: &regex::Captures| {
+            let output = resolve(caps);
+            let label = &normalize_label(caps.name("label").unwrap().as_str());
+            let reference = Reference {
+                label,
+                title_ref: caps.name("reftype").unwrap().as_str() == "tref",
+                path: chap_path,
+                target: refs.get(label).map(|info| info.path.as_path()),
+                output: "",
+            };
+            config.hooks.reference(reference, output)
+        })
+        .to_string();
+
+    if errors.is_empty() {
+        Ok(output)
+    } else {
+        Err(Error::msg(errors.join("\n")))
+    }
+}
+
+/// Wraps all references `{{ref: label}}` of the chapter `chap_path` to environments of `refs`
+/// in a `span` of class `numthm-preview` together with a preview of the environment shown on hover,
+/// i.e., its header followed by its body if it is a block environment whose body is in `bodies`,
+/// references of the body being replaced with the numbered names of their targets. As the preview is inline,
+/// the body is put on a single line, without HTML tags, and with display equations and code blocks elided;
+/// its frozen regions are appended to `frozen`, the frozen regions of the chapter.
+fn insert_previews(
+    s: &str,
+    chap_path: &Path,
+    refs: &HashMap<String, LabelInfo>,
+    bodies: &HashMap<String, BlockBody>,
+    frozen: &mut Vec<String>,
+    config: &NumThmPreprocessor,
+) -> String {
+    let re: Regex = config.syntax.ref_regex();
+    let plain_contexts = match config.plain_refs {
+        PlainRefs::Link => vec![],
+        _ => plain_contexts(s),
+    };
+    re.replace_all(s, |caps: &regex::Captures| {
+        let label = normalize_label(&caps["label"]);
+        // equations and private environments of other chapters have no preview
+        let Some((info, env)) = refs
+            .get(&label)
+            .filter(|info| !config.private_labels || info.exported || info.path == chap_path)
+            .and_then(|info| Some((info, config.environments.get(&info.env)?)))
+        else {
+            return caps[0].to_string();
+        };
+        // references which are not links have no preview
+        let start = caps.get(0).unwrap().start();
+        if plain_contexts.iter().any(|range| range.contains(&start)) {
+            return caps[0].to_string();
+        }
+        let number = config.number(info);
+        let caption = info.title.as_deref().unwrap_or_default();
+        let title = match caption {
+            "" => String::new(),
+            caption => format!(" ({caption})"),
+        };
+        let header = format_header(
+            env,
+            &config
+                .name_case
+                .header
+                .apply(info.name.as_deref().unwrap_or(env.header_name())),
+            number,
+            &title,
+            caption,
+            &label,
+            None,
+        );
+        let body = bodies.get(&label).map_or(String::new(), |body| {
+            let text = re.replace_all(&body.body, |caps: &regex::Captures| {
+                refs.get(&normalize_label(&caps["label"]))
+                    .map_or(config.strings.unresolved.clone(), |info| info.num_name.clone())
+            });
+            let elided: Regex = cached_regex(r"(?s)\$\$.*?\$\$|<[^<>\n]*>");
+            let text = elided.replace_all(&text, |caps: &regex::Captures| match &caps[0] {
+                tag if tag.starts_with('<') => "",
+                _ => "…",
+            });
+            // code blocks are elided as well, and code spans kept on a single line
+            let placeholder: Regex = cached_regex("\u{E000}(?P<index>[0-9]+)\n*\u{E001}");
+            let text = placeholder.replace_all(&text, |caps: &regex::Captures| {
+                let index = caps["index"].parse::<usize>().unwrap();
+                match body.frozen[index].contains('\n') {
+                    true => "…".to_string(),
+                    false => format!("\u{E000}{index}\u{E001}"),
+                }
+            });
+            // vertical bars would end the cell of a table containing the reference
+            let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+            let text = import_frozen(&text.replace('|', "&#124;"), &body.frozen, frozen);
+            format!(" {text}")
+        });
+        format!(
+            "<span class=\"numthm-preview\">{}<span class=\"numthm-preview-body\" role=\"tooltip\">{header}{body}</span></span>",
+            &caps[0]
+        )
+    })
+    .to_string()
+}
+
+/// Returns the ranges of the image alt texts and of the link texts and titles of `s`, where links can't be nested.
+fn plain_contexts(s: &str) -> Vec<std::ops::Range<usize>> {
+    let re: Regex = cached_regex(
+        r#"!?\[(?P<text>[^\[\]\n]*)\]\([^()\s]*(?:\s+(?:"(?P<title>[^"\n]*)"|'(?P<title2>[^'\n]*)'))?\s*\)"#,
+    );
+    re.captures_iter(s)
+        .flat_map(|caps| {
+            ["text", "title", "title2"]
+                .into_iter()
+                .filter_map(move |group| caps.name(group).map(|m| m.range()))
+        })
+        .collect()
+}
+
+/// Returns the default text of prose references, i.e., the numbered name `num_name` followed by the number
+/// of the chapter, e.g. "Theorem 1.2 in Section 3.1", or by its title if it is unnumbered.
+fn prose_text(num_name: &str, chapter: Option<&ChapterInfo>) -> String {
+    match chapter {
+        Some(ChapterInfo {
+            number: Some(number),
+            ..
+        }) => format!("{num_name} in Section {number}"),
+        Some(ChapterInfo { title, .. }) => format!("{num_name} in “{title}”"),
+        None => num_name.to_string(),
+    }
+}
+
+/// Finds the labeled block environments `{{#key}}{label}[title] body {{/key}}` of `s` and returns their labels
+/// together with the byte ranges of their bodies, i.e., of the text between the header and the end of the block,
+/// without trailing whitespace.
+fn find_block_bodies(s: &str, config: &NumThmPreprocessor) -> Vec<(String, Range<usize>)> {
+    let block: Regex = cached_regex(&config.syntax.pattern(&format!(
+        r"(?P<kind>[#/])(?P<key>[\w-]+)(?P<star>\*)?(?:\s+{})?\s*",
+        config.syntax.capture()
+    )));
+    let mut bodies = Vec::new();
+    for caps in config.env_regex().captures_iter(s) {
+        let (Some(key), Some(label), Some(_)) =
+            (caps.name("key"), caps.name("label"), caps.name("block"))
+        else {
+            continue;
+        };
+        let start = caps.get(0).unwrap().end();
+        // blocks of the same environment may be nested
+        let mut depth = 0;
+        for end in block.captures_iter(&s[start..]) {
+            if &end["key"] != key.as_str() {
+                continue;
+            }
+            match (&end["kind"], depth) {
+                ("#", _) => depth += 1,
+                (_, 0) => {
+                    let body = s[start..start + end.get(0).unwrap().start()].trim_end();
+                    bodies.push((normalize_label(label.as_str()), start..start + body.len()));
+                    break;
+                }
+                _ => depth -= 1,
+            }
+        }
+    }
+    bodies
+}
+
+/// The characters opening the markers of the start and of the end of bodies of block environments, and the one
+/// closing both, see `mark_block_bodies`.
+const BODY_MARKERS: (char, char, char) = ('\u{E008}', '\u{E009}', '\u{E00A}');
+
+/// The body of a labeled block environment, numbered and without anchors, which is repeated by restatements
+/// and previews, possibly in other chapters.
+#[derive(Clone, Debug, Default)]
+struct BlockBody {
+    /// The body, whose frozen regions are placeholders referring to `frozen`.
+    body: String,
+    /// The frozen regions of the body.
+    frozen: Vec<String>,
+}
+
+/// Surrounds the bodies of the labeled block environments of `s` with markers, i.e., the label between
+/// the characters of `BODY_MARKERS`, so that the bodies can be extracted once numbered with `extract_block_bodies`.
+fn mark_block_bodies(s: &str, config: &NumThmPreprocessor) -> String {
+    let (start, end, close) = BODY_MARKERS;
+    let mut markers: Vec<(usize, String)> = Vec::new();
+    for (label, range) in find_block_bodies(s, config) {
+        markers.push((range.start, format!("{start}{label}{close}")));
+        markers.push((range.end, format!("{end}{label}{close}")));
+    }
+    // markers are inserted from the end, so that positions remain valid
+    markers.sort_by_key(|(position, _)| *position);
+    let mut output = s.to_string();
+    for (position, marker) in markers.into_iter().rev() {
+        output.insert_str(position, &marker);
+    }
+    output
+}
+
+/// Removes the markers inserted by `mark_block_bodies` from `s`, and returns the result together with the labels
+/// and bodies of the marked environments, whose frozen regions are taken from `frozen`.
+/// Bodies are stripped of anchors, so that restatements don't duplicate them, and of the markers of
+/// backreferences and solutions.
+fn extract_block_bodies(
+    s: &str,
+    frozen: &[String],
+    config: &NumThmPreprocessor,
+) -> (String, Vec<(String, BlockBody)>) {
+    let (start, end, close) = BODY_MARKERS;
+    let marker: Regex = cached_regex(&format!("{start}(?P<label>[^{close}]*){close}"));
+    let markers: Regex = cached_regex(&format!("[{start}{end}][^{close}]*{close}"));
+    let mut bodies = Vec::new();
+    for caps in marker.captures_iter(s) {
+        let from = caps.get(0).unwrap().end();
+        let Some(len) = s[from..].find(&format!("{end}{}{close}", &caps["label"])) else {
+            continue;
+        };
+        let body = markers.replace_all(&s[from..from + len], "");
+        let mut body_frozen = Vec::new();
+        let body = import_frozen(&strip_anchors(&body, config), frozen, &mut body_frozen);
+        bodies.push((
+            caps["label"].to_string(),
+            BlockBody {
+                body,
+                frozen: body_frozen,
+            },
+        ));
+    }
+    (markers.replace_all(s, "").to_string(), bodies)
+}
+
+/// Removes the anchors of environments and equations from `s`, as well as the markers of backreferences and
+/// solutions inserted by `insert_end_markers`.
+fn strip_anchors(s: &str, config: &NumThmPreprocessor) -> String {
+    let re: Regex = match config.output {
+        OutputFormat::Html => cached_regex(
+            r#"<a id="[^"]*"></a>\n?|<div id="[^"]*"></div>\n\n| id="[^"]*"|\n\n[\x{E004}\x{E006}][^\x{E005}\x{E007}]*[\x{E005}\x{E007}]"#,
+        ),
+        OutputFormat::Markdown => {
+            cached_regex(r"\n\n[\x{E004}\x{E006}][^\x{E005}\x{E007}]*[\x{E005}\x{E007}]")
+        }
+        OutputFormat::Latex => cached_regex(
+            r"\\label\{[^}\n]*\}|\n\n[\x{E004}\x{E006}][^\x{E005}\x{E007}]*[\x{E005}\x{E007}]",
+        ),
+    };
+    re.replace_all(s, "").to_string()
+}
+
+/// Returns `s`, whose frozen regions are placeholders referring to `regions`, with the regions it refers to
+/// appended to `frozen` and its placeholders renumbered accordingly.
+fn import_frozen(s: &str, regions: &[String], frozen: &mut Vec<String>) -> String {
+    let re: Regex = cached_regex("\u{E000}(?P<index>[0-9]+)(?P<newlines>\n*)\u{E001}");
+    re.replace_all(s, |caps: &regex::Captures| {
+        // frozen regions may contain other frozen regions, which are imported first
+        let region = import_frozen(
+            &regions[caps["index"].parse::<usize>().unwrap()],
+            regions,
+            frozen,
+        );
+        frozen.push(region);
+        format!("\u{E000}{}{}\u{E001}", frozen.len() - 1, &caps["newlines"])
+    })
+    .to_string()
+}
+
+/// The delimiters of the markers inserted for backreferences, see `replace_backref_markers`.
+const BACKREF_MARKERS: (char, char) = ('\u{E004}', '\u{E005}');
+
+/// The delimiters of the markers inserted for links to solutions, see `replace_solution_markers`.
+const SOLUTION_MARKERS: (char, char) = ('\u{E006}', '\u{E007}');
+
+/// Inserts a marker, i.e. the label between the characters `open` and `close`, at the end of each environment of `s`
+/// whose label satisfies `filter`, i.e., before the end `{{/key}}` of block environments, and at the end
+/// of the paragraph of other environments, i.e., at the next blank line or at the beginning of the next environment,
+/// in a paragraph of its own.
+fn insert_end_markers(
+    s: &str,
+    config: &NumThmPreprocessor,
+    (open, close): (char, char),
+    filter: impl Fn(&str) -> bool,
+) -> String {
+    let bodies: HashMap<String, Range<usize>> = find_block_bodies(s, config).into_iter().collect();
+    let headers: Vec<regex::Captures> = config.env_regex().captures_iter(s).collect();
+    let mut markers: Vec<(usize, String)> = Vec::new();
+    for (i, caps) in headers.iter().enumerate() {
+        let (Some(_), Some(label)) = (caps.name("key"), caps.name("label")) else {
+            continue;
+        };
+        let label = normalize_label(label.as_str());
+        if !filter(&label) {
+            continue;
+        }
+        let start = caps.get(0).unwrap().end();
+        let end = match (caps.name("block"), bodies.get(&label)) {
+            (Some(_), Some(body)) => body.end,
+            (Some(_), None) => continue,
+            (None, _) => {
+                let next = headers
+                    .get(i + 1)
+                    .map_or(s.len(), |next| next.get(0).unwrap().start());
+                let end = s[start..next].find("\n\n").map_or(next, |end| start + end);
+                start + s[start..end].trim_end().len()
+            }
+        };
+        markers.push((end, format!("\n\n{open}{label}{close}")));
+    }
+    // markers are inserted from the end, so that positions remain valid
+    markers.sort_by_key(|(end, _)| *end);
+    let mut output = s.to_string();
+    for (end, marker) in markers.into_iter().rev() {
+        output.insert_str(end, &marker);
+    }
+    output
+}
+
+/// Replaces the markers inserted with `BACKREF_MARKERS` by `insert_end_markers` in the chapter `chap_path`
+/// with the list of chapters referring to the labeled environment, e.g. "Referenced in: [§2.3](algebra/groups.md), [Rings](rings.md)"
+/// (numbered chapters being shown by their number, and unnumbered ones by their title),
+/// given by `backrefs`, or removes them if the environment is not referred to from other chapters.
+fn replace_backref_markers(
+    s: &str,
+    chap_path: &PathBuf,
+    backrefs: &HashMap<&str, Vec<&PathBuf>>,
+    chapter_infos: &HashMap<PathBuf, ChapterInfo>,
+    config: &NumThmPreprocessor,
+) -> String {
+    let re: Regex = cached_regex("\n\n\u{E004}(?P<label>[^\u{E005}]*)\u{E005}");
+    re.replace_all(s, |caps: &regex::Captures| {
+        let Some(paths) = backrefs
+            .get(&caps["label"])
+            .filter(|paths| !paths.is_empty())
+        else {
+            return String::new();
+        };
+        let links = paths
+            .iter()
+            .map(|path| {
+                let text = match chapter_infos.get(*path) {
+                    Some(ChapterInfo {
+                        number: Some(number),
+                        ..
+                    }) => format!("§{number}"),
+                    Some(ChapterInfo { title, .. }) => title.clone(),
+                    None => path.display().to_string(),
+                };
+                format!("[{text}]({})", compute_rel_path(chap_path, path))
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+        match config.output {
+            OutputFormat::Html => format!(
+                "\n\n<small class=\"numthm-backrefs\">{} {links}</small>",
+                config.strings.referenced_in
+            ),
+            _ => format!("\n\n{} {links}", config.strings.referenced_in),
+        }
+    })
+    .to_string()
+}
+
+/// Finds all patterns `{{solution: label}}` and replaces them with the header of the solution of the environment
+/// with label `label` among `entries`, which takes its number, e.g. "**Solution 2.3.**", the number linking to the
+/// environment, preceded by the anchor of the solution (see `solution_anchor`).
+fn find_and_replace_solutions(
+    s: &str,
+    chap_path: &PathBuf,
+    entries: &[EnvEntry],
+    config: &NumThmPreprocessor,
+) -> String {
+    let re: Regex = config.syntax.solution_regex();
+
+    re.replace_all(s, |caps: &regex::Captures| {
+        let label = normalize_label(caps.name("label").unwrap().as_str());
+        let Some(entry) = entries
+            .iter()
+            .find(|entry| entry.label.as_ref() == Some(&label))
+        else {
+            warn!(
+                "{}: Solution of unknown label `{label}'",
+                chap_path.display()
+            );
+            return config.strings.unresolved.clone();
+        };
+        let env = &config.environments[&entry.env];
+        let link_path = config.link_path(chap_path, &entry.path);
+        let target = config.anchor(&entry.env, &label);
+        let solution = &config.strings.solution;
+        // unnumbered environments are linked by the name of the solution
+        let header = match entry.number.is_empty() {
+            true => config.link(solution, &link_path, &target),
+            false => format!(
+                "{solution} {}",
+                config.link(&entry.number, &link_path, &target)
+            ),
+        };
+        let anchor = solution_anchor(&entry.env, &label, config);
+        match config.output {
+            OutputFormat::Html => format!(
+                "<a id=\"{anchor}\"></a>\n{}{header}.{}",
+                env.emph, env.emph_close
+            ),
+            OutputFormat::Markdown => match env.heading {
+                Some(level) => format!(
+                    "{} {}{header}.{}",
+                    "#".repeat(level),
+                    env.emph,
+                    env.emph_close
+                ),
+                None => format!("{}{header}.{}", env.emph, env.emph_close),
+            },
+            OutputFormat::Latex => format!("\\textbf{{{header}.}}\\label{{{anchor}}}"),
+        }
+    })
+    .to_string()
+}
+
+/// Returns the anchor of the solution of the environment with key `key` and label `label`, e.g. `thm:x-solution`.
+fn solution_anchor(key: &str, label: &str, config: &NumThmPreprocessor) -> String {
+    format!("{}-solution", config.anchor(key, label))
+}
+
+/// Replaces the markers inserted with `SOLUTION_MARKERS` by `insert_end_markers` in the chapter `chap_path` with
+/// a link to the solution of the environment among `entries`, located in the chapter given by `solutions`.
+fn replace_solution_markers(
+    s: &str,
+    chap_path: &PathBuf,
+    entries: &[EnvEntry],
+    solutions: &HashMap<String, PathBuf>,
+    config: &NumThmPreprocessor,
+) -> String {
+    let re: Regex = cached_regex("\n\n\u{E006}(?P<label>[^\u{E007}]*)\u{E007}");
+    re.replace_all(s, |caps: &regex::Captures| {
+        let label = &caps["label"];
+        let (Some(entry), Some(path)) = (
+            entries
+                .iter()
+                .find(|entry| entry.label.as_deref() == Some(label)),
+            solutions.get(label),
+        ) else {
+            return String::new();
+        };
+        let link = config.link(
+            &config.strings.solution,
+            &config.link_path(chap_path, path),
+            &solution_anchor(&entry.env, label, config),
+        );
+        match config.output {
+            OutputFormat::Html => {
+                format!("\n\n<small class=\"numthm-solution-link\">{link}</small>")
+            }
+            _ => format!("\n\n{link}"),
+        }
+    })
+    .to_string()
+}
+
+/// Finds all patterns `{{restate: label}}` standing on their own line and replaces them with the header of
+/// the environment with label `label` among `entries`, without anchor and without advancing counters, followed by
+/// its body if it is a block environment whose body is in `bodies`, the frozen regions of which are appended to
+/// `frozen`, the frozen regions of the chapter.
+fn find_and_replace_restatements(
+    s: &str,
+    chap_path: &Path,
+    entries: &[EnvEntry],
+    bodies: &HashMap<String, BlockBody>,
+    frozen: &mut Vec<String>,
+    config: &NumThmPreprocessor,
+) -> String {
+    let re: Regex = config.syntax.restate_regex();
+    let breaks = line_breaks(s);
+
+    re.replace_all(s, |caps: &regex::Captures| {
+        let label = normalize_label(caps.name("label").unwrap().as_str());
+        // restated blocks can't be nested in paragraphs
+        let m = caps.get(0).unwrap();
+        let before = s[..m.start()].rsplit('\n').next().unwrap_or_default();
+        let after = s[m.end()..].split('\n').next().unwrap_or_default();
+        if !before.trim().is_empty() || !after.trim().is_empty() {
+            warn!(
+                "{}:{}: Restatement of `{label}' must stand on its own line, leaving it as is",
+                chap_path.display(),
+                line_number(&breaks, m.start())
+            );
+            return caps[0].to_string();
+        }
+        let Some(entry) = entries
+            .iter()
+            .find(|entry| entry.label.as_ref() == Some(&label))
+        else {
+            warn!("{}: Unknown restated label `{label}'", chap_path.display());
+            return config.strings.unresolved.clone();
+        };
+        let env = &config.environments[&entry.env];
+        let caption = entry.title.as_deref().unwrap_or_default();
+        let title = match caption {
+            "" => String::new(),
+            caption => format!(" ({caption})"),
+        };
+        let header = format_header(
+            env,
+            &config.name_case.header.apply(env.header_name()),
+            &entry.number,
+            &title,
+            caption,
+            &label,
+            None,
+        );
+        match bodies.get(&label) {
+            Some(body) => format!(
+                "{}{header}{}{}",
+                block_start(&entry.env, None, config),
+                import_frozen(&body.body, &body.frozen, frozen),
+                block_end(env, config)
+            ),
+            None => header,
+        }
+    })
+    .to_string()
+}
+
+/// Finds all patterns `{{numthm-list: keys options}}` and replaces them with a list of the environments of `entries`
+/// selected by `keys` (a comma-separated list of environment keys, all environments if empty) and `options`,
+/// linking to those with a label; options are `chapter=all|current` (the environments of the whole book or of
+/// the current chapter only), `title-only` (only environments with a title), `sort=number|title`,
+/// and `group=none|chapter` (one list per chapter, under a link to the chapter).
+/// Patterns `{{numthm-index: keys options}}` are replaced likewise, with environments grouped by chapter by default.
+fn find_and_replace_lists(
+    s: &str,
+    chap_path: &PathBuf,
+    entries: &[EnvEntry],
+    config: &NumThmPreprocessor,
+) -> String {
+    let re: Regex = cached_regex(&config.syntax.pattern(&format!(
+        r"numthm-(?P<kind>list|index)(?::\s*(?P<args>{}))?\s*",
+        config.syntax.capture()
+    )));
+
+    re.replace_all(s, |caps: &regex::Captures| {
+        let mut keys: Vec<&str> = Vec::new();
+        let mut current_chapter = false;
+        let mut title_only = false;
+        let mut unnumbered = false;
+        let mut by_title = false;
+        let mut by_chapter = &caps["kind"] == "index";
+        for arg in caps
+            .name("args")
+            .map_or("", |a| a.as_str())
+            .split_whitespace()
+        {
+            match arg.split_once('=') {
+                Some(("chapter", "all")) => current_chapter = false,
+                Some(("chapter", "current")) => current_chapter = true,
+                Some(("sort", "number")) => by_title = false,
+                Some(("sort", "title")) => by_title = true,
+                Some(("group", "none")) => by_chapter = false,
+                Some(("group", "chapter")) => by_chapter = true,
+                None if arg == "title-only" => title_only = true,
+                None if arg == "unnumbered" => unnumbered = true,
+                None => keys.extend(arg.split(',').filter(|key| !key.is_empty())),
+                Some(_) => warn!("{}: Unknown list option `{arg}'", chap_path.display()),
+            }
+        }
+        for key in keys.iter() {
+            if !config.environments.contains_key(*key) {
+                warn!("{}: Unknown environment `{key}'", chap_path.display());
+            }
+        }
+
+        let listed = entries
+            .iter()
+            .filter(|entry| keys.is_empty() || keys.contains(&entry.env.as_str()))
+            .filter(|entry| !current_chapter || &entry.path == chap_path)
+            .filter(|entry| !title_only || entry.title.is_some())
+            // unnumbered environments are listed by their title
+            .filter(|entry| entry.numbered || (unnumbered && entry.title.is_some()));
+        // environments of a chapter are consecutive since chapters are processed one after the other
+        let mut groups: Vec<Vec<&EnvEntry>> = Vec::new();
+        for entry in listed {
+            match groups.last_mut() {
+                Some(group) if !by_chapter || group[0].path == entry.path => group.push(entry),
+                _ => groups.push(vec![entry]),
+            }
+        }
+        if by_title {
+            for group in groups.iter_mut() {
+                // environments without title come last
+                group.sort_by_key(|entry| (entry.title.is_none(), entry.title.clone()));
+            }
+        }
+
+        let list = |group: &[&EnvEntry]| {
+            group
+                .iter()
+                .map(|entry| {
+                    let num_name = match &entry.local_name {
+                        Some(local_name) if &entry.path == chap_path => local_name,
+                        _ => &entry.num_name,
+                    };
+                    let num_name = config.cased(num_name, &entry.env, config.name_case.list);
+                    let text = match &entry.title {
+                        Some(title) if !entry.numbered => title.clone(),
+                        Some(title) => format!("{num_name} ({title})"),
+                        None => num_name.clone(),
+                    };
+                    let text = fill_template(
+                        &fill_fields(&config.templates.list, &entry.fields, &config.fields),
+                        &text,
+                        &num_name,
+                        entry.title.as_deref(),
+                        entry.label.as_deref().unwrap_or_default(),
+                        entry.chapter.as_ref(),
+                    );
+                    match &entry.label {
+                        Some(label) => {
+                            let rel_path = config.link_path(chap_path, &entry.path);
+                            let anchor = config.anchor(&entry.env, label);
+                            format!("- {}", config.link(&text, &rel_path, &anchor))
+                        }
+                        None => format!("- {text}"),
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join("\n")
+        };
+        if !by_chapter {
+            return list(groups.first().map_or(&[][..], Vec::as_slice));
+        }
+        groups
+            .iter()
+            .map(|group| {
+                let path = &group[0].path;
+                let chapter = group[0].chapter.clone().unwrap_or_default();
+                let name = match &chapter.number {
+                    // as in the sidebar of mdBook
+                    Some(number) => format!("{number}. {}", chapter.title),
+                    None => chapter.title,
+                };
+                // links to the current chapter are never empty
+                let link = match config.link_path(chap_path, path) {
+                    link if link.is_empty() => path
+                        .file_name()
+                        .map_or_else(String::new, |name| name.to_string_lossy().into_owned()),
+                    link => link,
+                };
+                format!("**[{name}]({link})**\n\n{}", list(group))
+            })
+            .collect::<Vec<String>>()
+            .join("\n\n")
+    })
+    .to_string()
+}
+
+/// Fills `template` by replacing the variables `{text}` (the default text), `{name}` (the numbered name),
+/// `{title}`, `{label}`, `{chapter}` (the title of the chapter of the environment) and `{chapter_number}`
+/// with their values, which are empty if undefined; other patterns are left as is.
+fn fill_template(
+    template: &str,
+    text: &str,
+    name: &str,
+    title: Option<&str>,
+    label: &str,
+    chapter: Option<&ChapterInfo>,
+) -> String {
+    let re: Regex = cached_regex(r"\{(?P<var>\w+)\}");
+    re.replace_all(template, |caps: &regex::Captures| {
+        match caps.name("var").unwrap().as_str() {
+            "text" => text,
+            "name" => name,
+            "title" => title.unwrap_or_default(),
+            "label" => label,
+            "chapter" => chapter.map_or("", |c| c.title.as_str()),
+            "chapter_number" => chapter
+                .and_then(|c| c.number.as_deref())
+                .unwrap_or_default(),
+            _ => &caps[0],
+        }
+        .to_string()
+    })
+    .to_string()
+}
+
+/// The names which can't be used as fields, being attributes or variables of templates.
+const RESERVED_FIELDS: &[&str] = &[
+    "of",
+    "number",
+    "lang",
+    "path",
+    "text",
+    "name",
+    "title",
+    "caption",
+    "label",
+    "emph",
+    "chapter",
+    "chapter_number",
+];
+
+/// Replaces the variables `{field}` of `template` for the fields `declared` in the configuration with their values
+/// among `fields`, fields which are not given being empty; other variables are left as is.
+fn fill_fields(template: &str, fields: &BTreeMap<String, String>, declared: &[String]) -> String {
+    if declared.is_empty() {
+        return template.to_string();
+    }
+    let re: Regex = cached_regex(r"\{(?P<var>\w+)\}");
+    re.replace_all(template, |caps: &regex::Captures| {
+        let var = caps.name("var").unwrap().as_str();
+        match declared.iter().any(|field| field == var) {
+            true => fields.get(var).cloned().unwrap_or_default(),
+            false => caps[0].to_string(),
+        }
+    })
+    .to_string()
+}
+
+/// Finds all wiki-style links `[[label]]` and `[[label|text]]` where `label` is a label, i.e., `is_label(label)`,
+/// and replaces them with the references `{{ref: label}}` and `{{ref: label | text}}`, respectively.
+/// Other wiki-style links (e.g. to notes) and embeds `![[...]]` are left as is.
+fn find_and_replace_wiki_links(
+    s: &str,
+    syntax: &MacroSyntax,
+    is_label: impl Fn(&str) -> bool,
+) -> String {
+    let re: Regex =
+        cached_regex(r"(?P<bang>!)?\[\[(?P<label>[^\]|\n]+?)\s*(?:\|(?P<text>[^\]\n]*))?\]\]");
+
+    re.replace_all(s, |caps: &regex::Captures| {
+        let label = caps.name("label").unwrap().as_str().trim();
+        if caps.name("bang").is_some() || !is_label(&normalize_label(label)) {
+            return caps[0].to_string();
+        }
+        match caps.name("text").map(|text| text.as_str().trim()) {
+            Some(text) if !text.is_empty() => syntax.wrap(&format!("ref: {label} | {text}")),
+            _ => syntax.wrap(&format!("ref: {label}")),
+        }
+    })
+    .to_string()
+}
+
+/// Finds all bare mentions of labels containing a colon (e.g. `thm:main`) which can be referred to from the chapter
+/// `s` at `chap_path`, i.e., `linkable(label)`, in the text of `s`, i.e., outside of macros and their labels
+/// (e.g. `{{thm}}{thm:main}`), HTML tags, heading attributes, draft badges, link texts and destinations, and code,
+/// and replaces them with references `{{ref: label}}`, reporting each of them.
+fn auto_link_labels(
+    s: &str,
+    chap_path: &Path,
+    config: &NumThmPreprocessor,
+    linkable: impl Fn(&str) -> bool,
+) -> String {
+    let syntax = &config.syntax;
+    let protected: Regex = cached_regex(&format!(
+        r"(?s:```.*?```)|`[^`\n]*`|<[^<>\n]*>|\{{#[^}}\n]*\}}|⟨[^⟩\n]*⟩|\[[^\]\n]*\](?:\([^)\n]*\))?|\]\([^)\n]*\)|{}.*?{}(?:\{{[^}}\n]*\}})?",
+        regex::escape(&syntax.open),
+        regex::escape(&syntax.close)
+    ));
+    // labels start and end with a word character, so that trailing punctuation is not part of them
+    let token: Regex = cached_regex(r"\w(?:[\w:.\-]*\w)?");
+    let breaks = line_breaks(s);
+
+    // replaces the labels of the fragment `text` of `s` starting at position `start`
+    let link = |text: &str, start: usize| {
+        token
+            .replace_all(text, |caps: &regex::Captures| {
+                let label = &caps[0];
+                if !label.contains(':') || !linkable(label) {
+                    return label.to_string();
+                }
+                info!(
+                    "{}:{}: Auto-linked label `{label}'",
+                    chap_path.display(),
+                    line_number(&breaks, start + caps.get(0).unwrap().start())
+                );
+                syntax.wrap(&format!("ref: {label}"))
+            })
+            .to_string()
+    };
+
+    let mut output = String::with_capacity(s.len());
+    let mut last = 0;
+    for m in protected.find_iter(s) {
+        output.push_str(&link(&s[last..m.start()], last));
+        output.push_str(m.as_str());
+        last = m.end();
+    }
+    output.push_str(&link(&s[last..], last));
+    output
+}
+
+/// Finds all labels given to environments and equations and returns the corresponding line numbers, labels,
+/// and keys of the environments, `eq` for equations.
+fn find_labels(s: &str, config: &NumThmPreprocessor) -> Vec<(usize, String, String)> {
+    let breaks = line_breaks(s);
+    let envs = config
+        .env_regex()
+        .captures_iter(s)
+        .filter_map(|caps| Some((caps.name("label")?, caps.name("key")?.as_str())))
+        .collect::<Vec<_>>();
+    let equations = config
+        .syntax
+        .eq_regex()
+        .captures_iter(s)
+        .filter_map(|caps| Some((caps.name("label")?, EQ_COUNTER)))
+        .collect::<Vec<_>>();
+    let mut labels: Vec<(usize, String, String)> = envs
+        .into_iter()
+        .chain(equations)
+        .map(|(label, key)| {
+            (
+                label.start(),
+                normalize_label(label.as_str()),
+                key.to_string(),
+            )
+        })
+        .filter(|(_, label, _)| !label.is_empty())
+        .collect();
+    labels.sort();
+    labels
+        .into_iter()
+        .map(|(offset, label, key)| (line_number(&breaks, offset), label, key))
+        .collect()
+}
+
+/// Finds all patterns {{ref: label}} and {{tref: label}} and returns the corresponding line numbers and labels.
+fn find_refs(s: &str, syntax: &MacroSyntax) -> Vec<(usize, String)> {
+    let re: Regex = syntax.ref_regex();
+    let breaks = line_breaks(s);
+
+    re.captures_iter(s)
+        .map(|caps| {
+            let label = caps.name("label").unwrap();
+            (
+                line_number(&breaks, label.start()),
+                normalize_label(label.as_str()),
+            )
+        })
+        .collect()
+}
+
+/// Generates the content of a chapter located at `report_path`
+/// listing all references `occurrences` together with their resolved target.
+fn reference_report(
+    occurrences: &[RefOccurrence],
+    report_path: &PathBuf,
+    refs: &HashMap<String, LabelInfo>,
+    config: &NumThmPreprocessor,
+) -> String {
+    let mut report = String::from(
+        "# Reference Report\n\n\
+         | Location | Label | Target | Title |\n\
+         |----------|-------|--------|-------|\n",
+    );
+    for occurrence in occurrences {
+        let path = &occurrence.path;
+        let location = format!(
+            "[{}:{}]({})",
+            path.display(),
+            occurrence.line,
+            compute_rel_path(report_path, path)
+        );
+        let label = &occurrence.label;
+        let (target, title) = match refs.get(label) {
+            Some(info) => (
+                format!(
+                    "[{}]({}#{})",
+                    info.num_name,
+                    compute_rel_path(report_path, &info.path),
+                    config.anchor(&info.env, label)
+                ),
+                info.title.clone().unwrap_or_default(),
+            ),
+            None => (config.strings.unresolved.clone(), String::new()),
+        };
+        report.push_str(&format!(
+            "| {location} | `{label}` | {target} | {title} |\n"
+        ));
+    }
+    report
+}
+
+/// Generates the content of a completions file listing all labels of `refs` in the format given by `config`.
+fn completions(refs: &HashMap<String, LabelInfo>, config: &NumThmPreprocessor) -> Result<String> {
+    // labels are sorted so that the file is stable across builds
+    let sorted_refs: BTreeMap<&String, &LabelInfo> = refs.iter().collect();
+    let content = match config.completions_format {
+        CompletionsFormat::Json => serde_json::to_string_pretty(&sorted_refs)?,
+        CompletionsFormat::Vscode => {
+            let snippets: serde_json::Map<String, serde_json::Value> = sorted_refs
+                .iter()
+                .map(|(label, info)| {
+                    let description = match &info.title {
+                        Some(title) => format!("{} ({title})", info.num_name),
+                        None => info.num_name.clone(),
+                    };
+                    let snippet = serde_json::json!({
+                        "prefix": label,
+                        "body": config.syntax.wrap(&format!("ref: {label}")),
+                        "description": description,
+                    });
+                    (label.to_string(), snippet)
+                })
+                .collect();
+            serde_json::to_string_pretty(&snippets)?
+        }
+    };
+    Ok(content)
+}
+
+/// The version of the format of the context file, incremented on breaking changes.
+const CONTEXT_VERSION: u32 = 1;
+
+/// Generates the content of the context file for custom renderers, which lists all numbered environments
+/// of `entries` in the order in which they appear in the book, together with their numbers and anchors.
+fn render_context(entries: &[EnvEntry], config: &NumThmPreprocessor) -> Result<String> {
+    let environments: Vec<serde_json::Value> = entries
+        .iter()
+        .filter(|entry| entry.numbered)
+        .map(|entry| {
+            serde_json::json!({
+                "key": entry.env,
+                "number": entry.number,
+                "num_name": entry.num_name,
+                "label": entry.label,
+                "title": entry.title,
+                "path": entry.path,
+                "anchor": entry.label.as_ref().map(|label| config.anchor(&entry.env, label)),
+                "chapter": entry.chapter,
+            })
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&serde_json::json!({
+        "version": CONTEXT_VERSION,
+        "environments": environments,
+    }))?)
+}
+
+/// Generates the summary of reference integrity, i.e. the number of references `occurrences`, of broken ones,
+/// and of labels of `refs` which are never referred to, together with a status suitable for badges,
+/// e.g. "OK" or "2 broken".
+fn integrity_summary(
+    occurrences: &[RefOccurrence],
+    refs: &HashMap<String, LabelInfo>,
+    config: &NumThmPreprocessor,
+) -> Result<String> {
+    let broken = occurrences
+        .iter()
+        .filter(|occurrence| {
+            !refs.contains_key(&occurrence.label)
+                && !config.external.contains_key(&occurrence.label)
+        })
+        .count();
+    let referenced: HashSet<&str> = occurrences
+        .iter()
+        .map(|occurrence| occurrence.label.as_str())
+        .collect();
+    let unused = refs
+        .keys()
+        .filter(|label| !referenced.contains(label.as_str()))
+        .count();
+    let status = match broken {
+        0 => "OK".to_string(),
+        n => format!("{n} broken"),
+    };
+    Ok(serde_json::to_string_pretty(&serde_json::json!({
+        "references": occurrences.len(),
+        "broken": broken,
+        "unused_labels": unused,
+        "status": status,
+    }))?)
+}
+
+/// Returns a markdown report of the changes between the labels `previous` of a previous build and the labels `refs`:
+/// new labels, removed labels, and renumbered or retitled environments.
+fn diff_report(previous: &HashMap<String, LabelInfo>, refs: &HashMap<String, LabelInfo>) -> String {
+    // labels are sorted so that the report is stable across builds
+    let previous: BTreeMap<&String, &LabelInfo> = previous.iter().collect();
+    let current: BTreeMap<&String, &LabelInfo> = refs.iter().collect();
+    let describe = |info: &LabelInfo| match &info.title {
+        Some(title) => format!("{} ({title})", info.num_name),
+        None => info.num_name.clone(),
+    };
+
+    let mut report = String::from("# Numbering Changes\n");
+    let mut section = |title: &str, lines: Vec<String>| {
+        report.push_str(&format!("\n## {title}\n\n"));
+        if lines.is_empty() {
+            report.push_str("None.\n");
+        }
+        for line in lines {
+            report.push_str(&format!("- {line}\n"));
+        }
+    };
+    section(
+        "New Labels",
+        current
+            .iter()
+            .filter(|(label, _)| !previous.contains_key(*label))
+            .map(|(label, info)| format!("`{label}`: {}", describe(info)))
+            .collect(),
+    );
+    section(
+        "Removed Labels",
+        previous
+            .iter()
+            .filter(|(label, _)| !current.contains_key(*label))
+            .map(|(label, info)| format!("`{label}`: {}", describe(info)))
+            .collect(),
+    );
+    section(
+        "Renumbered",
+        current
+            .iter()
+            .filter_map(|(label, info)| {
+                let old = previous.get(label)?;
+                (old.num_name != info.num_name)
+                    .then(|| format!("`{label}`: {} → {}", old.num_name, info.num_name))
+            })
+            .collect(),
+    );
+    section(
+        "Retitled",
+        current
+            .iter()
+            .filter_map(|(label, info)| {
+                let old = previous.get(label)?;
+                (old.title != info.title).then(|| {
+                    format!(
+                        "`{label}`: {} → {}",
+                        old.title.as_deref().unwrap_or("(no title)"),
+                        info.title.as_deref().unwrap_or("(no title)")
+                    )
+                })
+            })
+            .collect(),
+    );
+    report
+}
+
+/// Returns an `[output.html.redirect]` table mapping the URLs of the anchors of the labels `previous` of a previous
+/// build to their URLs in the current build with labels `refs`, for labels whose chapter or anchor changed
+/// and for renamed labels, i.e., removed labels whose environment kept its number and chapter under a new label.
+fn redirect_map(
+    previous: &HashMap<String, LabelInfo>,
+    refs: &HashMap<String, LabelInfo>,
+    config: &NumThmPreprocessor,
+) -> String {
+    let url = |label: &str, info: &LabelInfo| {
+        format!(
+            "/{}#{}",
+            info.path.with_extension("html").display(),
+            config.anchor(&info.env, label)
+        )
+    };
+    // labels are sorted so that the map is stable across builds
+    let mut redirects: BTreeMap<String, String> = BTreeMap::new();
+    for (label, old) in previous.iter() {
+        let new = match refs.get(label) {
+            Some(info) => Some((label, info)),
+            None => refs.iter().find(|(new_label, info)| {
+                !previous.contains_key(*new_label)
+                    && info.env == old.env
+                    && info.num_name == old.num_name
+                    && info.path == old.path
+            }),
+        };
+        if let Some((new_label, info)) = new {
+            let (from, to) = (url(label, old), url(new_label, info));
+            if from != to {
+                redirects.insert(from, to);
+            }
+        }
+    }
+
+    let mut map = String::from("[output.html.redirect]\n");
+    for (from, to) in redirects {
+        map.push_str(&format!("\"{from}\" = \"{to}\"\n"));
+    }
+    map
+}
+
+/// Finds the environments of `entries` whose title is already used by a previous environment of the same type,
+/// except titles of `ignore`, and returns them together with the first environment with the same title.
+fn find_duplicate_titles<'a>(
+    entries: &'a [EnvEntry],
+    ignore: &HashSet<String>,
+) -> Vec<(&'a EnvEntry, &'a EnvEntry)> {
+    let mut first: HashMap<(&str, &str), &EnvEntry> = HashMap::new();
+    let mut duplicates = Vec::new();
+    for entry in entries.iter().filter(|entry| entry.numbered) {
+        let Some(title) = entry.title.as_deref().map(str::trim) else {
+            continue;
+        };
+        if ignore.contains(title) {
+            continue;
+        }
+        match first.get(&(entry.env.as_str(), title)) {
+            Some(previous) => duplicates.push((entry, *previous)),
+            None => {
+                first.insert((&entry.env, title), entry);
+            }
+        }
+    }
+    duplicates
+}
+
+/// The names of the macros other than environments, e.g. `ref` for `{{ref: label}}`.
+const MACRO_NAMES: &[&str] = &[
+    "selfnum",
+    "selfref",
+    "ref",
+    "tref",
+    "pref",
+    "nref",
+    "export",
+    "restate",
+    "solution",
+    "snippet-quote",
+    "snippet",
+    "numthm-count",
+    "numthm-last",
+    "set-counter",
+    "numthm-list",
+    "numthm-index",
+    "numthm-freeze",
+    "numthm-unfreeze",
+    "qed",
+    "eq",
+];
+
+/// Finds all patterns `{{word}}` (possibly with attributes) which look like environments
+/// but whose key `word` is not configured, and returns their line numbers and keys.
+fn find_unknown_environments(s: &str, config: &NumThmPreprocessor) -> Vec<(usize, String)> {
+    let syntax = &config.syntax;
+    let re: Regex = cached_regex(&syntax.pattern(&format!(
+        r"\s*(?P<key>[A-Za-z][\w-]*)(?:\s+{})?\s*",
+        syntax.capture()
+    )));
+    let breaks = line_breaks(s);
+
+    re.captures_iter(s)
+        .map(|caps| caps.name("key").unwrap())
+        .filter(|key| {
+            !config.environments.contains_key(key.as_str()) && !MACRO_NAMES.contains(&key.as_str())
+        })
+        .map(|key| (line_number(&breaks, key.start()), key.as_str().to_string()))
+        .collect()
+}
+
+/// Finds all fragments of `s` which look like macros of the preprocessor (i.e., the opening delimiter followed by
+/// an environment key or a macro name) but are malformed, e.g. `{{thm}` or `{{ref: }}`,
+/// and returns the corresponding line numbers and fragments.
+fn find_malformed_macros(s: &str, config: &NumThmPreprocessor) -> Vec<(usize, String)> {
+    let syntax = &config.syntax;
+    let keys = config
+        .environments
+        .keys()
+        .map(|key| regex::escape(key))
+        .collect::<Vec<String>>()
+        .join("|");
+    let names = MACRO_NAMES.join("|");
+    // matches the beginning of anything which looks like a macro, e.g. "{{ thm"
+    let candidate: Regex = cached_regex(&format!(
+        r"{}\s*{}(?:{keys}|{names})\b",
+        regex::escape(&syntax.open),
+        syntax.namespace_pattern()
+    ));
+    // labels start with a character which is neither a space nor the beginning of the closing delimiter
+    let label_start = format!(
+        r"[^\s{}]",
+        regex::escape(&syntax.close.chars().next().unwrap().to_string())
+    );
+    // matches well-formed macros at the beginning of the string
+    let well_formed: Regex = cached_regex(&format!(
+        r"^(?:{}|{}|{}|{}|{}|{}|{}|{}|{}|{}|{})",
+        syntax.pattern(&format!(
+            r"(?:(?:{keys})\*?(?:\s+{})?|selfnum|selfref)",
+            syntax.capture()
+        )),
+        syntax.pattern(&format!(
+            r"(?:ref|tref|pref|nref)(?:\s+(?:[\w-]+\s*=\s*[\w-]+\s*)+)?:\s*{label_start}{}",
+            syntax.capture()
+        )),
+        syntax.pattern(&format!(r"export:\s*{label_start}{}", syntax.capture())),
+        syntax.pattern(&format!(
+            r"(?:restate|solution):\s*{label_start}{}",
+            syntax.capture()
+        )),
+        syntax.pattern(r"snippet(?:-quote)?:\s*\S+?\s*"),
+        syntax.pattern(r"numthm-(?:count|last):\s*\S+?\s*"),
+        syntax.pattern(SET_COUNTER_PATTERN),
+        syntax.pattern(&format!(r"numthm-(?:list|index)(?::{})?", syntax.capture())),
+        syntax.pattern(r"numthm-(?:freeze(?::\s*\S+?)?|unfreeze)\s*"),
+        syntax.pattern(r"qed\s*"),
+        syntax.pattern(r"eq\s*"),
+    ));
+    let breaks = line_breaks(s);
+
+    candidate
+        .find_iter(s)
+        .filter(|m| !well_formed.is_match(&s[m.start()..]))
+        .map(|m| {
+            // the fragment extends to the closing delimiter, if any, on the same line and before the next macro
+            let rest = &s[m.start()..];
+            let mut end = rest.find('\n').unwrap_or(rest.len());
+            if let Some(i) = rest[syntax.open.len()..end].find(&syntax.open) {
+                end = syntax.open.len() + i;
+            }
+            if let Some(i) = rest[..end].find(&syntax.close) {
+                end = i + syntax.close.len();
+            }
+            let rest = rest[..end].trim_end();
+            let fragment: String = rest.chars().take(40).collect();
+            (line_number(&breaks, m.start()), fragment)
+        })
+        .collect()
+}
+
+/// Finds all plain markdown links `[text](path#label)` where label is an existing key in hashmap `refs`
+/// and returns the corresponding line numbers and labels.
+fn find_plain_links(s: &str, refs: &HashMap<String, LabelInfo>) -> Vec<(usize, String)> {
+    // matches [text](path#fragment) where path is optional
+    let re: Regex = cached_regex(r"\[[^\]]*\]\([^)#\s]*#(?P<fragment>[^)\s]+)\)");
+    let breaks = line_breaks(s);
+
+    re.captures_iter(s)
+        .filter_map(|caps| {
+            let fragment = caps.name("fragment").unwrap();
+            refs.contains_key(fragment.as_str()).then(|| {
+                (
+                    line_number(&breaks, fragment.start()),
+                    fragment.as_str().to_string(),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Returns the positions of the line breaks of `s`, from which line numbers are computed with `line_number`.
+fn line_breaks(s: &str) -> Vec<usize> {
+    s.match_indices('\n').map(|(i, _)| i).collect()
+}
+
+/// Returns the line number (starting from 1) of the byte at position `offset` in a string
+/// whose line breaks are at positions `breaks`.
+fn line_number(breaks: &[usize], offset: usize) -> usize {
+    breaks.partition_point(|&b| b < offset) + 1
+}
+
+/// Computes the relative path from the folder containing `chap_path` to the file `path_to_ref`.
+fn compute_rel_path(chap_path: &PathBuf, path_to_ref: &PathBuf) -> String {
+    if chap_path == path_to_ref {
+        return "".to_string();
+    }
+    let mut local_chap_path = chap_path.clone();
+    local_chap_path.pop();
+    format!(
+        "{}",
+        diff_paths(path_to_ref, &local_chap_path).unwrap().display()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mdbook::Config;
+    use std::str::FromStr;
+
+    const SECNUM: &str = "1.2.";
+
+    lazy_static! {
+        static ref CONFIG: NumThmPreprocessor = NumThmPreprocessor::default();
+        static ref PATH: PathBuf = "crypto/groups.md".into();
+    }
+
+    /// Returns an empty temporary directory named after `name` and the current process, so that tests and
+    /// concurrent test runs don't share files.
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("numthm-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    /// Numbers the environments of the chapter `s` as a single part, see `number_envs`.
+    fn find_and_replace_envs(
+        s: &str,
+        prefix: &str,
+        path: &Path,
+        config: &NumThmPreprocessor,
+        counter: &mut HashMap<String, u32>,
+        refs: &mut HashMap<String, LabelInfo>,
+        entries: &mut Vec<EnvEntry>,
+    ) -> String {
+        let mut chapter = ChapterEnvs::default();
+        let output = number_envs(
+            s,
+            prefix,
+            path,
+            config,
+            counter,
+            refs,
+            entries,
+            &mut chapter,
+        );
+        close_envs(s, &output, path, config, &chapter)
+    }
+
+    /// Builds a preprocessor context from the content of a `book.toml` file.
+    fn context(book_toml: &str) -> PreprocessorContext {
+        let config = Config::from_str(book_toml).unwrap();
+        serde_json::from_value(serde_json::json!({
+            "root": "",
+            "config": config,
+            "renderer": "html",
+            "mdbook_version": mdbook::MDBOOK_VERSION,
+        }))
+        .unwrap()
+    }
+
+    /// Returns the content of all chapters of `book` in summary order.
+    fn contents(book: &Book) -> Vec<String> {
+        book.iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(chapter) => Some(
+                    chapter
+                        .content
+                        .strip_prefix(PROCESSED_MARKER)
+                        .unwrap_or(&chapter.content)
+                        .to_string(),
+                ),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn wo_label_wo_title() {
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{prop}}");
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let expected = String::from("**Proposition 1.2.1.**");
+        assert_eq!(output, expected);
+        assert!(refs.is_empty());
+    }
+
+    #[test]
+    fn wo_label_wo_title_replace_default() {
+        let mut config = NumThmPreprocessor::default();
+        config
+            .environments
+            .insert(String::from("prop"), Env::create("Proposal", "*"));
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{prop}}");
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &config,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let expected = String::from("*Proposal 1.2.1.*");
+        assert_eq!(output, expected);
+        assert!(refs.is_empty());
+    }
+
+    #[test]
+    fn with_label_wo_title() {
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{prop}}{prop:lagrange}");
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let expected = String::from(
+            "<a id=\"prop:lagrange\"></a>\n\
+            **Proposition 1.2.1.**",
+        );
+        assert_eq!(output, expected);
+        assert_eq!(refs.len(), 1);
+        assert_eq!(
+            *refs.get("prop:lagrange").unwrap(),
+            LabelInfo {
+                env: "prop".to_string(),
+                num_name: "Proposition 1.2.1".to_string(),
+                name: None,
+                path: "crypto/groups.md".into(),
+                title: None,
+                exported: false,
+                local_name: None,
+                of: None,
+                chapter: None,
+                fields: BTreeMap::new(),
+            }
+        )
+    }
+
+    #[test]
+    fn wo_label_with_title() {
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{prop}}[Lagrange Theorem]");
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let expected = String::from("**Proposition 1.2.1 (Lagrange Theorem).**");
+        assert_eq!(output, expected);
+        assert!(refs.is_empty());
+    }
+
+    #[test]
+    fn with_label_with_title() {
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let expected = String::from(
+            "<a id=\"prop:lagrange\"></a>\n\
+            **Proposition 1.2.1 (Lagrange Theorem).**",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn double_label() {
+        let mut refs = HashMap::new();
+        let input = String::from(
+            r"{{prop}}{prop:lagrange}[Lagrange Theorem] {{thm}}{prop:lagrange}[Another Lagrange Theorem]",
+        );
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let expected = String::from(
+            "<a id=\"prop:lagrange\"></a>\n\
+            **Proposition 1.2.1 (Lagrange Theorem).** \
+            <a id=\"prop:lagrange\"></a>\n\
+            **Theorem 1.2.1 (Another Lagrange Theorem).**",
+        );
+        assert_eq!(output, expected);
+        assert_eq!(refs.len(), 1);
+    }
+
+    #[test]
+    fn label_and_ref_in_same_file() {
+        let mut refs = HashMap::new();
+        let input =
+            String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem] {{ref: prop:lagrange}}");
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let output = find_and_replace_refs(&output, &PATH, &refs, &CONFIG).unwrap();
+        let expected = String::from(
+            "<a id=\"prop:lagrange\"></a>\n\
+            **Proposition 1.2.1 (Lagrange Theorem).** \
+            [Proposition 1.2.1](#prop:lagrange)",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn label_and_ref_in_different_files() {
+        let mut refs = HashMap::new();
+        let label_file: PathBuf = "math/groups.md".into();
+        let ref_file: PathBuf = "crypto/bls_signatures.md".into();
+        let label_input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
+        let ref_input = String::from(r"{{ref: prop:lagrange}}");
+        let _label_output = find_and_replace_envs(
+            &label_input,
+            SECNUM,
+            &label_file,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let ref_output = find_and_replace_refs(&ref_input, &ref_file, &refs, &CONFIG).unwrap();
+        let expected = String::from("[Proposition 1.2.1](../math/groups.md#prop:lagrange)");
+        assert_eq!(ref_output, expected);
+    }
+
+    #[test]
+    fn label_and_ref_in_different_files_2() {
+        let mut refs = HashMap::new();
+        let label_file: PathBuf = "math/algebra/groups.md".into();
+        let ref_file: PathBuf = "math/crypto//signatures/bls_signatures.md".into();
+        let label_input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
+        let ref_input = String::from(r"{{ref: prop:lagrange}}");
+        let _label_output = find_and_replace_envs(
+            &label_input,
+            SECNUM,
+            &label_file,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let ref_output = find_and_replace_refs(&ref_input, &ref_file, &refs, &CONFIG).unwrap();
+        let expected = String::from("[Proposition 1.2.1](../../algebra/groups.md#prop:lagrange)");
+        assert_eq!(ref_output, expected);
+    }
+
+    #[test]
+    fn title_ref() {
+        let mut refs = HashMap::new();
+        let label_file: PathBuf = "math/algebra/groups.md".into();
+        let ref_file: PathBuf = "math/crypto//signatures/bls_signatures.md".into();
+        let label_input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
+        let ref_input = String::from(r"{{tref: prop:lagrange}}");
+        let _label_output = find_and_replace_envs(
+            &label_input,
+            SECNUM,
+            &label_file,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let ref_output = find_and_replace_refs(&ref_input, &ref_file, &refs, &CONFIG).unwrap();
+        let expected = String::from("[Lagrange Theorem](../../algebra/groups.md#prop:lagrange)");
+        assert_eq!(ref_output, expected);
+    }
+
+    #[test]
+    fn title_ref_without_title() {
+        let mut refs = HashMap::new();
+        let label_file: PathBuf = "math/algebra/groups.md".into();
+        let ref_file: PathBuf = "math/crypto//signatures/bls_signatures.md".into();
+        let label_input = String::from(r"{{prop}}{prop:lagrange}");
+        let ref_input = String::from(r"{{tref: prop:lagrange}}");
+        let _label_output = find_and_replace_envs(
+            &label_input,
+            SECNUM,
+            &label_file,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let ref_output = find_and_replace_refs(&ref_input, &ref_file, &refs, &CONFIG).unwrap();
+        let expected = String::from("[Proposition 1.2.1](../../algebra/groups.md#prop:lagrange)");
+        assert_eq!(ref_output, expected);
+    }
+
+    #[test]
+    fn roman_numerals() {
+        assert_eq!(to_roman(1), "I");
+        assert_eq!(to_roman(4), "IV");
+        assert_eq!(to_roman(9), "IX");
+        assert_eq!(to_roman(14), "XIV");
+        assert_eq!(to_roman(1994), "MCMXCIV");
+    }
+
+    #[test]
+    fn part_scope() {
+        let ctx = context("[preprocessor.numthm]\nnumbering_scope = \"part\"");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Intro", "{{thm}}".into(), "intro.md", vec![]));
+        book.push_item(BookItem::PartTitle("Algebra".into()));
+        let mut groups = Chapter::new("Groups", "{{thm}}".into(), "groups.md", vec![]);
+        groups.sub_items.push(BookItem::Chapter(Chapter::new(
+            "Subgroups",
+            "{{thm}}{thm:lagrange}".into(),
+            "subgroups.md",
+            vec![],
+        )));
+        book.push_item(groups);
+        book.push_item(BookItem::PartTitle("Analysis".into()));
+        book.push_item(Chapter::new(
+            "Limits",
+            "{{thm}} {{lem}} {{ref: thm:lagrange}}".into(),
+            "limits.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.**",
+                "**Theorem I.1.**",
+                "<a id=\"thm:lagrange\"></a>\n**Theorem I.2.**",
+                "**Theorem II.1.** **Lemma II.1.** [Theorem I.2](subgroups.md#thm:lagrange)",
+            ]
+        );
+    }
+
+    #[test]
+    fn book_scope() {
+        let chapters = |preprocessor: &NumThmPreprocessor, ctx: &PreprocessorContext| {
+            let mut book = Book::new();
+            for (name, number) in [("Groups", 1), ("Rings", 2)] {
+                let mut chapter =
+                    Chapter::new(name, "{{thm}} {{ex}}".into(), format!("{name}.md"), vec![]);
+                chapter.number = Some(SectionNumber(vec![number]));
+                book.push_item(chapter);
+            }
+            contents(&preprocessor.run(ctx, book).unwrap())
+        };
+        let toml = "[preprocessor.numthm]\nprefix = true\n\
+                    [preprocessor.numthm.environments]\n\
+                    ex = {name = \"Exercise\", numbering_scope = \"book\"}";
+        let ctx = context(toml);
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        assert_eq!(
+            chapters(&preprocessor, &ctx),
+            [
+                "**Theorem 1.1.** **Exercise 1.**",
+                "**Theorem 2.1.** **Exercise 2.**"
+            ]
+        );
+
+        let ctx = context(&toml.replace("prefix = true", "numbering_scope = \"book\""));
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        assert_eq!(
+            chapters(&preprocessor, &ctx),
+            [
+                "**Theorem 1.** **Exercise 1.**",
+                "**Theorem 2.** **Exercise 2.**"
+            ]
+        );
+    }
+
+    #[test]
+    fn padded_numbers() {
+        let mut config = NumThmPreprocessor::default();
+        config.environments.get_mut("prop").unwrap().pad = 2;
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{prop}}{prop:lagrange} {{ref: prop:lagrange}}");
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &config,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let output = find_and_replace_refs(&output, &PATH, &refs, &CONFIG).unwrap();
+        let expected = String::from(
+            "<a id=\"prop:lagrange\"></a>\n\
+            **Proposition 1.2.01.** \
+            [Proposition 1.2.01](#prop:lagrange)",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn numbering_styles() {
+        let ctx = context(
+            "[preprocessor.numthm.environments]\n\
+             thm = {numbering = \"Roman\", format = \"{emph}{number}. {name}{title}{emph}\"}\n\
+             lem = {numbering = \"alph\", format = \"<b>{name} {number}</b>{title}:\"}\n\
+             def = {numbering = \"greek\"}",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        assert_eq!(
+            preprocessor.environments["def"].numbering,
+            NumberStyle::Arabic
+        );
+        let mut counter = HashMap::from([("lem".to_string(), 26)]);
+        let output = find_and_replace_envs(
+            "{{thm}} {{thm}}[Lagrange] {{lem}}",
+            "",
+            &PATH,
+            &preprocessor,
+            &mut counter,
+            &mut HashMap::new(),
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            output,
+            "**I. Theorem** **II. Theorem (Lagrange)** <b>Lemma aa</b>:"
+        );
+        assert_eq!(to_letters(52), "AZ");
+        assert_eq!(to_letters(703), "AAA");
+        // zero has no roman numeral nor letter
+        assert_eq!(NumberStyle::UpperRoman.apply(0, 0), "0");
+        assert_eq!(NumberStyle::LowerAlph.apply(0, 0), "0");
+        // the enums are deserialized from the names accepted in `book.toml`
+        assert_eq!(
+            serde_json::from_str::<NumberStyle>("\"Roman\"").unwrap(),
+            NumberStyle::UpperRoman
+        );
+        assert!(serde_json::from_str::<NumberStyle>("\"greek\"").is_err());
+    }
+
+    #[test]
+    fn counter_group() {
+        let mut config = NumThmPreprocessor::default();
+        config.environments.get_mut("thm").unwrap().counter = Some("results".to_string());
+        config.environments.get_mut("lem").unwrap().counter = Some("results".to_string());
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{thm}} {{lem}} {{def}} {{thm}}");
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &config,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let expected = String::from(
+            "**Theorem 1.2.1.** **Lemma 1.2.2.** **Definition 1.2.1.** **Theorem 1.2.3.**",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn counter_group_config() {
+        let ctx = context(
+            "[preprocessor.numthm.environments]\n\
+             cor = {name = \"Corollary\"}\n\
+             [preprocessor.numthm.counters.results]\n\
+             members = [\"thm\", \"lem\", \"cor\"]\n\
+             [preprocessor.numthm.counters.others]\n\
+             members = [\"rem\", \"lem\"]",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let envs = &preprocessor.environments;
+        assert_eq!(envs["thm"].counter.as_deref(), Some("results"));
+        assert_eq!(envs["cor"].counter.as_deref(), Some("results"));
+        assert_eq!(envs["rem"].counter.as_deref(), Some("others"));
+        // an environment belongs to at most one group (groups are processed in alphabetical order)
+        assert_eq!(envs["lem"].counter.as_deref(), Some("others"));
+        assert_eq!(envs["def"].counter, None);
+
+        // groups whose counter would be shared with equations, a family or another environment are ignored
+        let ctx = context(
+            "[preprocessor.numthm.counters.eq]\n\
+             members = [\"thm\"]\n\
+             [preprocessor.numthm.counters.def]\n\
+             members = [\"lem\"]\n\
+             [preprocessor.numthm.counters.rem]\n\
+             members = [\"rem\", \"exercise\"]\n\
+             [preprocessor.numthm.counters.statements]\n\
+             members = [\"prop\"]\n\
+             [preprocessor.numthm.families.statements]\n\
+             members = {conj = \"Conjecture\"}",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let envs = &preprocessor.environments;
+        assert_eq!(envs["thm"].counter, None);
+        assert_eq!(envs["lem"].counter, None);
+        assert_eq!(envs["prop"].counter, None);
+        assert_eq!(envs["exercise"].counter.as_deref(), Some("rem"));
+    }
+
+    #[test]
+    fn extended_environment() {
+        let ctx = context(
+            "[preprocessor.numthm.environments]\n\
+             conj = {name = \"Conjecture\", extends = \"ax\"}\n\
+             ax = {name = \"Axiom\", extends = \"thm\", pad = 2}\n\
+             thm = {emph = \"*\"}\n\
+             rem = {extends = \"unknown\"}\n\
+             [preprocessor.numthm.counters.results]\n\
+             members = [\"thm\", \"lem\"]",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let envs = &preprocessor.environments;
+        assert_eq!(envs["conj"].name, "Conjecture");
+        assert_eq!(envs["conj"].emph, "*");
+        assert_eq!(envs["conj"].pad, 2);
+        assert_eq!(envs["conj"].counter.as_deref(), Some("results"));
+        assert_eq!(envs["ax"].counter.as_deref(), Some("results"));
+        assert_eq!(envs["rem"].name, "Remark");
+    }
+
+    #[test]
+    fn title_ref_without_title_fallback() {
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{prop}}{prop:lagrange} {{tref: prop:lagrange}}");
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let config = NumThmPreprocessor {
+            tref_fallback: TrefFallback::Label,
+            ..Default::default()
+        };
+        let ref_output = find_and_replace_refs(&output, &PATH, &refs, &config).unwrap();
+        let expected = String::from(
+            "<a id=\"prop:lagrange\"></a>\n\
+            **Proposition 1.2.1.** \
+            [prop:lagrange](#prop:lagrange)",
+        );
+        assert_eq!(ref_output, expected);
+        let config = NumThmPreprocessor {
+            tref_fallback: TrefFallback::Error,
+            ..Default::default()
+        };
+        assert!(find_and_replace_refs(&output, &PATH, &refs, &config).is_err());
+    }
+
+    #[test]
+    fn plain_links() {
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{prop}}{prop:lagrange}");
+        find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let input = String::from(
+            "See [Proposition 1.2.1](groups.md#prop:lagrange).\n\
+            See [this section](#intro) and [Proposition 1.2.1](#prop:lagrange).",
+        );
+        assert_eq!(
+            find_plain_links(&input, &refs),
+            vec![
+                (1, "prop:lagrange".to_string()),
+                (2, "prop:lagrange".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn markdown_output() {
+        let config = NumThmPreprocessor {
+            output: OutputFormat::Markdown,
+            ..Default::default()
+        };
+        let mut refs = HashMap::new();
+        let input = String::from("{{prop}}{prop:lagrange}[Lagrange Theorem]\n\n{{thm}}");
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &config,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let expected = String::from(
+            "**Proposition 1.2.1 (Lagrange Theorem).**\n\n\
+            **Theorem 1.2.1.**",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn markdown_headings() {
+        let ctx = context(
+            "[preprocessor.numthm]\noutput = \"markdown\"\n\
+             [preprocessor.numthm.environments]\nthm = {heading = 3}\nrem = {heading = false}\n\
+             lem = {heading = 7}\nprop = {heading = true}",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        assert_eq!(preprocessor.environments["lem"].heading, None);
+        let output = find_and_replace_envs(
+            "{{thm}}{thm:main}\n\n{{rem}}{rem:x} Note.\n\n{{lem}}{lem:y}\n\n{{prop}}{prop:z}",
+            "",
+            &PATH,
+            &preprocessor,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            output,
+            "### **Theorem 1.**\n\n*Remark 1.* Note.\n\n**Lemma 1.**\n\n\
+             #### **Proposition 1.**"
+        );
+    }
+
+    #[test]
+    fn double_label_strategies() {
+        let input = String::from(
+            r"{{prop}}{prop:lagrange}[Lagrange Theorem] {{thm}}{prop:lagrange}[Another Lagrange Theorem]",
+        );
+        let mut config = NumThmPreprocessor {
+            duplicate_labels: DuplicateLabels::KeepLast,
+            ..Default::default()
+        };
+        let mut refs = HashMap::new();
+        find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &config,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs["prop:lagrange"].num_name, "Theorem 1.2.1");
+
+        config.duplicate_labels = DuplicateLabels::Suffix;
+        let mut refs = HashMap::new();
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &config,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let expected = String::from(
+            "<a id=\"prop:lagrange\"></a>\n\
+            **Proposition 1.2.1 (Lagrange Theorem).** \
+            <a id=\"prop:lagrange-2\"></a>\n\
+            **Theorem 1.2.1 (Another Lagrange Theorem).**",
+        );
+        assert_eq!(output, expected);
+        assert_eq!(refs.len(), 2);
+        assert_eq!(refs["prop:lagrange"].num_name, "Proposition 1.2.1");
+        assert_eq!(refs["prop:lagrange-2"].num_name, "Theorem 1.2.1");
+
+        // suffixes of labels defined later in the book are skipped
+        let ctx = context("[preprocessor.numthm]\nduplicate_labels = \"suffix\"");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:a} {{thm}}{thm:a}".into(),
+            "groups.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Rings",
+            "{{thm}}{thm:a-2} {{ref: thm:a-2}} {{ref: thm:a-3}}".into(),
+            "rings.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:a\"></a>\n**Theorem 1.** <a id=\"thm:a-3\"></a>\n**Theorem 2.**",
+                "<a id=\"thm:a-2\"></a>\n**Theorem 1.** [Theorem 1](#thm:a-2) \
+                 [Theorem 2](groups.md#thm:a-3)",
+            ]
+        );
+    }
+
+    #[test]
+    fn report() {
+        let ctx = context("[preprocessor.numthm]\nreference_report = \"appendix/report.md\"");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:lagrange}[Lagrange]\n\nSee {{ref: thm:lagrange}}.".into(),
+            "algebra/groups.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Limits",
+            "{{tref: thm:lagrange}}\n{{ref: thm:unknown}}".into(),
+            "limits.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        let expected = "# Reference Report\n\n\
+            | Location | Label | Target | Title |\n\
+            |----------|-------|--------|-------|\n\
+            | [algebra/groups.md:3](../algebra/groups.md) | `thm:lagrange` | [Theorem 1](../algebra/groups.md#thm:lagrange) | Lagrange |\n\
+            | [limits.md:1](../limits.md) | `thm:lagrange` | [Theorem 1](../algebra/groups.md#thm:lagrange) | Lagrange |\n\
+            | [limits.md:2](../limits.md) | `thm:unknown` | **[??]** |  |\n";
+        assert_eq!(contents(&book)[2], expected);
+    }
+
+    #[test]
+    fn count_and_last() {
+        let mut refs = HashMap::new();
+        let input = String::from(
+            "{{numthm-count: thm}} theorems, {{numthm-count: def}} definitions, \
+            last theorem: {{numthm-last: thm}}\n\
+            {{thm}} {{thm}}",
+        );
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let expected = String::from(
+            "2 theorems, 0 definitions, last theorem: 1.2.2\n\
+            **Theorem 1.2.1.** **Theorem 1.2.2.**",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn label_prefixes() {
+        let ctx = context(
+            "[preprocessor.numthm]\n\
+             lint_label_prefixes = true\n\
+             [preprocessor.numthm.environments]\n\
+             def = {label_prefix = \"defn:\"}",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let envs = &preprocessor.environments;
+        assert_eq!(envs["thm"].label_prefix.as_deref(), Some("thm:"));
+        assert_eq!(envs["def"].label_prefix.as_deref(), Some("defn:"));
+    }
+
+    #[test]
+    fn label_search() {
+        let database = r#"{
+            "thm:lagrange": {"env": "thm", "num_name": "Theorem 2.1", "path": "algebra/groups.md", "title": "Lagrange"},
+            "lem:cosets": {"env": "lem", "num_name": "Lemma 2.2", "path": "algebra/groups.md", "title": null},
+            "def:ring": {"env": "def", "num_name": "Definition 3.1", "path": "rings.md", "title": "Ring"}
+        }"#;
+        assert_eq!(
+            search_labels(database, "LAGRANGE").unwrap(),
+            vec!["thm:lagrange: Theorem 2.1 (Lagrange), algebra/groups.md"]
+        );
+        assert_eq!(
+            search_labels(database, "lem").unwrap(),
+            vec!["lem:cosets: Lemma 2.2, algebra/groups.md"]
+        );
+        assert_eq!(search_labels(database, "2.").unwrap().len(), 2);
+        assert!(search_labels(database, "field").unwrap().is_empty());
+    }
+
+    #[test]
+    fn completions_file() {
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem] {{def}}{def:group}");
+        find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&completions(&refs, &CONFIG).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "def:group": {
+                    "env": "def",
+                    "num_name": "Definition 1.2.1",
+                    "path": "crypto/groups.md",
+                    "title": null,
+                    "exported": false,
+                },
+                "prop:lagrange": {
+                    "env": "prop",
+                    "num_name": "Proposition 1.2.1",
+                    "path": "crypto/groups.md",
+                    "title": "Lagrange Theorem",
+                    "exported": false,
+                },
+            })
+        );
+        let config = NumThmPreprocessor {
+            completions_format: CompletionsFormat::Vscode,
+            ..Default::default()
+        };
+        let vscode: serde_json::Value =
+            serde_json::from_str(&completions(&refs, &config).unwrap()).unwrap();
+        assert_eq!(
+            vscode["prop:lagrange"],
+            serde_json::json!({
+                "prefix": "prop:lagrange",
+                "body": "{{ref: prop:lagrange}}",
+                "description": "Proposition 1.2.1 (Lagrange Theorem)",
+            })
+        );
+    }
+
+    #[test]
+    fn render_single_chapter() {
+        let database = r#"{
+            "thm:old": {
+                "env": "thm",
+                "num_name": "Theorem 1.2.1",
+                "path": "crypto/groups.md",
+                "title": null
+            },
+            "thm:other": {
+                "env": "thm",
+                "num_name": "Theorem 2.1",
+                "path": "crypto/schnorr.md",
+                "title": "Security"
+            }
+        }"#;
+        let chapter = Chapter::new(
+            "Groups",
+            "{{thm}}{thm:new} {{tref: thm:other}} {{ref: thm:old}}".into(),
+            "crypto/groups.md",
+            vec![],
+        );
+        let ctx = context("[preprocessor.numthm]");
+        let output = CONFIG.render_chapter(&ctx, &chapter, database).unwrap();
+        let expected = String::from(
+            "<a id=\"thm:new\"></a>\n\
+            **Theorem 1.** [Security](schnorr.md#thm:other) **[??]**",
+        );
+        assert_eq!(output, expected);
+
+        // the chapter is processed as by `run`, with initial counters and private labels
+        let ctx = context(
+            "[preprocessor.numthm]\nprivate_labels = true\n\
+             [preprocessor.numthm.initial_counters]\nthm = 4",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let chapter = Chapter::new(
+            "Groups",
+            "{{thm}}\n\n## Cyclic groups\n\n{{thm}} {{tref: thm:other}}".into(),
+            "crypto/groups.md",
+            vec![],
+        );
+        let output = preprocessor
+            .render_chapter(&ctx, &chapter, database)
+            .unwrap();
+        assert_eq!(
+            output,
+            "**Theorem 5.**\n\n## Cyclic groups\n\n**Theorem 6.** **[??]**"
+        );
+    }
+
+    #[test]
+    fn custom_delimiters() {
+        let config = NumThmPreprocessor {
+            syntax: MacroSyntax {
+                open: "@[".to_string(),
+                close: "]".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut refs = HashMap::new();
+        let input = String::from(
+            r"@[prop]{prop:lagrange}[Lagrange Theorem] {{thm}} @[numthm-count: prop] @[ref: prop:lagrange]",
+        );
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &config,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let output = find_and_replace_refs(&output, &PATH, &refs, &config).unwrap();
+        let expected = String::from(
+            "<a id=\"prop:lagrange\"></a>\n\
+            **Proposition 1.2.1 (Lagrange Theorem).** {{thm}} 1 \
+            [Proposition 1.2.1](#prop:lagrange)",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn namespaced_macros() {
+        let mut config = NumThmPreprocessor::default();
+        config.syntax.namespace = Namespace::Optional;
+        let mut refs = HashMap::new();
+        let input =
+            String::from(r"{{numthm:prop}}{prop:lagrange} {{thm}} {{numthm:ref: prop:lagrange}}");
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &config,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let output = find_and_replace_refs(&output, &PATH, &refs, &config).unwrap();
+        let expected = String::from(
+            "<a id=\"prop:lagrange\"></a>\n\
+            **Proposition 1.2.1.** **Theorem 1.2.1.** [Proposition 1.2.1](#prop:lagrange)",
+        );
+        assert_eq!(output, expected);
+
+        config.syntax.namespace = Namespace::Required;
+        let mut refs = HashMap::new();
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &config,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let expected = String::from(
+            "<a id=\"prop:lagrange\"></a>\n\
+            **Proposition 1.2.1.** {{thm}} {{numthm:ref: prop:lagrange}}",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn summary_order() {
+        let ctx = context("[preprocessor.numthm]\nnumbering_scope = \"part\"");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(BookItem::PartTitle("Algebra".into()));
+        let mut groups = Chapter::new("Groups", "{{thm}}".into(), "groups.md", vec![]);
+        groups.number = Some(SectionNumber(vec![1]));
+        let mut subgroups = Chapter::new("Subgroups", "{{thm}}".into(), "subgroups.md", vec![]);
+        subgroups.number = Some(SectionNumber(vec![1, 1]));
+        let mut cosets = Chapter::new("Cosets", "{{thm}}".into(), "cosets.md", vec![]);
+        cosets.number = Some(SectionNumber(vec![1, 1, 1]));
+        subgroups.sub_items.push(BookItem::Chapter(cosets));
+        groups.sub_items.push(BookItem::Chapter(subgroups));
+        book.push_item(groups);
+        let mut rings = Chapter::new("Rings", "{{thm}}".into(), "rings.md", vec![]);
+        rings.number = Some(SectionNumber(vec![2]));
+        book.push_item(rings);
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem I.1.**",
+                "**Theorem I.2.**",
+                "**Theorem I.3.**",
+                "**Theorem I.4.**",
+            ]
+        );
+    }
+
+    #[test]
+    fn chapter_order() {
+        let number = |v: &[u32]| SectionNumber(v.to_vec());
+        assert!(chapter_follows(&number(&[1]), &number(&[1, 1])));
+        assert!(chapter_follows(&number(&[1, 2]), &number(&[1, 2, 1])));
+        assert!(chapter_follows(&number(&[1, 2, 1]), &number(&[2])));
+        assert!(!chapter_follows(&number(&[1, 2]), &number(&[1, 1])));
+        assert!(!chapter_follows(&number(&[2]), &number(&[1, 3])));
+    }
+
+    #[test]
+    fn self_references() {
+        let mut refs = HashMap::new();
+        let input = String::from(
+            "{{selfnum}} {{prop}}{prop:lagrange} inequality ({{selfnum}}.1), see {{selfref}}. \
+            {{thm}} {{selfref}}",
+        );
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let expected = String::from(
+            "**[??]** <a id=\"prop:lagrange\"></a>\n\
+            **Proposition 1.2.1.** inequality (1.2.1.1), see [Proposition 1.2.1](#prop:lagrange). \
+            **Theorem 1.2.1.** Theorem 1.2.1",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn snippets() {
+        let snippets = "[fermat]\n\
+            env = \"thm\"\n\
+            title = \"Fermat\"\n\
+            body = \"\"\"\nThere are no solutions.\nNone at all.\n\"\"\"";
+        let config = NumThmPreprocessor {
+            snippets: toml::from_str(snippets).unwrap(),
+            ..Default::default()
+        };
+        let mut instantiated = HashSet::new();
+        let summary_path: PathBuf = "summary.md".into();
+        let summary = expand_snippets(
+            "{{snippet-quote: fermat}}",
+            &summary_path,
+            &config,
+            &mut instantiated,
+            &mut Vec::new(),
+        );
+        let chapter = expand_snippets(
+            "{{thm}} {{snippet: fermat}}",
+            &PATH,
+            &config,
+            &mut instantiated,
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            chapter,
+            "{{thm}} {{thm}}{fermat}[Fermat] There are no solutions.\nNone at all."
+        );
+
+        let mut refs = HashMap::new();
+        let chapter = find_and_replace_envs(
+            &chapter,
+            SECNUM,
+            &PATH,
+            &config,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            chapter,
+            "**Theorem 1.2.1.** <a id=\"fermat\"></a>\n\
+            **Theorem 1.2.2 (Fermat).** There are no solutions.\nNone at all."
+        );
+        let summary = find_and_replace_refs(&summary, &summary_path, &refs, &config).unwrap();
+        assert_eq!(
+            summary,
+            "> **[Theorem 1.2.2](crypto/groups.md#fermat) (Fermat).** There are no solutions.\n\
+            > None at all."
+        );
+
+        // code in the bodies of snippets is left as is, and their references are located
+        let ctx = context("[preprocessor.numthm]\nstrict = true");
+        let mut preprocessor = NumThmPreprocessor::new(&ctx);
+        preprocessor.snippets = toml::from_str(
+            "[cauchy]\nenv = \"thm\"\nbody = \"See `{{thm}}` and {{ref: cauchy}}.\"",
+        )
+        .unwrap();
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{snippet: cauchy}}".into(),
+            "groups.md",
+            vec![],
+        ));
+        let output = preprocessor.run(&ctx, book.clone()).unwrap();
+        assert_eq!(
+            contents(&output),
+            ["<a id=\"cauchy\"></a>\n**Theorem 1.** See `{{thm}}` and [Theorem 1](#cauchy)."]
+        );
+        preprocessor.snippets =
+            toml::from_str("[cauchy]\nenv = \"thm\"\nbody = \"See {{ref: thm:missing}}.\"")
+                .unwrap();
+        let error = preprocessor.run(&ctx, book).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Unresolved reference to label `thm:missing'"));
+    }
+
+    #[test]
+    fn private_labels() {
+        let ctx = context("[preprocessor.numthm]\nprivate_labels = true");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{export: thm:lagrange}}{{thm}}{thm:lagrange} {{lem}}{lem:aux} {{ref: lem:aux}}"
+                .into(),
+            "groups.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Rings",
+            "{{ref: thm:lagrange}} {{ref: lem:aux}}".into(),
+            "rings.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:lagrange\"></a>\n**Theorem 1.** \
+                <a id=\"lem:aux\"></a>\n**Lemma 1.** [Lemma 1](#lem:aux)",
+                "[Theorem 1](groups.md#thm:lagrange) **[??]**",
+            ]
+        );
+    }
+
+    #[test]
+    fn anchor_validation() {
+        let root = temp_root("anchors");
+        std::fs::create_dir_all(root.join("crypto")).unwrap();
+        std::fs::write(
+            root.join("crypto/groups.html"),
+            "<a id=\"thm:lagrange\"></a><h2 id='intro'>Intro</h2>\n\
+             <a href=\"#intro\">1</a> <a href=\"../rings.html#thm%3Aideal\">2</a>\n\
+             <a href=\"../rings.html#thm:gone\">3</a> <a href=\"https://example.com/#x\">4</a>",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("rings.html"),
+            "<a name=\"thm:ideal\"></a> <a href=\"crypto/groups.html#thm:lagrange\">5</a>\n\
+             <a href=\"/crypto/groups.html?x=1#intro\">6</a> <a href=\"fields.html#thm:x\">7</a>\n\
+             <a href=\"crypto/groups.html?at=10:30#gone\">8</a> <a href=\"//example.com/#x\">9</a>",
+        )
+        .unwrap();
+        assert_eq!(
+            check_anchors(&root).unwrap(),
+            [
+                format!(
+                    "{}: ../rings.html#thm:gone",
+                    Path::new("crypto/groups.html").display()
+                ),
+                "rings.html: fields.html#thm:x".to_string(),
+                "rings.html: crypto/groups.html?at=10:30#gone".to_string(),
+            ]
+        );
+        assert_eq!(percent_decode("thm%3Amain%2"), "thm:main%2");
+    }
+
+    #[test]
+    fn external_labels() {
+        let root = temp_root("external");
+        let volume1 = NumThmPreprocessor {
+            completions: Some("numthm.json".into()),
+            ..NumThmPreprocessor::default()
+        };
+        let mut ctx = context("[preprocessor.numthm]\ncompletions = \"numthm.json\"");
+        ctx.root = root.clone();
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:lagrange}[Lagrange] {{lem}}{lem:a}".into(),
+            "algebra/groups.md",
+            vec![],
+        ));
+        volume1.run(&ctx, book).unwrap();
+
+        let ctx = context(&format!(
+            "[preprocessor.numthm]\nstrict = true\n\
+             external = [{{database = \"{}\", url = \"https://example.org/vol1\"}}]",
+            root.join("numthm.json").display()
+        ));
+        let volume2 = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Rings",
+            "{{lem}}{lem:a} {{ref: lem:a}} {{tref: thm:lagrange}} {{ref: thm:lagrange}}".into(),
+            "rings.md",
+            vec![],
+        ));
+        let book = volume2.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"lem:a\"></a>\n**Lemma 1.** [Lemma 1](#lem:a) \
+                 [Lagrange](https://example.org/vol1/algebra/groups.html#thm:lagrange) \
+                 [Theorem 1](https://example.org/vol1/algebra/groups.html#thm:lagrange)"
+            ]
+        );
+    }
+
+    #[test]
+    fn renderer_gated_assets() {
+        let root = temp_root("assets");
+        let book_toml = "[preprocessor.numthm.assets]\ninject = \"auto\"\ndir = \"theme\"";
+        let mut ctx = context(book_toml);
+        ctx.root = root.clone();
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}".into(),
+            "crypto/groups.md",
+            vec![],
+        ));
+
+        let output = NumThmPreprocessor::new(&ctx)
+            .run(&ctx, book.clone())
+            .unwrap();
+        assert_eq!(
+            contents(&output),
+            vec![
+                "<link rel=\"stylesheet\" href=\"../theme/numthm.css\">\n\
+                 <script src=\"../theme/numthm.js\"></script>\n**Theorem 1.**"
+            ]
+        );
+        assert!(root.join("src/theme/numthm.css").exists());
+        assert!(root.join("src/theme/numthm.js").exists());
+
+        // other renderers using the assets link them, but only the HTML renderer writes them, unless no output
+        // is written
+        std::fs::remove_dir_all(root.join("src")).unwrap();
+        let preprocessor = NumThmPreprocessor::new(&ctx).without_outputs();
+        assert_eq!(
+            contents(&preprocessor.run(&ctx, book.clone()).unwrap()),
+            contents(&output)
+        );
+        let mut epub_ctx = context(&format!("{book_toml}\nrenderers = [\"html\", \"epub\"]"));
+        epub_ctx.root = root.clone();
+        epub_ctx.renderer = "epub".into();
+        let output = NumThmPreprocessor::new(&epub_ctx)
+            .run(&epub_ctx, book.clone())
+            .unwrap();
+        assert!(contents(&output)[0].starts_with("<link rel=\"stylesheet\""));
+        assert!(!root.join("src").exists());
+
+        ctx.renderer = "pandoc".into();
+        let output = NumThmPreprocessor::new(&ctx).run(&ctx, book).unwrap();
+        assert_eq!(contents(&output), vec!["**Theorem 1.**"]);
+    }
+
+    #[test]
+    fn private_use_characters() {
+        let mut ctx = context("[preprocessor.numthm]");
+        ctx.renderer = "latex".into();
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}} Marks \u{E002} and \u{E000}0\u{E001}.".into(),
+            "groups.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[0],
+            "\\begin{theorem} Marks \u{E002} and \u{E000}0\u{E001}.\n\\end{theorem}"
+        );
+    }
+
+    #[test]
+    fn latex_output() {
+        let mut ctx = context("[preprocessor.numthm.environments]\nlem = {latex = \"lemma*\"}");
+        ctx.renderer = "latex".into();
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{lem}} Obvious. {{thm}}{thm:lagrange}[Lagrange] The order divides.\n\n\
+             {{#proof}}\nBy {{ref: thm:lagrange}}, {{tref: thm:lagrange}} and {{selfref}}.\n{{/proof}}\n\n\
+             $$x {{eq}}{eq:x}$$ {{numthm-list: thm}}"
+                .into(),
+            "groups.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[0],
+            "\\begin{lemma*} Obvious.\n\\end{lemma*} \
+             \\begin{theorem}[Lagrange]\\label{thm:lagrange} The order divides.\n\\end{theorem}\n\n\
+             \\begin{proof}\nBy \\Cref{thm:lagrange}, \\hyperref[thm:lagrange]{Lagrange} \
+             and \\Cref{thm:lagrange}.\n\n\\end{proof}\n\n\
+             $$x \\label{eq:x}$$ - \\hyperref[thm:lagrange]{Theorem 1 (Lagrange)}"
+        );
+
+        ctx.renderer = "html".into();
+        let ctx_output = context("[preprocessor.numthm]\noutput = \"latex\"");
+        assert_eq!(
+            NumThmPreprocessor::new(&ctx_output).output,
+            OutputFormat::Latex
+        );
+        assert_eq!(NumThmPreprocessor::new(&ctx).output, OutputFormat::Html);
+    }
+
+    #[test]
+    fn fields() {
+        let ctx = context(
+            "[preprocessor.numthm]\nfields = [\"author\", \"year\", \"title\"]\n\
+             templates = { ref = \"{text} ({author}, {year})\", list = \"{text}, {author}\" }\n\
+             [preprocessor.numthm.environments]\n\
+             thm = {format = \"{emph}{name} {number}{title}.{emph} [{author}]\"}",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        assert_eq!(preprocessor.fields, ["author", "year"]);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Numbers",
+            "{{thm author=\"Gauss\" year=1801 isbn=x}}{thm:qr}[Quadratic Reciprocity] {{thm}}{thm:x}\n\n\
+             {{ref: thm:qr}} {{ref: thm:x}}\n\n{{numthm-list: thm}}"
+                .into(),
+            "numbers.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[0],
+            "<a id=\"thm:qr\"></a>\n**Theorem 1 (Quadratic Reciprocity).** [Gauss] \
+             <a id=\"thm:x\"></a>\n**Theorem 2.** []\n\n\
+             [Theorem 1 (Gauss, 1801)](#thm:qr) [Theorem 2 (, )](#thm:x)\n\n\
+             - [Theorem 1 (Quadratic Reciprocity), Gauss](#thm:qr)\n\
+             - [Theorem 2, ](#thm:x)"
+        );
+    }
+
+    #[test]
+    fn child_counters() {
+        let ctx = context(
+            "[preprocessor.numthm]\nprefix = true\n\
+             [preprocessor.numthm.environments]\n\
+             cor = {name = \"Corollary\", within = \"thm\"}\n\
+             rem = {within = \"cor\"}\n\
+             ex = {name = \"Example\", within = \"ex\"}\n\
+             def = {within = \"foo\"}",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        assert_eq!(preprocessor.environments["ex"].within, None);
+        assert_eq!(preprocessor.environments["def"].within, None);
+        let mut book = Book::new();
+        let mut chapter = Chapter::new(
+            "Groups",
+            "{{cor}} {{thm}} {{cor}} {{rem}} {{rem}} {{cor}} {{rem}} {{thm}} {{cor}} {{ex}}".into(),
+            "groups.md",
+            vec![],
+        );
+        chapter.number = Some(SectionNumber(vec![3]));
+        book.push_item(chapter);
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[0],
+            "**Corollary 3.0.1.** **Theorem 3.1.** **Corollary 3.1.1.** *Remark 3.1.1.1.* \
+             *Remark 3.1.1.2.* **Corollary 3.1.2.** *Remark 3.1.2.1.* **Theorem 3.2.** \
+             **Corollary 3.2.1.** **Example 3.1.**"
+        );
+    }
+
+    #[test]
+    fn manual_counters() {
+        let ctx = context(
+            "[preprocessor.numthm.environments]\n\
+             lem = {start = 0}\n\
+             cor = {name = \"Corollary\", within = \"thm\"}",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Counters",
+            "{{lem}} {{thm}} {{set-counter: thm=9 lem=+2}}{{thm}} {{cor}} {{thm number=previous}}[continued] \
+             {{cor}} {{lem}} {{thm number=20}} {{thm}} {{set-counter: foo=3}}\
+             {{set-counter: lem=4294967295}}{{lem}} {{set-counter: lem=+1}}{{lem}}"
+                .into(),
+            "counters.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[0],
+            "**Lemma 0.** **Theorem 1.** **Theorem 10.** **Corollary 10.1.** **Theorem 10 (continued).** \
+             **Corollary 10.2.** **Lemma 3.** **Theorem 20.** **Theorem 21.** \
+             **Lemma 4294967295.** **Lemma 4294967295.**"
+        );
+    }
+
+    #[test]
+    fn number_separators() {
+        let ctx = context(
+            "[preprocessor.numthm]\nprefix = true\nnumber_separator = \"–\"\n\
+             [preprocessor.numthm.environments]\ncor = {name = \"Corollary\", within = \"thm\"}\n\
+             [preprocessor.numthm.templates]\npref = \"{text}\"",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        let mut chapter = Chapter::new(
+            "Groups",
+            "{{thm}}{thm:a} {{cor}} {{ref: thm:a}} {{pref: thm:a}}\n\n$$x {{eq}}$$\n\n{{numthm-list: cor}}"
+                .into(),
+            "groups.md",
+            vec![],
+        );
+        chapter.number = Some(SectionNumber(vec![1, 2]));
+        book.push_item(chapter);
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[0],
+            "<a id=\"thm:a\"></a>\n**Theorem 1–2–1.** **Corollary 1–2–1–1.** [Theorem 1–2–1](#thm:a) \
+             [Theorem 1–2–1 in Section 1–2](#thm:a)\n\n$$x \\tag{1–2–1}$$\n\n- Corollary 1–2–1–1"
+        );
+    }
+
+    #[test]
+    fn prefix_depth_and_headings() {
+        let ctx =
+            context("[preprocessor.numthm]\nprefix = true\nprefix_depth = 1\nprefix_headings = 1");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        for (name, number, content) in [
+            ("Groups", vec![3, 1], "{{thm}}"),
+            ("Rings", vec![3, 2], "{{thm}}"),
+            (
+                "Fields",
+                vec![4],
+                "{{thm}}\n\n## Extensions\n\n{{thm}}\n\n```\n## Code\n```\n\n## Galois\n\n{{thm}}",
+            ),
+        ] {
+            let mut chapter = Chapter::new(name, content.into(), format!("{name}.md"), vec![]);
+            chapter.number = Some(SectionNumber(number));
+            book.push_item(chapter);
+        }
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 3.1.**",
+                "**Theorem 3.2.**",
+                "**Theorem 4.1.**\n\n## Extensions\n\n**Theorem 4.1.1.**\n\n```\n## Code\n```\n\n\
+                 ## Galois\n\n**Theorem 4.2.1.**"
+            ]
+        );
+
+        // skipped levels are numbered 1, and code blocks end with a fence of the same kind and length
+        let ctx = context("[preprocessor.numthm]\nprefix = true\nprefix_headings = 2");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let s = "### Early\n\n````\n```\n## Code\n````\n\n## Galois\n\n~~~\n## Code\n~~~~\n";
+        assert_eq!(
+            preprocessor
+                .split_sections(s, "4.".to_string())
+                .into_iter()
+                .map(|(_, prefix)| prefix)
+                .collect::<Vec<String>>(),
+            vec!["4.", "4.1.1.", "4.2."]
+        );
+    }
+
+    #[test]
+    fn sections_share_chapter_state() {
+        let ctx = context("[preprocessor.numthm]\nprefix = true\nprefix_headings = 1");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        let mut chapter = Chapter::new(
+            "Fields",
+            "{{#thm}}\nStatement.\n\n## Extensions\n\nMore.\n{{/thm}}\n\n{{proof}} By {{selfref}}.\n\n\
+             ## Galois\n\n{{numthm-last: thm}}"
+                .into(),
+            "fields.md",
+            vec![],
+        );
+        chapter.number = Some(SectionNumber(vec![4]));
+        book.push_item(chapter);
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[0],
+            "<div class=\"numthm numthm-thm\">\n\n**Theorem 4.1.**\nStatement.\n\n## Extensions\n\nMore.\n\n\n\
+             </div>\n\n*Proof.* By Theorem 4.1.\n\n## Galois\n\n4.1"
+        );
+    }
+
+    #[test]
+    fn appendix_letters() {
+        for options in [
+            "appendix_after = \"Conclusion\"",
+            "appendices = [\"Proofs.md\", \"Tables.md\"]",
+        ] {
+            let ctx = context(&format!("[preprocessor.numthm]\nprefix = true\n{options}"));
+            let preprocessor = NumThmPreprocessor::new(&ctx);
+            let mut book = Book::new();
+            for (name, number) in [
+                ("Intro", 1),
+                ("Conclusion", 2),
+                ("Proofs", 3),
+                ("Tables", 4),
+            ] {
+                let content = format!("{{{{thm}}}}{{thm:{name}}}\n\n{{{{thm}}}}");
+                let mut chapter = Chapter::new(name, content, format!("{name}.md"), vec![]);
+                chapter.number = Some(SectionNumber(vec![number]));
+                book.push_item(chapter);
+            }
+            let book = preprocessor.run(&ctx, book).unwrap();
+            let contents = contents(&book);
+            assert!(contents[1].contains("**Theorem 2.1.**"));
+            assert!(contents[1].ends_with("**Theorem 2.2.**"));
+            assert!(contents[2].contains("**Theorem A.1.**"));
+            assert!(contents[3].ends_with("**Theorem B.2.**"));
+        }
+    }
+
+    #[test]
+    fn excluded_chapters() {
+        let ctx = context("[preprocessor.numthm]\nexclude = [\"changelog/*.md\", \"legal.md\"]");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        for path in [
+            "groups.md",
+            "changelog/v1.md",
+            "legal.md",
+            "changelog/v1/fix.md",
+        ] {
+            let content = "{{thm}}{thm:x} {{ref: thm:x}}".into();
+            book.push_item(Chapter::new(path, content, path, vec![]));
+        }
+        let book = preprocessor.run(&ctx, book).unwrap();
+        let contents = contents(&book);
+        assert_eq!(
+            contents[0],
+            "<a id=\"thm:x\"></a>\n**Theorem 1.** [Theorem 1](#thm:x)"
+        );
+        assert_eq!(contents[1], "{{thm}}{thm:x} {{ref: thm:x}}");
+        assert_eq!(contents[2], "{{thm}}{thm:x} {{ref: thm:x}}");
+        assert!(contents[3].starts_with("<a id=\"thm:x\"></a>"));
+    }
+
+    #[test]
+    fn included_chapters() {
+        let ctx = context(
+            "[preprocessor.numthm]\ninclude = [\"chapters/**\"]\nexclude = [\"chapters/draft.md\"]",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        for path in ["chapters/groups.md", "chapters/draft.md", "reference.md"] {
+            let content = "{{thm}}".into();
+            book.push_item(Chapter::new(path, content, path, vec![]));
+        }
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(contents(&book), ["**Theorem 1.**", "{{thm}}", "{{thm}}"]);
+    }
+
+    #[test]
+    fn restricted_macros() {
+        let ctx = context("[preprocessor.numthm]\nmacros = [\"thm\", \"ref\"]");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let content =
+            "{{#if draft}}{{lem}}{{/if}} {{thm}}{thm:x} \\{{title}} \\{{thm}} {{ref: thm:x}}";
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Groups", content.into(), "groups.md", vec![]));
+        assert_eq!(
+            preprocessor.macro_report(&book),
+            vec![
+                "groups.md:1: `{{#if draft}}' left as is",
+                "groups.md:1: `{{lem}}' left as is",
+                "groups.md:1: `{{/if}}' left as is",
+                "groups.md:1: `{{thm}}' processed",
+                "groups.md:1: `\\{{title}}' left as is",
+                "groups.md:1: `\\{{thm}}' left as is",
+                "groups.md:1: `{{ref: thm:x}}' processed",
+            ]
+        );
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "{{#if draft}}{{lem}}{{/if}} <a id=\"thm:x\"></a>\n**Theorem 1.** \\{{title}} {{thm}} \
+                 [Theorem 1](#thm:x)"
+            ]
+        );
+    }
+
+    #[test]
+    fn self_links_and_slugs() {
+        let ctx = context("[preprocessor.numthm]\nself_links = true");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let content =
+            "{{thm}}{thm:central limit} {{proof}}{prf:gödel’s} {{ref: thm:central limit}}";
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Groups", content.into(), "groups.md", vec![]));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:central-limit\"></a>\n**Theorem [1](#thm:central-limit).** \
+                 <a id=\"prf:gödel-s\"></a>\n*[Proof](#prf:gödel-s).* [Theorem 1](#thm:central-limit)"
+            ]
+        );
+        assert_eq!(slugify("a  b/c.d_e"), "a-b-c.d_e");
+    }
+
+    #[test]
+    fn parallel_references() {
+        let ctx = context("[preprocessor.numthm]\nprefix = true");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        for i in 1..=50 {
+            let content = format!("{{{{thm}}}}{{thm:{i}}} {{{{ref: thm:{}}}}}", i % 50 + 1);
+            let mut chapter = Chapter::new(&i.to_string(), content, format!("{i}.md"), vec![]);
+            chapter.number = Some(SectionNumber(vec![i]));
+            book.push_item(chapter);
+        }
+        let book = preprocessor.run(&ctx, book).unwrap();
+        for (i, content) in contents(&book).iter().enumerate() {
+            let (i, j) = (i + 1, (i + 1) % 50 + 1);
+            assert_eq!(
+                content,
+                &format!(
+                    "<a id=\"thm:{i}\"></a>\n**Theorem {i}.1.** [Theorem {j}.1]({j}.md#thm:{j})"
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn snapshot_diffs() {
+        let baseline = "# Groups\n\n**Theorem 1.2.**\n\nSee [Theorem 1.2](#thm:a).\n";
+        assert!(diff_snapshot(baseline, baseline).is_empty());
+        assert_eq!(
+            diff_snapshot(
+                baseline,
+                "# Groups\n\n**Lemma 1.1.**\n\n**Theorem 1.3.**\n\nSee [Theorem 1.3](#thm:a).\n"
+            ),
+            vec![
+                "-3: **Theorem 1.2.**",
+                "+3: **Lemma 1.1.**",
+                "-5: See [Theorem 1.2](#thm:a).",
+                "+5: **Theorem 1.3.**",
+                "+6: ",
+                "+7: See [Theorem 1.3](#thm:a).",
+            ]
+        );
+        // long chapters are compared in linear space
+        let baseline: String = (0..5000).map(|i| format!("line {i}\n")).collect();
+        let current = baseline
+            .replace("line 10\n", "")
+            .replace("line 4000\n", "line 4000\nnew\n");
+        assert_eq!(
+            diff_snapshot(&baseline, &current),
+            vec!["-11: line 10", "+4001: new"]
+        );
+    }
+
+    #[test]
+    fn without_outputs() {
+        let root = temp_root("without-outputs");
+        let mut ctx = context(
+            "[preprocessor.numthm]
+completions = \"numthm.json\"\n\
+             label_cache = \"cache/labels.json\"",
+        );
+        ctx.root = root.clone();
+        let preprocessor = NumThmPreprocessor::new(&ctx).without_outputs();
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:lagrange}".into(),
+            "groups.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec!["<a id=\"thm:lagrange\"></a>\n**Theorem 1.**"]
+        );
+        assert!(!root.join("numthm.json").exists());
+        assert!(!root.join("cache").exists());
+    }
+
+    #[test]
+    fn backreferences() {
+        let ctx = context("[preprocessor.numthm]\nbackrefs = true");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        for (name, number, content) in [
+            (
+                "Groups",
+                Some(vec![1]),
+                "{{thm}}{thm:a} Statement.\n\n{{#lem}}{lem:b}\nBody.\n{{/lem}}\n\n{{lem}}{lem:c} {{ref: thm:a}}",
+            ),
+            ("Rings", Some(vec![2]), "{{ref: thm:a}} {{ref: lem:b}} {{ref: thm:a}}"),
+            ("Notes", None, "{{tref: thm:a}}"),
+        ] {
+            let mut chapter = Chapter::new(name, content.into(), format!("{name}.md"), vec![]);
+            chapter.number = number.map(SectionNumber);
+            book.push_item(chapter);
+        }
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[0],
+            "<a id=\"thm:a\"></a>\n**Theorem 1.** Statement.\n\n\
+             <small class=\"numthm-backrefs\">Referenced in: [§2](Rings.md), [Notes](Notes.md)</small>\n\n\
+             <div class=\"numthm numthm-lem\">\n\n<a id=\"lem:b\"></a>\n**Lemma 1.**\nBody.\n\n\
+             <small class=\"numthm-backrefs\">Referenced in: [§2](Rings.md)</small>\n\n\n</div>\n\n\
+             <a id=\"lem:c\"></a>\n**Lemma 2.** [Theorem 1](#thm:a)"
+        );
+    }
+
+    #[test]
+    fn label_cache() {
+        let root = temp_root("label-cache");
+        let mut ctx = context("[preprocessor.numthm]\nlabel_cache = \"cache/labels.json\"");
+        ctx.root = root.clone();
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let build = |groups: &str| {
+            let mut book = Book::new();
+            book.push_item(Chapter::new("Groups", groups.into(), "groups.md", vec![]));
+            let content = "{{ref: thm:a}}".into();
+            book.push_item(Chapter::new("Rings", content, "rings.md", vec![]));
+            contents(&preprocessor.run(&ctx, book).unwrap())[1].clone()
+        };
+        assert_eq!(build("{{thm}}{thm:a}"), "[Theorem 1](groups.md#thm:a)");
+        assert!(root.join("cache/labels.json").exists());
+        // the chapter is being edited
+        assert_eq!(build("{{thm}{thm:a}"), "[Theorem 1](groups.md#thm:a)");
+        // the label is removed from a chapter without malformed macros
+        assert_eq!(build("{{thm}}"), "**[??]**");
+        assert_eq!(build("{{thm}{thm:a}"), "**[??]**");
+    }
+
+    #[test]
+    fn rtl_numbers() {
+        let book_toml = r#"
+[book]
+text-direction = "rtl"
+
+[preprocessor.numthm]
+prefix = true
+
+[preprocessor.numthm.environments]
+thm = {name = "משפט"}
+"#;
+        let ctx = context(book_toml);
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut chapter = Chapter::new(
+            "חבורות",
+            "{{thm}}{thm:lagrange}[לגרנז'] {{ref: thm:lagrange}}".into(),
+            "groups.md",
+            vec![],
+        );
+        chapter.number = Some(SectionNumber(vec![1, 2]));
+        let mut book = Book::new();
+        book.push_item(chapter);
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:lagrange\"></a>\n**משפט \u{2066}1.2.1\u{2069} (לגרנז').** \
+                [משפט \u{2066}1.2.1\u{2069}](#thm:lagrange)"
+            ]
+        );
+
+        // isolation can be disabled, e.g. for books whose numbers use a native script
+        let ctx = context(
+            "[book]\ntext-direction = \"rtl\"\n[preprocessor.numthm]\nisolate_numbers = false",
+        );
+        assert!(!NumThmPreprocessor::new(&ctx).isolate_numbers);
+    }
+
+    #[test]
+    fn empty_label_and_title() {
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{thm}}{}[  ] {{lem}}{ }[Title]");
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let expected = String::from("**Theorem 1.2.1.** **Lemma 1.2.1 (Title).**");
+        assert_eq!(output, expected);
+        assert!(refs.is_empty());
+    }
+
+    #[test]
+    fn bounded_captures() {
+        let ctx = context("[preprocessor.numthm]\nmax_capture = 10");
+        let config = NumThmPreprocessor::new(&ctx);
+        let mut refs = HashMap::new();
+        let input =
+            String::from(r"{{thm}}{thm:short} {{lem}}{lem:much-too-long} {{ref: thm:short}}");
+        let output = find_and_replace_envs(
+            &input,
+            "",
+            &PATH,
+            &config,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let expected = String::from(
+            "<a id=\"thm:short\"></a>\n**Theorem 1.** **Lemma 1.**{lem:much-too-long} {{ref: thm:short}}",
+        );
+        assert_eq!(output, expected);
+        assert_eq!(refs.len(), 1);
+
+        // pathological inputs are processed in linear time
+        let input = "{{thm}}[".repeat(10_000);
+        let output = find_and_replace_envs(
+            &input,
+            "",
+            &PATH,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+            &mut Vec::new(),
+        );
+        assert!(output.ends_with("**Theorem 10000.**["));
+    }
+
+    #[test]
+    fn malformed_macros() {
+        let input = "{{thm}}{thm:main} {{ref: thm:main}} {{#include file.rs}}\n\
+                     {{thm} {{ref: }} {{ref thm:main}}\n\
+                     {{ lem }} {{snippet: }}";
+        assert_eq!(
+            find_malformed_macros(input, &CONFIG),
+            vec![
+                (2, "{{thm}".to_string()),
+                (2, "{{ref: }}".to_string()),
+                (2, "{{ref thm:main}}".to_string()),
+                (3, "{{ lem }}".to_string()),
+                (3, "{{snippet: }}".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn draft_mode() {
+        let config = NumThmPreprocessor {
+            draft: true,
+            ..Default::default()
+        };
+        let input = String::from(r"{{thm}}{thm:main}[Main Theorem] {{lem}}");
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &config,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+            &mut Vec::new(),
+        );
+        let expected = String::from(
+            "<a id=\"thm:main\"></a>\n**Theorem 1.2.1 (Main Theorem).** \
+            <small class=\"numthm-label\">⟨thm:main⟩</small> **Lemma 1.2.1.**",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn header_layout_missing_pieces() {
+        let ctx = context(
+            "[preprocessor.numthm]\n\
+             header_layout = [\"permalink\", \"icon\", \"name-number\", \"title\", \"anchor\"]",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        assert_eq!(
+            preprocessor.header_layout,
+            [
+                HeaderPiece::Permalink,
+                HeaderPiece::Anchor,
+                HeaderPiece::Header
+            ]
+        );
+    }
+
+    #[test]
+    fn header_layout() {
+        let ctx = context(
+            "[preprocessor.numthm]\ndraft = true\n\
+             header_layout = [\"label\", \"header\", \"icon\", \"permalink\"]",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        assert_eq!(
+            preprocessor.header_layout,
+            [
+                HeaderPiece::Anchor,
+                HeaderPiece::Label,
+                HeaderPiece::Header,
+                HeaderPiece::Permalink
+            ]
+        );
+        let input = String::from(r"{{thm}}{thm:main} {{lem}}");
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &preprocessor,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+            &mut Vec::new(),
+        );
+        let expected = String::from(
+            "<a id=\"thm:main\"></a>\n<small class=\"numthm-label\">⟨thm:main⟩</small> **Theorem 1.2.1.** \
+            <a class=\"numthm-permalink\" href=\"#thm:main\">¶</a> **Lemma 1.2.1.**",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn filtered_lists() {
+        let ctx = context("[preprocessor.numthm]");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:lagrange}[Lagrange] {{lem}} {{def}}{def:group}[Group]\n\
+            {{numthm-list: thm,lem chapter=current}}"
+                .into(),
+            "groups/index.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Rings",
+            "{{thm}}{thm:chinese}[Chinese Remainder]\n\
+            {{numthm-list: thm, def title-only sort=title}}\n\
+            {{numthm-list}}"
+                .into(),
+            "rings.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        let contents = contents(&book);
+        assert!(contents[0].ends_with(
+            "\n- [Theorem 1 (Lagrange)](#thm:lagrange)\n\
+            - Lemma 1"
+        ));
+        assert!(contents[1].ends_with(
+            "\n- [Theorem 1 (Chinese Remainder)](#thm:chinese)\n\
+            - [Definition 1 (Group)](groups/index.md#def:group)\n\
+            - [Theorem 1 (Lagrange)](groups/index.md#thm:lagrange)\n\
+            - [Theorem 1 (Lagrange)](groups/index.md#thm:lagrange)\n\
+            - Lemma 1\n\
+            - [Definition 1 (Group)](groups/index.md#def:group)\n\
+            - [Theorem 1 (Chinese Remainder)](#thm:chinese)"
+        ));
+    }
+
+    #[test]
+    fn grouped_lists() {
+        let ctx = context("[preprocessor.numthm]\nprefix = true");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        for (name, number, content) in [
+            ("Groups", Some(1), "{{thm}}{thm:lagrange}[Lagrange] {{def}}"),
+            ("Rings", Some(2), "{{thm}}[Chinese Remainder]"),
+            (
+                "Index",
+                None,
+                "{{numthm-index: thm}}\n\n{{numthm-list: group=chapter sort=title}}",
+            ),
+        ] {
+            let mut chapter = Chapter::new(name, content.into(), format!("{name}.md"), vec![]);
+            chapter.number = number.map(|n| SectionNumber(vec![n]));
+            book.push_item(chapter);
+        }
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[2],
+            "**[1. Groups](Groups.md)**\n\n\
+             - [Theorem 1.1 (Lagrange)](Groups.md#thm:lagrange)\n\n\
+             **[2. Rings](Rings.md)**\n\n\
+             - Theorem 2.1 (Chinese Remainder)\n\n\
+             **[1. Groups](Groups.md)**\n\n\
+             - [Theorem 1.1 (Lagrange)](Groups.md#thm:lagrange)\n\
+             - Definition 1.1\n\n\
+             **[2. Rings](Rings.md)**\n\n\
+             - Theorem 2.1 (Chinese Remainder)"
+        );
+    }
+
+    #[test]
+    fn unnumbered_in_lists() {
+        let ctx = context(
+            "[preprocessor.numthm.environments]\nthms = {name = \"Theorem\", numbered = false}",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:a} {{thms}}{thms:fermat}[Fermat's Last Theorem] {{thms}} {{thm}}[Lagrange]\n\n\
+             {{numthm-list: thm,thms}}\n\n{{numthm-list: thm,thms unnumbered sort=title}}"
+                .into(),
+            "groups.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[0].split("\n\n").skip(1).collect::<Vec<_>>(),
+            [
+                "- [Theorem 1](#thm:a)\n\
+                 - Theorem 2 (Lagrange)",
+                "- [Fermat's Last Theorem](#thms:fermat)\n\
+                 - Theorem 2 (Lagrange)\n\
+                 - [Theorem 1](#thm:a)"
+            ]
+        );
+    }
+
+    #[test]
+    fn restatements() {
+        let ctx = context("[preprocessor.numthm]");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{#thm}}{thm:lagrange}[Lagrange] The order of {{ref: def:sub}} divides `{{ref: x}}`:\n\
+             $$\nn = km {{eq}}{eq:order}\n$$\n{{/thm}}\n\n{{def}}{def:sub}"
+                .into(),
+            "groups.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Rings",
+            "{{thm}} We now restate\n\n{{restate: thm:lagrange}}\n\n\
+             {{restate: def:sub}}\n{{restate: thm:x}}\n\nSee {{restate: def:sub}}."
+                .into(),
+            "rings.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[1],
+            "**Theorem 1.** We now restate\n\n<div class=\"numthm numthm-thm\">\n\n\
+             **Theorem 1 (Lagrange).** The order of [Definition 1](groups.md#def:sub) divides `{{ref: x}}`:\n\
+             $$\nn = km \\tag{1}\n$$\n\n</div>\n\n\
+             **Definition 1.**\n**[??]**\n\nSee {{restate: def:sub}}."
+        );
+    }
+
+    #[test]
+    fn solutions() {
+        let ctx = context("[preprocessor.numthm]");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{exercise}}{ex:order} Compute the order.\n\n{{exercise}}{ex:open} Solve it.".into(),
+            "groups.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Solutions",
+            "{{solution: ex:order}} It is 2.\n\n{{solution: ex:x}}".into(),
+            "appendix/solutions.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            [
+                "<a id=\"ex:order\"></a>\n**Exercise 1.** Compute the order.\n\n\
+                 <small class=\"numthm-solution-link\">[Solution](appendix/solutions.md#ex:order-solution)</small>\n\n\
+                 <a id=\"ex:open\"></a>\n**Exercise 2.** Solve it.",
+                "<a id=\"ex:order-solution\"></a>\n**Solution [1](../groups.md#ex:order).** It is 2.\n\n**[??]**"
+            ]
+        );
+    }
+
+    #[test]
+    fn previews() {
+        let ctx = context("[preprocessor.numthm]\npreviews = true");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{#lem}}{lem:order}[Order] The order of {{ref: def:sub}}\ndivides <b>n</b> | `m`:\n\
+             $$\nn = km {{eq}}{eq:order}\n$$\n{{/lem}}\n\n{{def}}{def:sub}"
+                .into(),
+            "groups.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Rings",
+            "By {{ref: lem:order}} and {{ref: thm:x}}.".into(),
+            "rings.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[1],
+            "By <span class=\"numthm-preview\">[Lemma 1](groups.md#lem:order)\
+             <span class=\"numthm-preview-body\" role=\"tooltip\">**Lemma 1 (Order).** \
+             The order of Definition 1 divides n &#124; `m`: …</span></span> and **[??]**."
+        );
+    }
+
+    #[test]
+    fn plain_refs() {
+        let input = "![Graph of {{ref: thm:a}}](graph.png \"By {{tref: thm:a}}\") \
+                     [see {{ref: thm:a | the theorem}}](x.md) {{ref: thm:a}}";
+        let mut refs = HashMap::new();
+        find_and_replace_envs(
+            "{{thm}}{thm:a}[Main]",
+            SECNUM,
+            &PATH,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            find_and_replace_refs(input, &PATH, &refs, &CONFIG).unwrap(),
+            "![Graph of Theorem 1.2.1](graph.png \"By Main\") [see the theorem](x.md) \
+             [Theorem 1.2.1](#thm:a)"
+        );
+        let config = NumThmPreprocessor {
+            plain_refs: PlainRefs::Number,
+            ..Default::default()
+        };
+        assert_eq!(
+            find_and_replace_refs(input, &PATH, &refs, &config).unwrap(),
+            "![Graph of 1.2.1](graph.png \"By 1.2.1\") [see the theorem](x.md) [Theorem 1.2.1](#thm:a)"
+        );
+    }
+
+    #[test]
+    fn reference_styles() {
+        let ctx = context(
+            "[preprocessor.numthm.environments]\nthm = {ref_name = \"Thm.\"}\n\
+             lem = {ref_name = \"Lem.\", ref_style = \"full\"}\nprop = {ref_style = \"number\"}",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:a} {{lem}}{lem:b} {{prop}}{prop:c}\n\n\
+             {{ref: thm:a}} {{ref: lem:b}} {{ref: prop:c}} {{nref: thm:a}} {{nref: lem:b | one}}"
+                .into(),
+            "groups.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert!(contents(&book)[0].ends_with(
+            "[Thm. 1](#thm:a) [Lemma 1](#lem:b) [1](#prop:c) [1](#thm:a) [one](#lem:b)"
+        ));
+    }
+
+    #[test]
+    fn hooks() {
+        #[derive(Default)]
+        struct Metrics {
+            envs: Mutex<Vec<String>>,
+            refs: Mutex<Vec<(String, bool)>>,
+        }
+
+        impl Hooks for Metrics {
+            fn environment(&self, env: &Environment) -> Option<String> {
+                self.envs.lock().unwrap().push(env.num_name.to_string());
+                (env.key == "def").then(|| format!("<dfn>{}</dfn>", env.output))
+            }
+
+            fn reference(&self, reference: &Reference) -> Option<String> {
+                let resolved = reference.target.is_some();
+                self.refs
+                    .lock()
+                    .unwrap()
+                    .push((reference.label.to_string(), resolved));
+                None
+            }
+        }
+
+        let ctx = context("[preprocessor.numthm]");
+        let metrics = Arc::new(Metrics::default());
+        let preprocessor = NumThmPreprocessor::new(&ctx).with_hooks(metrics.clone());
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}} {{def}} {{ref: thm:main}}".into(),
+            "groups.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec!["**Theorem 1.** <dfn>**Definition 1.**</dfn> **[??]**"]
+        );
+        assert_eq!(
+            *metrics.envs.lock().unwrap(),
+            vec!["Theorem 1", "Definition 1"]
+        );
+        assert_eq!(
+            *metrics.refs.lock().unwrap(),
+            vec![("thm:main".to_string(), false)]
+        );
+    }
+
+    #[test]
+    fn initial_state() {
+        let book_toml = r#"
+[preprocessor.numthm]
+prefix = true
+chapter_offset = 3
+
+[preprocessor.numthm.initial_counters]
+thm = 4
+"#;
+        let ctx = context(book_toml);
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        for (i, path) in ["groups.md", "rings.md"].iter().enumerate() {
+            let mut chapter = Chapter::new(path, "{{thm}}".into(), path, vec![]);
+            chapter.number = Some(SectionNumber(vec![i as u32 + 1]));
+            book.push_item(chapter);
+        }
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec!["**Theorem 4.5.**", "**Theorem 5.1.**"]
+        );
+
+        let book_toml = r#"
+[preprocessor.numthm]
+numbering_scope = "part"
+initial-part = 2
+initial-counters = {thm = 4}
+"#;
+        let ctx = context(book_toml);
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(BookItem::PartTitle("Algebra".into()));
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}".into(),
+            "groups.md",
+            vec![],
+        ));
+        book.push_item(BookItem::PartTitle("Analysis".into()));
+        book.push_item(Chapter::new(
+            "Limits",
+            "{{thm}}".into(),
+            "limits.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec!["**Theorem III.5.**", "**Theorem IV.1.**"]
+        );
+
+        // values which don't fit a counter are ignored
+        let ctx = context("[preprocessor.numthm.initial_counters]\nthm = 4294967296\nlem = 2");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        assert_eq!(
+            preprocessor.initial_counters,
+            HashMap::from([("lem".to_string(), 2)])
+        );
+    }
+
+    #[test]
+    fn same_page_links() {
+        let book_toml = "[preprocessor.numthm.same_page_links]\nhtml = \"explicit\"";
+        let mut ctx = context(book_toml);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:lagrange} {{selfref}} {{ref: thm:lagrange}}".into(),
+            "crypto/groups.md",
+            vec![],
+        ));
+        let output = NumThmPreprocessor::new(&ctx)
+            .run(&ctx, book.clone())
+            .unwrap();
+        assert_eq!(
+            contents(&output),
+            vec![
+                "<a id=\"thm:lagrange\"></a>\n**Theorem 1.** \
+                [Theorem 1](groups.md#thm:lagrange) [Theorem 1](groups.md#thm:lagrange)"
+            ]
+        );
+
+        ctx.renderer = "epub".into();
+        let output = NumThmPreprocessor::new(&ctx).run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&output),
+            vec![
+                "<a id=\"thm:lagrange\"></a>\n**Theorem 1.** \
+                [Theorem 1](#thm:lagrange) [Theorem 1](#thm:lagrange)"
+            ]
+        );
+    }
+
+    #[test]
+    fn name_cases() {
+        let book_toml = r#"
+[preprocessor.numthm.name_case]
+header = "upper"
+ref = "lower"
+
+[preprocessor.numthm.environments]
+cor = {name = "main corollary"}
+"#;
+        let ctx = context(book_toml);
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:lagrange} {{selfref}} {{cor}}{cor:main} {{ref: cor:main}}\n\
+            {{numthm-list}}"
+                .into(),
+            "groups.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:lagrange\"></a>\n**THEOREM 1.** [theorem 1](#thm:lagrange) \
+                <a id=\"cor:main\"></a>\n**MAIN COROLLARY 1.** [main corollary 1](#cor:main)\n\
+                - [Theorem 1](#thm:lagrange)\n\
+                - [main corollary 1](#cor:main)"
+            ]
+        );
+        assert_eq!(NameCase::Title.apply("main corollary"), "Main Corollary");
+    }
+
+    #[test]
+    fn ref_and_header_names() {
+        let ctx = context(
+            "[preprocessor.numthm.environments]\n\
+             thm = {ref_name = \"Thm.\", header_name = \"Main Theorem\"}",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:x} {{selfref}} {{ref: thm:x}}\n{{numthm-list}}".into(),
+            "groups.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:x\"></a>\n**Main Theorem 1.** [Thm. 1](#thm:x) [Thm. 1](#thm:x)\n\
+                - [Theorem 1](#thm:x)"
+            ]
+        );
+    }
+
+    #[test]
+    fn anchor_prefixes() {
+        let ctx = context("[preprocessor.numthm.environments]\nthm = {anchor_prefix = \"thm--\"}");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{lagrange} {{selfref}} {{lem}}{aux}".into(),
+            "groups.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Rings",
+            "{{ref: lagrange}} {{ref: aux}}".into(),
+            "rings.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm--lagrange\"></a>\n**Theorem 1.** [Theorem 1](#thm--lagrange) \
+                <a id=\"aux\"></a>\n**Lemma 1.**",
+                "[Theorem 1](groups.md#thm--lagrange) [Lemma 1](groups.md#aux)",
+            ]
+        );
+    }
+
+    #[test]
+    fn rename_label() {
+        let input = "{{thm}}{thm:main}[Main] {{export: thm:main}} {{ref: thm:main}}\n\
+                     {{tref:thm:main }} {{ref: thm:main2}} [thm:main](#thm:main)";
+        let (output, count) = CONFIG.rename_label(input, "thm:main", "thm:lagrange");
+        assert_eq!(
+            output,
+            "{{thm}}{thm:lagrange}[Main] {{export: thm:lagrange}} {{ref: thm:lagrange}}\n\
+             {{tref:thm:lagrange }} {{ref: thm:main2}} [thm:main](#thm:main)"
+        );
+        assert_eq!(count, 4);
+    }
+
+    #[test]
+    fn relative_numbers() {
+        let ctx = context("[preprocessor.numthm]\nprefix = true\nrelative_numbers = true");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        for (i, (path, content)) in [
+            (
+                "groups.md",
+                "{{thm}}{thm:lagrange} {{selfref}} {{ref: thm:lagrange}}",
+            ),
+            ("rings.md", "{{ref: thm:lagrange}}"),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let mut chapter = Chapter::new(path, content.to_string(), path, vec![]);
+            chapter.number = Some(SectionNumber(vec![i as u32 + 1]));
+            book.push_item(chapter);
+        }
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:lagrange\"></a>\n**Theorem 1.** \
+                [Theorem 1](#thm:lagrange) [Theorem 1](#thm:lagrange)",
+                "[Theorem 1.1](groups.md#thm:lagrange)",
+            ]
+        );
+    }
+
+    #[test]
+    fn derived_environments() {
+        let ctx = context("[preprocessor.numthm.environments]\ncor = {name = \"Corollary\"}");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:lagrange} {{cor of=thm:lagrange}}{cor:order} \
+            {{cor of=\"thm:lagrange\"}}[Fermat]"
+                .into(),
+            "groups.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:lagrange\"></a>\n**Theorem 1.** \
+                <a id=\"cor:order\"></a>\n**Corollary 1 (of [Theorem 1](#thm:lagrange)).** \
+                **Corollary 2 (Fermat, of [Theorem 1](#thm:lagrange)).**"
+            ]
+        );
+
+        let (output, count) = preprocessor.rename_label(
+            "{{thm}}{thm:lagrange} {{cor of=thm:lagrange}}",
+            "thm:lagrange",
+            "thm:main",
+        );
+        assert_eq!(output, "{{thm}}{thm:main} {{cor of=thm:main}}");
+        assert_eq!(count, 2);
+        assert!(find_malformed_macros("{{cor of=thm:lagrange}}", &preprocessor).is_empty());
+    }
+
+    #[test]
+    fn frozen_regions() {
+        let ctx = context("[preprocessor.numthm]");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:a}\n{{numthm-freeze}}\n> {{thm}}{thm:b} {{ref: thm:b}}\n{{numthm-unfreeze}}\n\
+            {{thm}} {{numthm-freeze: render}}{{thm}}{thm:c}[Quoted] {{ref: thm:a}}{{numthm-unfreeze}} \
+            {{ref: thm:b}}"
+                .into(),
+            "groups.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:a\"></a>\n**Theorem 1.**\n\n> {{thm}}{thm:b} {{ref: thm:b}}\n\n\
+                **Theorem 2.** **Theorem (Quoted).** {{ref: thm:a}} **[??]**"
+            ]
+        );
+    }
+
+    #[test]
+    fn off_directives() {
+        let input = "{{thm}} <!-- numthm: off -->\n{{thm}} `{{lem}}` <!-- x -->\n<!-- numthm: on --> {{thm}} \
+                     `<!-- numthm: off -->` {{thm}} <!--numthm:off--> {{thm}}";
+        let (frozen, regions) = freeze_regions(input, &CONFIG);
+        assert_eq!(
+            frozen,
+            "{{thm}} \u{E000}0\n\n\u{E001} {{thm}} \u{E000}1\u{E001} {{thm}} \u{E000}2\u{E001}"
+        );
+        assert_eq!(
+            regions,
+            [
+                "<!-- numthm: off -->\n{{thm}} `{{lem}}` <!-- x -->\n<!-- numthm: on -->",
+                "`<!-- numthm: off -->`",
+                "<!--numthm:off--> {{thm}}"
+            ]
+        );
+    }
+
+    #[test]
+    fn check() {
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:a} {{lem}}{thm:b} {{prop}}{thm:d}\n{{thm}}{thm:a} {{ref: thm:c}}\n\n\
+             $$ x {{eq}}{eq:x} $$ {{ref: thm:b}} {{ref: thm:d}}"
+                .into(),
+            "groups.md",
+            vec![],
+        ));
+        let issues = |options: &str| -> Vec<(CheckKind, bool, String)> {
+            let ctx = context(&format!(
+                "[preprocessor.numthm]\n{options}\n\
+                 [preprocessor.numthm.environments]\nlem = {{label_prefix = \"lem:\"}}"
+            ));
+            NumThmPreprocessor::new(&ctx)
+                .check(&book)
+                .iter()
+                .map(|issue| (issue.kind, issue.error, issue.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            issues(""),
+            [
+                (
+                    CheckKind::LabelPrefix,
+                    true,
+                    "groups.md:1: Label `thm:b' of a `lem' environment should start with `lem:'"
+                        .to_string()
+                ),
+                (
+                    CheckKind::DuplicateLabel,
+                    true,
+                    "groups.md:2: Label `thm:a' already defined at groups.md:1".to_string()
+                ),
+                (
+                    CheckKind::UnresolvedRef,
+                    true,
+                    "groups.md:2: Reference to undefined label `thm:c'".to_string()
+                ),
+                (
+                    CheckKind::UnusedLabel,
+                    false,
+                    "groups.md:1: Label `thm:a' is never referenced".to_string()
+                ),
+                (
+                    CheckKind::UnusedLabel,
+                    false,
+                    "groups.md:4: Label `eq:x' is never referenced".to_string()
+                ),
+            ]
+        );
+        // suffixed duplicates are not errors
+        assert_eq!(
+            issues("duplicate_labels = \"suffix\"")[1],
+            (
+                CheckKind::DuplicateLabel,
+                false,
+                "groups.md:2: Label `thm:a' already defined at groups.md:1, renamed to `thm:a-2'"
+                    .to_string()
+            )
+        );
+
+        // references to labels private to another chapter are unresolved
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{export: thm:a}}{{thm}}{thm:a} {{thm}}{thm:b} {{ref: thm:b}}".into(),
+            "groups.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Rings",
+            "{{ref: thm:a}} {{ref: thm:b}}".into(),
+            "rings.md",
+            vec![],
+        ));
+        let ctx = context("[preprocessor.numthm]\nprivate_labels = true");
+        let unresolved: Vec<String> = NumThmPreprocessor::new(&ctx)
+            .check(&book)
+            .iter()
+            .filter(|issue| issue.kind == CheckKind::UnresolvedRef)
+            .map(|issue| issue.to_string())
+            .collect();
+        assert_eq!(
+            unresolved,
+            ["rings.md:1: Reference to label `thm:b' private to chapter `groups.md'"]
+        );
+    }
+
+    #[test]
+    fn processed_marker() {
+        let ctx = context("[preprocessor.numthm]\nprocessed_marker = true");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:a} {{ref: thm:a}}".into(),
+            "groups.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        let BookItem::Chapter(chapter) = &book.sections[0] else {
+            unreachable!()
+        };
+        let processed = chapter.content.clone();
+        assert!(processed.starts_with("<!-- numthm: processed -->\n<a id=\"thm:a\"></a>"));
+        // running the preprocessor on its own output leaves it as is
+        let book = preprocessor.run(&ctx, book).unwrap();
+        let BookItem::Chapter(chapter) = &book.sections[0] else {
+            unreachable!()
+        };
+        assert_eq!(chapter.content, processed);
+    }
+
+    #[test]
+    fn baked_sources() {
+        let root = temp_root("baked");
+        let ctx = context("[preprocessor.numthm]");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:lagrange}[Lagrange]\n\n$$|G| = |H| [G:H] {{eq}}{eq:index}$$\n\n\
+             {{#proof}}\nBy {{ref: eq:index}}.\n{{/proof}}"
+                .into(),
+            "algebra/groups.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Rings",
+            "See {{tref: thm:lagrange}} and {{ref: thm:lagrange}}.".into(),
+            "rings.md",
+            vec![],
+        ));
+        preprocessor.bake(&ctx, book.clone(), &root).unwrap();
+        let processed = contents(&preprocessor.run(&ctx, book).unwrap());
+        let mut baked = Book::new();
+        for (name, path) in [("Groups", "algebra/groups.md"), ("Rings", "rings.md")] {
+            let content = std::fs::read_to_string(root.join(path)).unwrap();
+            assert!(!content.contains("{{"));
+            baked.push_item(Chapter::new(name, content, path, vec![]));
+        }
+        // the baked sources are the processed chapters, which the preprocessor leaves as is
+        assert_eq!(contents(&baked), processed);
+        assert_eq!(contents(&preprocessor.run(&ctx, baked).unwrap()), processed);
+
+        // included files are expanded before macros are replaced, and no output is written
+        let mut ctx = context("[preprocessor.numthm]\ncompletions = \"numthm.json\"");
+        ctx.root = temp_root("baked-book");
+        std::fs::create_dir_all(ctx.root.join("src")).unwrap();
+        std::fs::write(ctx.root.join("src/lemma.md"), "{{lem}}{lem:index}").unwrap();
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{#include lemma.md}}\n\nSee {{ref: lem:index}}.".into(),
+            "groups.md",
+            vec![],
+        ));
+        NumThmPreprocessor::new(&ctx)
+            .bake(&ctx, book, &root)
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("groups.md")).unwrap(),
+            "<a id=\"lem:index\"></a>\n**Lemma 1.**\n\nSee [Lemma 1](#lem:index)."
+        );
+        assert!(!ctx.root.join("numthm.json").exists());
+    }
+
+    #[test]
+    fn latex_syntax() {
+        let input = "\\begin{theorem}[Lagrange]\n\\label{thm:lagrange}\nIt divides.\n\\end{theorem}\n\n\
+                     \\begin{proof}\nObvious.\n\\end{proof} \\begin{lemma*} \\end{lemma*} \\begin{foo}\\end{foo}\n\n\
+                     $$\na = b \\label{eq:x}\n$$\n\n\\Cref{thm:lagrange} \\eqref{eq:x} \\ref{thm:lagrange} `\\ref{x}`";
+        let config = NumThmPreprocessor {
+            latex_syntax: true,
+            ..Default::default()
+        };
+        let (converted, _) = freeze_regions(input, &config);
+        assert_eq!(
+            converted,
+            "{{#thm}}{thm:lagrange}[Lagrange]\nIt divides.\n{{/thm}}\n\n{{#proof}}\nObvious.\n{{/proof}} \
+             {{#lem*}} {{/lem}} \\begin{foo}\\end{foo}\n\n$$\na = b {{eq}}{eq:x}\n$$\n\n\
+             {{ref: thm:lagrange}} {{ref: eq:x}} {{nref: thm:lagrange}} \u{E000}0\u{E001}"
+        );
+
+        let ctx = context("[preprocessor.numthm]\nlatex_syntax = true");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Groups", input.into(), "groups.md", vec![]));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert!(contents(&book)[0]
+            .ends_with("[Theorem 1](#thm:lagrange) [(1)](#eq:x) [1](#thm:lagrange) `\\ref{x}`"));
+    }
+
+    #[test]
+    fn reference_path_styles() {
+        let ctx = context("[preprocessor.numthm]\n[output.html]\nsite-url = \"/book\"");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:a}".into(),
+            "algebra/groups.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Summary",
+            "{{ref: thm:a}} {{ref path=absolute: thm:a}} {{tref path=site: thm:a | it}} \
+             {{pref path = root: thm:a}} {{ref path=relative: thm:a}} {{ref path=home: thm:a}}"
+                .into(),
+            "notes/summary.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[1],
+            "[Theorem 1](../algebra/groups.md#thm:a) [Theorem 1](/algebra/groups.html#thm:a) \
+             [it](/book/algebra/groups.html#thm:a) [Theorem 1 in “Groups”](algebra/groups.md#thm:a) \
+             [Theorem 1](../algebra/groups.md#thm:a) [Theorem 1](../algebra/groups.md#thm:a)"
+        );
+    }
+
+    #[test]
+    fn localization() {
+        let book_toml = r#"
+[book]
+language = "fr"
+
+[preprocessor.numthm.environments]
+thm = {names = {en = "Theorem", fr = "Théorème"}}
+cor = {name = "Corollary", names = {fr = "Corollaire"}}
+
+[preprocessor.numthm.translations.fr]
+unresolved = "**[réf. ?]**"
+of = "du"
+"#;
+        let ctx = context(book_toml);
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groupes",
+            "{{thm}}{thm:a} {{cor of=thm:a}} {{lem}} {{ref: thm:b}}".into(),
+            "groups.md",
+            vec![],
+        ));
+        let output = preprocessor.run(&ctx, book.clone()).unwrap();
+        assert_eq!(
+            contents(&output),
+            vec![
+                "<a id=\"thm:a\"></a>\n**Théorème 1.** **Corollaire 1 (du [Théorème 1](#thm:a)).** \
+                 **Lemma 1.** **[réf. ?]**"
+            ]
+        );
+        let ctx = context(
+            &book_toml.replace("[book]", "[preprocessor.numthm]\nlanguage = \"en\"\n[book]"),
+        );
+        let output = NumThmPreprocessor::new(&ctx).run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&output),
+            vec![
+                "<a id=\"thm:a\"></a>\n**Theorem 1.** **Corollary 1 (of [Theorem 1](#thm:a)).** \
+                 **Lemma 1.** **[??]**"
+            ]
+        );
+    }
+
+    #[test]
+    fn environment_language() {
+        let ctx = context(
+            "[preprocessor.numthm]\npreviews = true\nname_case = {ref = \"lower\"}\n\
+             [preprocessor.numthm.environments]\n\
+             thm = {ref_name = \"Thm.\", names = {fr = \"Théorème\"}}",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:a} {{thm lang=fr}}{thm:b} {{lem lang=fr}}\n\n\
+             {{ref: thm:a}} {{ref: thm:b}} {{nref: thm:b}}"
+                .into(),
+            "groups.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[0],
+            "<a id=\"thm:a\"></a>\n**Theorem 1.** <a id=\"thm:b\"></a>\n**Théorème 2.** **Lemma 1.**\n\n\
+             <span class=\"numthm-preview\">[thm. 1](#thm:a)\
+             <span class=\"numthm-preview-body\" role=\"tooltip\">**Theorem 1.**</span></span> \
+             <span class=\"numthm-preview\">[théorème 2](#thm:b)\
+             <span class=\"numthm-preview-body\" role=\"tooltip\">**Théorème 2.**</span></span> \
+             <span class=\"numthm-preview\">[2](#thm:b)\
+             <span class=\"numthm-preview-body\" role=\"tooltip\">**Théorème 2.**</span></span>"
+        );
+    }
+
+    #[test]
+    fn unnumbered_variants() {
+        let ctx = context("[preprocessor.numthm]");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Numbers",
+            "{{thm*}}{thm:flt}[Fermat's Last Theorem] {{thm}}{thm:a}\n\n\
+             {{#thm*}}[Catalan]\n$x^a - y^b = 1$\n{{/thm}}\n\n{{tref: thm:flt}} {{ref: thm:flt}}"
+                .into(),
+            "numbers.md",
+            vec![],
+        ));
+        let output = preprocessor.run(&ctx, book.clone()).unwrap();
+        assert_eq!(
+            contents(&output),
+            vec![
+                "<a id=\"thm:flt\"></a>\n**Theorem (Fermat's Last Theorem).** <a id=\"thm:a\"></a>\n\
+                 **Theorem 1.**\n\n<div class=\"numthm numthm-thm\">\n\n**Theorem (Catalan).**\n\
+                 $x^a - y^b = 1$\n\n\n</div>\n\n[Fermat's Last Theorem](#thm:flt) [Theorem](#thm:flt)"
+            ]
+        );
+        let ctx = context("[preprocessor.numthm]\noutput = \"latex\"");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let output = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&output),
+            vec![
+                "\\begin{theorem*}[Fermat's Last Theorem]\\label{thm:flt}\n\\end{theorem*} \
+                 \\begin{theorem}\\label{thm:a}\n\\end{theorem}\n\n\\begin{theorem*}[Catalan]\n\
+                 $x^a - y^b = 1$\n\n\\end{theorem*}\n\n\\hyperref[thm:flt]{Fermat's Last Theorem} \\Cref{thm:flt}"
+            ]
+        );
     }
 
     #[test]
-    fn wo_label_wo_title() {
-        let mut refs = HashMap::new();
-        let input = String::from(r"{{prop}}");
-        let output = find_and_replace_envs(&input, SECNUM, &PATH, &ENVMAP, &mut refs);
-        let expected = String::from("**Proposition 1.2.1.**");
-        assert_eq!(output, expected);
-        assert!(refs.is_empty());
+    fn environment_families() {
+        let ctx = context(
+            "[preprocessor.numthm.families.statements]\n\
+             members = {thm = \"Theorem\", lem = \"Lemma\", cor = \"Corollary\"}\nemph = \"*\"\n\
+             [preprocessor.numthm.families.remarks]\nmembers = [\"rem\", \"exr\", \"claim\"]\n\
+             [preprocessor.numthm.environments]\ncor = {emph = \"**\"}\nclaim = {name = \"Claim\"}",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let output = find_and_replace_envs(
+            "{{thm}} {{lem}} {{cor}} {{rem}} {{claim}} {{def}}",
+            "",
+            &PATH,
+            &preprocessor,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            output,
+            "*Theorem 1.* *Lemma 2.* **Corollary 3.** *Remark 1.* **Claim 2.** **Definition 1.**"
+        );
+        assert!(!preprocessor.environments.contains_key("exr"));
     }
 
     #[test]
-    fn wo_label_wo_title_replace_default() {
-        let mut env_map = EnvMap::default();
-        env_map.insert(String::from("prop"), Env::create("Proposal", "*"));
-        let mut refs = HashMap::new();
-        let input = String::from(r"{{prop}}");
-        let output = find_and_replace_envs(&input, SECNUM, &PATH, &env_map, &mut refs);
-        let expected = String::from("*Proposal 1.2.1.*");
-        assert_eq!(output, expected);
-        assert!(refs.is_empty());
+    fn code_is_left_as_is() {
+        let ctx = context("[preprocessor.numthm]");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "Write `{{thm}}` or ``{{ref: `x`}}``.\n\n````md\n{{thm}}\n```\n````\n\n<!-- {{lem}} -->\n\
+             \\{{thm}} {{thm}}{thm:a}[The `Group` trait]"
+                .into(),
+            "groups.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Rings",
+            "{{tref: thm:a}}".into(),
+            "rings.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "Write `{{thm}}` or ``{{ref: `x`}}``.\n\n````md\n{{thm}}\n```\n````\n\n<!-- {{lem}} -->\n\
+                 {{thm}} <a id=\"thm:a\"></a>\n**Theorem 1 (The `Group` trait).**",
+                "[The `Group` trait](groups.md#thm:a)"
+            ]
+        );
     }
 
     #[test]
-    fn with_label_wo_title() {
-        let mut refs = HashMap::new();
-        let input = String::from(r"{{prop}}{prop:lagrange}");
-        let output = find_and_replace_envs(&input, SECNUM, &PATH, &ENVMAP, &mut refs);
+    fn numbering_changes() {
+        let info = |num_name: &str, title: Option<&str>| LabelInfo {
+            env: "thm".to_string(),
+            num_name: num_name.to_string(),
+            name: None,
+            path: "groups.md".into(),
+            title: title.map(String::from),
+            exported: false,
+            local_name: None,
+            of: None,
+            chapter: None,
+            fields: BTreeMap::new(),
+        };
+        let previous = HashMap::from([
+            ("thm:a".to_string(), info("Theorem 1", None)),
+            ("thm:b".to_string(), info("Theorem 2", Some("Old"))),
+            ("thm:c".to_string(), info("Theorem 3", None)),
+        ]);
+        let current = HashMap::from([
+            ("thm:a".to_string(), info("Theorem 1", None)),
+            ("thm:b".to_string(), info("Theorem 3", Some("New"))),
+            ("thm:d".to_string(), info("Theorem 2", None)),
+        ]);
+        assert_eq!(
+            diff_report(&previous, &current),
+            "# Numbering Changes\n\
+            \n## New Labels\n\n- `thm:d`: Theorem 2\n\
+            \n## Removed Labels\n\n- `thm:c`: Theorem 3\n\
+            \n## Renumbered\n\n- `thm:b`: Theorem 2 → Theorem 3\n\
+            \n## Retitled\n\n- `thm:b`: Old → New\n"
+        );
+    }
+
+    #[test]
+    fn redirects() {
+        let info = |num_name: &str, path: &str| LabelInfo {
+            env: "thm".to_string(),
+            num_name: num_name.to_string(),
+            name: None,
+            path: path.into(),
+            title: None,
+            exported: false,
+            local_name: None,
+            of: None,
+            chapter: None,
+            fields: BTreeMap::new(),
+        };
+        let previous = HashMap::from([
+            ("thm:a".to_string(), info("Theorem 1", "groups.md")),
+            ("thm:b".to_string(), info("Theorem 2", "groups.md")),
+            ("thm:c".to_string(), info("Theorem 3", "groups.md")),
+            ("thm:d".to_string(), info("Theorem 4", "groups.md")),
+        ]);
+        let current = HashMap::from([
+            ("thm:a".to_string(), info("Theorem 1", "groups.md")),
+            ("thm:b".to_string(), info("Theorem 1", "algebra/rings.md")),
+            ("thm:lagrange".to_string(), info("Theorem 3", "groups.md")),
+        ]);
+        assert_eq!(
+            redirect_map(&previous, &current, &CONFIG),
+            "[output.html.redirect]\n\
+             \"/groups.html#thm:b\" = \"/algebra/rings.html#thm:b\"\n\
+             \"/groups.html#thm:c\" = \"/groups.html#thm:lagrange\"\n"
+        );
+    }
+
+    #[test]
+    fn marked_environments() {
+        let config = NumThmPreprocessor {
+            mark_environments: true,
+            ..Default::default()
+        };
+        let input = String::from(r"{{thm}}{thm:main} {{rem}}");
+        let output = find_and_replace_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &config,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+            &mut Vec::new(),
+        );
         let expected = String::from(
-            "<a name=\"prop:lagrange\"></a>\n\
-            **Proposition 1.2.1.**",
+            "<a id=\"thm:main\"></a>\n\
+            <span class=\"numthm-header numthm-thm\" data-numthm-env=\"thm\">**Theorem 1.2.1.**</span> \
+            <span class=\"numthm-header numthm-rem\" data-numthm-env=\"rem\">*Remark 1.2.1.*</span>",
         );
         assert_eq!(output, expected);
-        assert_eq!(refs.len(), 1);
+    }
+
+    #[test]
+    fn wiki_links() {
+        let ctx = context("[preprocessor.numthm]\nwiki_links = true");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:lagrange}".into(),
+            "groups.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Rings",
+            "[[thm:lagrange]], [[thm:lagrange|Lagrange's theorem]], [[Some Note]], ![[thm:lagrange]]"
+                .into(),
+            "rings.md",
+            vec![],
+        ));
+        // the label is referenced
+        assert!(preprocessor.check(&book).is_empty());
+        let book = preprocessor.run(&ctx, book).unwrap();
         assert_eq!(
-            *refs.get("prop:lagrange").unwrap(),
-            LabelInfo {
-                num_name: "Proposition 1.2.1".to_string(),
-                path: "crypto/groups.md".into(),
-                title: None,
-            }
-        )
+            contents(&book)[1],
+            "[Theorem 1](groups.md#thm:lagrange), [Lagrange's theorem](groups.md#thm:lagrange), \
+            [[Some Note]], ![[thm:lagrange]]"
+        );
+
+        // wiki-style links are references, e.g. to private labels in strict mode
+        let ctx = context(
+            "[preprocessor.numthm]\nwiki_links = true\nprivate_labels = true\nstrict = true",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:lagrange}".into(),
+            "groups.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Rings",
+            "By [[thm:lagrange|Lagrange]].".into(),
+            "rings.md",
+            vec![],
+        ));
+        let error = preprocessor.run(&ctx, book).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "rings.md:1: Unresolved reference to label `thm:lagrange'"
+        );
     }
 
     #[test]
-    fn wo_label_with_title() {
-        let mut refs = HashMap::new();
-        let input = String::from(r"{{prop}}[Lagrange Theorem]");
-        let output = find_and_replace_envs(&input, SECNUM, &PATH, &ENVMAP, &mut refs);
-        let expected = String::from("**Proposition 1.2.1 (Lagrange Theorem).**");
-        assert_eq!(output, expected);
-        assert!(refs.is_empty());
+    fn auto_links() {
+        let ctx = context("[preprocessor.numthm]\nauto_link = true");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:lagrange} {{lem}}{order}".into(),
+            "groups.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Rings",
+            "By thm:lagrange, {{tref: thm:lagrange}} and `thm:lagrange`, the order divides.\n\
+             See [here](groups.md#thm:lagrange), [thm:lagrange](groups.md) and thm:lagrange-bis."
+                .into(),
+            "rings.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[1],
+            "By [Theorem 1](groups.md#thm:lagrange), [Theorem 1](groups.md#thm:lagrange) \
+             and `thm:lagrange`, the order divides.\n\
+             See [here](groups.md#thm:lagrange), [thm:lagrange](groups.md) and thm:lagrange-bis."
+        );
+
+        // mentions are references, which the chapters they appear in are listed with
+        let ctx = context("[preprocessor.numthm]\nauto_link = true\nbackrefs = true");
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:lagrange}\nBy thm:order, the order divides.".into(),
+            "groups.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Rings",
+            "{{lem}}{thm:order}\nSee thm:lagrange.".into(),
+            "rings.md",
+            vec![],
+        ));
+        let book = NumThmPreprocessor::new(&ctx).run(&ctx, book).unwrap();
+        assert!(contents(&book)[0].contains("By [Lemma 1](rings.md#thm:order), the order divides."));
+        assert!(contents(&book)[0].contains("numthm-backrefs"));
+        assert!(contents(&book)[1].contains("See [Theorem 1](groups.md#thm:lagrange)."));
+        assert!(contents(&book)[1].contains("numthm-backrefs"));
     }
 
     #[test]
-    fn with_label_with_title() {
-        let mut refs = HashMap::new();
-        let input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
-        let output = find_and_replace_envs(&input, SECNUM, &PATH, &ENVMAP, &mut refs);
-        let expected = String::from(
-            "<a name=\"prop:lagrange\"></a>\n\
-            **Proposition 1.2.1 (Lagrange Theorem).**",
+    fn strict_mode() {
+        let ctx = context("[preprocessor.numthm]\nstrict = true");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:lagrange}\n\n$$x {{eq}}{eq:order}$$".into(),
+            "groups.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Rings",
+            "{{lem}}{thm:lagrange}\n\nSee {{ref: eq:order}} and {{tref: thm:unknown}}.".into(),
+            "rings.md",
+            vec![],
+        ));
+        let error = preprocessor.run(&ctx, book.clone()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "rings.md:1: Label `thm:lagrange' already defined at groups.md:1\n\
+             rings.md:3: Unresolved reference to label `thm:unknown'"
         );
-        assert_eq!(output, expected);
+
+        let ctx = context("[preprocessor.numthm]");
+        assert!(NumThmPreprocessor::new(&ctx).run(&ctx, book).is_ok());
     }
 
     #[test]
-    fn double_label() {
-        let mut refs = HashMap::new();
-        let input = String::from(
-            r"{{prop}}{prop:lagrange}[Lagrange Theorem] {{thm}}{prop:lagrange}[Another Lagrange Theorem]",
+    fn chapter_templates() {
+        let ctx = context(
+            "[preprocessor.numthm.templates]\n\
+             ref = \"{text} in *{chapter}*\"\n\
+             list = \"{chapter_number} {chapter} > {text}\"",
         );
-        let output = find_and_replace_envs(&input, SECNUM, &PATH, &ENVMAP, &mut refs);
-        let expected = String::from(
-            "<a name=\"prop:lagrange\"></a>\n\
-            **Proposition 1.2.1 (Lagrange Theorem).** \
-            <a name=\"prop:lagrange\"></a>\n\
-            **Theorem 1.2.1 (Another Lagrange Theorem).**",
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        let mut groups = Chapter::new(
+            "Groups",
+            "{{thm}}{thm:lagrange}".into(),
+            "groups.md",
+            vec![],
+        );
+        groups.number = Some(SectionNumber(vec![3]));
+        book.push_item(groups);
+        book.push_item(Chapter::new(
+            "Rings",
+            "{{ref: thm:lagrange}}\n{{numthm-list}}".into(),
+            "rings.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[1],
+            "[Theorem 1 in *Groups*](groups.md#thm:lagrange)\n\
+             - [3 Groups > Theorem 1](groups.md#thm:lagrange)"
         );
-        assert_eq!(output, expected);
-        assert_eq!(refs.len(), 1);
     }
 
     #[test]
-    fn label_and_ref_in_same_file() {
-        let mut refs = HashMap::new();
-        let input =
-            String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem] {{ref: prop:lagrange}}");
-        let output = find_and_replace_envs(&input, SECNUM, &PATH, &ENVMAP, &mut refs);
-        let output = find_and_replace_refs(&output, &PATH, &refs);
-        let expected = String::from(
-            "<a name=\"prop:lagrange\"></a>\n\
-            **Proposition 1.2.1 (Lagrange Theorem).** \
-            [Proposition 1.2.1](#prop:lagrange)",
+    fn prose_references() {
+        let ctx = context("[preprocessor.numthm.templates]\npref = \"{text} ({title})\"");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        let mut groups = Chapter::new(
+            "Groups",
+            "{{thm}}{thm:lagrange}[Lagrange]".into(),
+            "groups.md",
+            vec![],
+        );
+        groups.number = Some(SectionNumber(vec![3, 1]));
+        book.push_item(groups);
+        book.push_item(Chapter::new(
+            "Appendix",
+            "{{lem}}{lem:x} {{pref: thm:lagrange}}".into(),
+            "appendix.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[1],
+            "<a id=\"lem:x\"></a>\n**Lemma 1.** \
+             [Theorem 1 in Section 3.1 (Lagrange)](groups.md#thm:lagrange)"
+        );
+        assert_eq!(
+            prose_text(
+                "Lemma 1",
+                Some(&ChapterInfo {
+                    title: "Appendix".to_string(),
+                    number: None
+                })
+            ),
+            "Lemma 1 in “Appendix”"
         );
-        assert_eq!(output, expected);
     }
 
     #[test]
-    fn label_and_ref_in_different_files() {
+    fn custom_link_texts() {
+        let ctx = context("[preprocessor.numthm]\ntref_fallback = \"error\"");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{thm}}{thm:lagrange} {{ref: thm:lagrange | the key lemma}}\n\
+             {{tref:thm:lagrange|Lagrange's theorem}} {{ref: thm:lagrange| }}"
+                .into(),
+            "groups.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[0],
+            "<a id=\"thm:lagrange\"></a>\n**Theorem 1.** \
+             [the key lemma](#thm:lagrange)\n\
+             [Lagrange's theorem](#thm:lagrange) [Theorem 1](#thm:lagrange)"
+        );
+    }
+
+    #[test]
+    fn sidebar_counts() {
+        let ctx = context(
+            "[preprocessor.numthm.sidebar_counts]\n\
+             def = [\"{count} definition\", \"{count} definitions\"]\n\
+             thm = \"{count} thm.\"\n\
+             foo = \"{count} foo\"",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{def}} {{def}} {{thm}}".into(),
+            "groups.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new("Rings", "{{def}}".into(), "rings.md", vec![]));
+        book.push_item(Chapter::new(
+            "Fields",
+            "{{lem}}".into(),
+            "fields.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        let names: Vec<&str> = book
+            .iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(chapter) => Some(chapter.name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "Groups (2 definitions, 1 thm.)",
+                "Rings (1 definition)",
+                "Fields"
+            ]
+        );
+    }
+
+    #[test]
+    fn heading_section_numbers() {
+        let ctx = context("[preprocessor.numthm]\nprefix = true\nsection_numbers = \"headings\"");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        for (name, content) in [
+            ("Groups", "# 2.3. Groups\n{{thm}}"),
+            ("Rings", "# 4 Rings\n\n## 4.1 Ideals\n{{thm}}"),
+            ("Preface", "# Preface\n{{thm}}"),
+            // headings inside code are skipped
+            ("Fields", "```sh\n# 7 steps\n```\n# 5 Fields\n{{thm}}"),
+        ] {
+            let mut chapter = Chapter::new(name, content.into(), format!("{name}.md"), vec![]);
+            chapter.number = Some(SectionNumber(vec![1]));
+            book.push_item(chapter);
+        }
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            [
+                "# 2.3. Groups\n**Theorem 2.3.1.**",
+                "# 4 Rings\n\n## 4.1 Ideals\n**Theorem 4.1.**",
+                "# Preface\n**Theorem 1.**",
+                "```sh\n# 7 steps\n```\n# 5 Fields\n**Theorem 5.1.**",
+            ]
+        );
+    }
+
+    #[test]
+    fn figures_and_tables() {
+        let ctx = context("[preprocessor.numthm]");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Groups",
+            "{{tab}}{tab:orders}[Group orders]\n\n| n | 1 |\n\n\
+             ![Cayley graph](cayley.svg)\n\n{{fig}}{fig:cayley}[A Cayley graph]\n\n\
+             {{fig}}\n\nSee {{ref: fig:cayley}} and {{tref: tab:orders}}."
+                .into(),
+            "groups.md",
+            vec![],
+        ));
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[0],
+            "<a id=\"tab:orders\"></a>\n**Table 1.** Group orders\n\n| n | 1 |\n\n\
+             ![Cayley graph](cayley.svg)\n\n<a id=\"fig:cayley\"></a>\n**Figure 1.** A Cayley graph\n\n\
+             **Figure 2.**\n\nSee [Figure 1](#fig:cayley) and [Group orders](#tab:orders)."
+        );
+    }
+
+    #[test]
+    fn intro_environments() {
+        let content = "{{thm}} {{lem}}\n# 2.3. Groups\n{{thm}} {{numthm-count: lem}}";
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Groups", content.into(), "groups.md", vec![]));
+        let numbered = |options: &str| {
+            let ctx = context(&format!(
+                "[preprocessor.numthm]\nprefix = true\nsection_numbers = \"headings\"\n{options}"
+            ));
+            contents(
+                &NumThmPreprocessor::new(&ctx)
+                    .run(&ctx, book.clone())
+                    .unwrap(),
+            )
+        };
+        assert_eq!(
+            numbered(""),
+            ["**Theorem 2.3.1.** **Lemma 2.3.1.**\n# 2.3. Groups\n**Theorem 2.3.2.** 1"]
+        );
+        assert_eq!(
+            numbered("intro_environments = \"chapter\""),
+            ["**Theorem 2.1.** **Lemma 2.1.**\n# 2.3. Groups\n**Theorem 2.3.1.** 1"]
+        );
+    }
+
+    #[test]
+    fn asymmetric_emphasis() {
+        let ctx = context(
+            "[preprocessor.numthm.environments]\n\
+             thm = {emph = \"<strong class=\\\"thm\\\">\"}\n\
+             lem = {emph = [\"<span class=\\\"lem\\\">\", \"</span >\"]}\n\
+             prop = {emph = \"*_\"}\n\
+             def = {emph = [\"<b>\", \"</i>\"]}\n\
+             rem = {emph = \"__\"}",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let envs = &preprocessor.environments;
+        assert_eq!(envs["thm"].emph_close, "</strong>");
+        assert_eq!(envs["lem"].emph_close, "</span >");
+        // invalid emphases are ignored
+        assert_eq!(
+            (envs["prop"].emph.as_str(), envs["prop"].emph_close.as_str()),
+            ("**", "**")
+        );
+        assert_eq!(
+            (envs["def"].emph.as_str(), envs["def"].emph_close.as_str()),
+            ("**", "**")
+        );
+        assert_eq!(envs["rem"].emph_close, "__");
+
+        let output = find_and_replace_envs(
+            "{{thm}}[Lagrange]",
+            "",
+            &PATH,
+            &preprocessor,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            output,
+            "<strong class=\"thm\">Theorem 1 (Lagrange).</strong>"
+        );
+    }
+
+    #[test]
+    fn label_whitespace() {
         let mut refs = HashMap::new();
-        let label_file: PathBuf = "math/groups.md".into();
-        let ref_file: PathBuf = "crypto/bls_signatures.md".into();
-        let label_input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
-        let ref_input = String::from(r"{{ref: prop:lagrange}}");
-        let _label_output =
-            find_and_replace_envs(&label_input, SECNUM, &label_file, &ENVMAP, &mut refs);
-        let ref_output = find_and_replace_refs(&ref_input, &ref_file, &refs);
-        let expected = String::from("[Proposition 1.2.1](../math/groups.md#prop:lagrange)");
-        assert_eq!(ref_output, expected);
+        let output = find_and_replace_envs(
+            "{{thm}}{ thm:x }",
+            "",
+            &PATH,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        assert_eq!(output, "<a id=\"thm:x\"></a>\n**Theorem 1.**");
+        let output =
+            find_and_replace_refs("{{ref:   thm:x }} {{tref:thm:x\t}}", &PATH, &refs, &CONFIG)
+                .unwrap();
+        assert_eq!(output, "[Theorem 1](#thm:x) [Theorem 1](#thm:x)");
+
+        let (renamed, count) =
+            CONFIG.rename_label("{{thm}}{ thm:x } {{ref:   thm:x }}", "thm:x", "thm:y");
+        assert_eq!(
+            (renamed.as_str(), count),
+            ("{{thm}}{ thm:y } {{ref:   thm:y }}", 2)
+        );
+        assert_eq!(
+            find_refs("{{ref: a  b }}", &CONFIG.syntax),
+            [(1, "a b".to_string())]
+        );
     }
 
     #[test]
-    fn label_and_ref_in_different_files_2() {
+    fn context_file() {
+        let mut entries = Vec::new();
+        let mut groups = Chapter::new(
+            "Groups",
+            "{{thm}}{thm:lagrange}[Lagrange]\n{{lem}}".into(),
+            "groups.md",
+            vec![],
+        );
+        groups.number = Some(SectionNumber(vec![2]));
+        groups.content = find_and_replace_envs(
+            &groups.content,
+            "2.",
+            &PathBuf::from("groups.md"),
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+            &mut entries,
+        );
+        CONFIG.record_chapter(&groups, &mut entries, &mut HashMap::new());
+        let context: serde_json::Value =
+            serde_json::from_str(&render_context(&entries, &CONFIG).unwrap()).unwrap();
+        assert_eq!(
+            context,
+            serde_json::json!({
+                "version": 1,
+                "environments": [
+                    {
+                        "key": "thm",
+                        "number": "2.1",
+                        "num_name": "Theorem 2.1",
+                        "label": "thm:lagrange",
+                        "title": "Lagrange",
+                        "path": "groups.md",
+                        "anchor": "thm:lagrange",
+                        "chapter": {"title": "Groups", "number": "2"},
+                    },
+                    {
+                        "key": "lem",
+                        "number": "2.1",
+                        "num_name": "Lemma 2.1",
+                        "label": null,
+                        "title": null,
+                        "path": "groups.md",
+                        "anchor": null,
+                        "chapter": {"title": "Groups", "number": "2"},
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn integrity_file() {
         let mut refs = HashMap::new();
-        let label_file: PathBuf = "math/algebra/groups.md".into();
-        let ref_file: PathBuf = "math/crypto//signatures/bls_signatures.md".into();
-        let label_input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
-        let ref_input = String::from(r"{{ref: prop:lagrange}}");
-        let _label_output =
-            find_and_replace_envs(&label_input, SECNUM, &label_file, &ENVMAP, &mut refs);
-        let ref_output = find_and_replace_refs(&ref_input, &ref_file, &refs);
-        let expected = String::from("[Proposition 1.2.1](../../algebra/groups.md#prop:lagrange)");
-        assert_eq!(ref_output, expected);
+        find_and_replace_envs(
+            "{{thm}}{thm:lagrange} {{lem}}{lem:x} {{def}}{def:group}",
+            "",
+            &PathBuf::from("groups.md"),
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let occurrences: Vec<RefOccurrence> = find_refs(
+            "{{ref: thm:lagrange}} {{tref: thm:lagrange}}\n{{ref: thm:cauchy}} {{pref: lem:x}}",
+            &CONFIG.syntax,
+        )
+        .into_iter()
+        .map(|(line, label)| RefOccurrence {
+            path: PathBuf::from("rings.md"),
+            line,
+            label,
+        })
+        .collect();
+        let summary: serde_json::Value =
+            serde_json::from_str(&integrity_summary(&occurrences, &refs, &CONFIG).unwrap())
+                .unwrap();
+        assert_eq!(
+            summary,
+            serde_json::json!({
+                "references": 4,
+                "broken": 1,
+                "unused_labels": 1,
+                "status": "1 broken",
+            })
+        );
     }
 
     #[test]
-    fn title_ref() {
+    fn unknown_environments() {
+        let input = "{{thm}}{thm:main}\n{{cor}}{cor:x} {{selfref}}\n{{ Conj of=thm:main}}[Title] {{ref: thm:main}} {{#include x.md}}";
+        assert_eq!(
+            find_unknown_environments(input, &CONFIG),
+            [(2, "cor".to_string()), (3, "Conj".to_string())]
+        );
+    }
+
+    #[test]
+    fn counter_field() {
+        let ctx = context(
+            "[preprocessor.numthm.environments]\n\
+             thm = {counter = \"results\"}\n\
+             lem = {counter = \"results\"}\n\
+             cor = {name = \"Corollary\", counter = \"results\"}\n\
+             [preprocessor.numthm.counters.others]\n\
+             members = [\"lem\", \"def\"]",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let envs = &preprocessor.environments;
+        assert_eq!(envs["cor"].counter.as_deref(), Some("results"));
+        // the counter field takes precedence over counter groups
+        assert_eq!(envs["lem"].counter.as_deref(), Some("results"));
+        assert_eq!(envs["def"].counter.as_deref(), Some("others"));
+
+        let output = find_and_replace_envs(
+            "{{thm}} {{lem}} {{def}} {{cor}}",
+            "3.",
+            &PATH,
+            &preprocessor,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            output,
+            "**Theorem 3.1.** **Lemma 3.2.** **Definition 3.1.** **Corollary 3.3.**"
+        );
+    }
+
+    #[test]
+    fn duplicate_titles() {
+        let mut entries = Vec::new();
+        find_and_replace_envs(
+            "{{thm}}[Lagrange] {{lem}}[Lagrange] {{thm}}[Example] {{thm}}[ Lagrange ] {{thm}}[Example] {{thm}}",
+            "",
+            &PATH,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+            &mut entries,
+        );
+        let ignore = HashSet::from(["Example".to_string()]);
+        let duplicates: Vec<(&str, &str)> = find_duplicate_titles(&entries, &ignore)
+            .into_iter()
+            .map(|(entry, first)| (entry.num_name.as_str(), first.num_name.as_str()))
+            .collect();
+        assert_eq!(duplicates, [("Theorem 3", "Theorem 1")]);
+        assert_eq!(find_duplicate_titles(&entries, &HashSet::new()).len(), 2);
+    }
+
+    #[test]
+    fn maximum_depth() {
+        let ctx = context(
+            "[preprocessor.numthm]\nmax_depth = 3\ndepth_overflow = \"truncate\"\n\
+             [preprocessor.numthm.environments]\ncor = {name = \"Corollary\", within = \"thm\"}",
+        );
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut counters = HashMap::new();
+        let output = find_and_replace_envs(
+            "{{thm}} {{cor}}",
+            "1.2.3.",
+            &PATH,
+            &preprocessor,
+            &mut counters,
+            &mut HashMap::new(),
+            &mut Vec::new(),
+        );
+        assert_eq!(output, "**Theorem 1.2.1.** **Corollary 1.1.1.**");
+        let output = find_and_replace_equations(
+            "$$x {{eq}}$$",
+            "1.2.3.",
+            &PATH,
+            &preprocessor,
+            &mut counters,
+            &mut HashMap::new(),
+        );
+        assert_eq!(output, "$$x \\tag{1.2.1}$$");
+    }
+
+    #[test]
+    fn wrapper_anchors() {
+        let ctx = context("[preprocessor.numthm]\nanchor_placement = \"wrapper\"");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let output = find_and_replace_envs(
+            "{{#thm}}{thm:x}\n$G$ is finite.\n{{/thm}}\n\n{{lem}}{lem:y} $H$ is finite.",
+            "",
+            &PATH,
+            &preprocessor,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            output,
+            "<div class=\"numthm numthm-thm\" id=\"thm:x\">\n\n**Theorem 1.**\n$G$ is finite.\n\n\n</div>\n\n\
+             <div id=\"lem:y\"></div>\n\n**Lemma 1.** $H$ is finite."
+        );
+    }
+
+    #[test]
+    fn block_environments() {
+        let ctx = context("[preprocessor.numthm]\nblock_class = \"box {key}-box\"");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let input = "{{#thm}}{thm:x}[Lagrange]\nThe order of a subgroup divides the order of the group.\n{{/thm}}\n{{/lem}}";
+        let output = find_and_replace_envs(
+            input,
+            "",
+            &PATH,
+            &preprocessor,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            output,
+            "<div class=\"box thm-box\">\n\n<a id=\"thm:x\"></a>\n**Theorem 1 (Lagrange).**\n\
+             The order of a subgroup divides the order of the group.\n\n\n</div>\n{{/lem}}"
+        );
+        assert_eq!(
+            check_blocks(input, &preprocessor),
+            BlockNesting {
+                errors: vec![(4, "`{{/lem}}' without matching block".to_string())],
+                unmatched: HashSet::from([1]),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            check_blocks("{{#thm}}\n{{#lem}}\n{{/thm}}", &preprocessor).errors,
+            [
+                (
+                    3,
+                    "`{{/thm}}' closes block `lem' opened on line 2".to_string()
+                ),
+                (2, "Block `lem' is never closed".to_string()),
+                (1, "Block `thm' is never closed".to_string()),
+            ]
+        );
+        // blocks which are never closed are closed at the end of the chapter
+        let output = find_and_replace_envs(
+            "{{#thm}}\nBody.",
+            "",
+            &PATH,
+            &preprocessor,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            output,
+            "<div class=\"box thm-box\">\n\n**Theorem 1.**\nBody.\n\n</div>"
+        );
+    }
+
+    #[test]
+    fn front_matter_prefix() {
+        let ctx = context("[preprocessor.numthm]\nprefix = true\nfront_matter_prefix = \"roman\"");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        for (name, number) in [
+            ("Preface", None),
+            ("Notation", None),
+            ("Groups", Some(1)),
+            ("Epilogue", None),
+        ] {
+            let mut chapter = Chapter::new(name, "{{def}}".into(), format!("{name}.md"), vec![]);
+            chapter.number = number.map(|n| SectionNumber(vec![n]));
+            book.push_item(chapter);
+        }
+        let book = preprocessor.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            [
+                "**Definition i.1.**",
+                "**Definition ii.1.**",
+                "**Definition 1.1.**",
+                "**Definition 1.**",
+            ]
+        );
+    }
+
+    #[test]
+    fn unnumbered_chapters() {
+        let run = |numbering: &str| {
+            let ctx = context(&format!(
+                "[preprocessor.numthm]\nprefix = true\nunnumbered_chapters = \"{numbering}\"\nfallback_prefix = \"A.\""
+            ));
+            let preprocessor = NumThmPreprocessor::new(&ctx);
+            let mut book = Book::new();
+            for (name, number) in [("Groups", Some(1)), ("Notes", None), ("More Notes", None)] {
+                let mut chapter =
+                    Chapter::new(name, "{{def}} {{def}}".into(), format!("{name}.md"), vec![]);
+                chapter.number = number.map(|n| SectionNumber(vec![n]));
+                book.push_item(chapter);
+            }
+            preprocessor
+                .run(&ctx, book)
+                .map(|book| contents(&book)[1..].join(" | "))
+        };
+        assert_eq!(
+            run("none").unwrap(),
+            "**Definition 1.** **Definition 2.** | **Definition 1.** **Definition 2.**"
+        );
+        assert_eq!(
+            run("skip").unwrap(),
+            "**Definition.** **Definition.** | **Definition.** **Definition.**"
+        );
+        assert_eq!(
+            run("fallback").unwrap(),
+            "**Definition A.1.** **Definition A.2.** | **Definition A.1.** **Definition A.2.**"
+        );
+        assert_eq!(
+            run("global").unwrap(),
+            "**Definition 1.** **Definition 2.** | **Definition 3.** **Definition 4.**"
+        );
+        assert!(run("error").is_err());
+    }
+
+    #[test]
+    fn emphasis_per_renderer() {
+        let toml = "[preprocessor.numthm.environments]\n\
+                    thm = {emph = {html = \"<strong>\", latex = [\"\\\\textbf{\", \"}\"]}}\n\
+                    lem = {emph = {markdown = \"*\", default = \"__\"}}";
+        let mut ctx = context(toml);
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        assert_eq!(preprocessor.environments["thm"].emph_close, "</strong>");
+        assert_eq!(preprocessor.environments["lem"].emph, "__");
+
+        ctx.renderer = "markdown".to_string();
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        // renderers without an entry keep the builtin emphasis
+        assert_eq!(preprocessor.environments["thm"].emph, "**");
+        assert_eq!(preprocessor.environments["lem"].emph, "*");
+
+        ctx.renderer = "latex".to_string();
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        assert_eq!(preprocessor.environments["thm"].emph, "\\textbf{");
+        assert_eq!(preprocessor.environments["thm"].emph_close, "}");
+    }
+
+    #[test]
+    fn proofs() {
         let mut refs = HashMap::new();
-        let label_file: PathBuf = "math/algebra/groups.md".into();
-        let ref_file: PathBuf = "math/crypto//signatures/bls_signatures.md".into();
-        let label_input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
-        let ref_input = String::from(r"{{tref: prop:lagrange}}");
-        let _label_output =
-            find_and_replace_envs(&label_input, SECNUM, &label_file, &ENVMAP, &mut refs);
-        let ref_output = find_and_replace_refs(&ref_input, &ref_file, &refs);
-        let expected = String::from("[Lagrange Theorem](../../algebra/groups.md#prop:lagrange)");
-        assert_eq!(ref_output, expected);
+        let input = "{{thm}}{thm:x}\n{{#proof of=thm:x}}\nBy {{selfref}}.\n{{/proof}}\n{{proof}} Obvious. {{qed}}\n{{thm}}";
+        let output = find_and_replace_envs(
+            input,
+            "",
+            &PATH,
+            &CONFIG,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut Vec::new(),
+        );
+        let qed = "<span class=\"numthm-qed\" style=\"float: right\">∎</span>";
+        assert_eq!(
+            output,
+            format!(
+                "<a id=\"thm:x\"></a>\n**Theorem 1.**\n\
+                 <div class=\"numthm numthm-proof\">\n\n*Proof (of {{{{ref: thm:x}}}}).*\n\
+                 By [Theorem 1](#thm:x).\n{qed}\n\n</div>\n\
+                 *Proof.* Obvious. {qed}\n**Theorem 2.**"
+            )
+        );
+
+        let ctx = context("[preprocessor.numthm]\nqed_symbol = \"□\"\noutput = \"markdown\"");
+        let preprocessor = NumThmPreprocessor::new(&ctx);
+        assert_eq!(replace_qed("Done.{{qed}}", &preprocessor), "Done. □");
     }
 
     #[test]
-    fn title_ref_without_title() {
+    fn equations() {
         let mut refs = HashMap::new();
-        let label_file: PathBuf = "math/algebra/groups.md".into();
-        let ref_file: PathBuf = "math/crypto//signatures/bls_signatures.md".into();
-        let label_input = String::from(r"{{prop}}{prop:lagrange}");
-        let ref_input = String::from(r"{{tref: prop:lagrange}}");
-        let _label_output =
-            find_and_replace_envs(&label_input, SECNUM, &label_file, &ENVMAP, &mut refs);
-        let ref_output = find_and_replace_refs(&ref_input, &ref_file, &refs);
-        let expected = String::from("[Proposition 1.2.1](../../algebra/groups.md#prop:lagrange)");
-        assert_eq!(ref_output, expected);
+        let mut counter = HashMap::new();
+        let input = "$$\na^2 + b^2 = c^2 {{eq}}{eq:pyth}\n$$\n\\\\[ e^{i\\pi} = -1 {{eq}} \\\\]\nSee {{eq}}{eq:x}.";
+        let output =
+            find_and_replace_equations(input, "1.2.", &PATH, &CONFIG, &mut counter, &mut refs);
+        assert_eq!(
+            output,
+            "<a id=\"eq:pyth\"></a>$$\na^2 + b^2 = c^2 \\tag{1.2.1}\n$$\n\\\\[ e^{i\\pi} = -1 \\tag{1.2.2} \\\\]\nSee {{eq}}{eq:x}."
+        );
+        assert_eq!(counter[EQ_COUNTER], 2);
+        assert_eq!(refs["eq:pyth"].num_name, "(1.2.1)");
+        assert!(!refs.contains_key("eq:x"));
+
+        let output = find_and_replace_refs("By {{ref: eq:pyth}}.", &PATH, &refs, &CONFIG).unwrap();
+        assert_eq!(output, "By [(1.2.1)](#eq:pyth).");
+
+        // the counter saturates instead of overflowing
+        let mut counter = HashMap::from([(EQ_COUNTER.to_string(), u32::MAX)]);
+        let output = find_and_replace_equations(
+            "$$ x {{eq}} $$",
+            "",
+            &PATH,
+            &CONFIG,
+            &mut counter,
+            &mut HashMap::new(),
+        );
+        assert_eq!(output, format!("$$ x \\tag{{{}}} $$", u32::MAX));
     }
 }
@@ -1,9 +1,16 @@
-use clap::{crate_version, Arg, ArgMatches, Command};
+use clap::{crate_version, Arg, ArgAction, ArgMatches, Command};
+use mdbook::book::BookItem;
 use mdbook::errors::{Error, Result};
-use mdbook::preprocess::{CmdPreprocessor, Preprocessor};
-use mdbook_numthm::NumThmPreprocessor;
+use mdbook::preprocess::{CmdPreprocessor, LinkPreprocessor, Preprocessor, PreprocessorContext};
+use mdbook::utils::fs::copy_files_except_ext;
+use mdbook::MDBook;
+use mdbook_numthm::{
+    check_anchors, diff_snapshot, search_labels, write_assets, NumThmPreprocessor,
+};
 use semver::{Version, VersionReq};
+use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
 
 /// Parse CLI options.
 pub fn make_app() -> Command {
@@ -15,6 +22,168 @@ pub fn make_app() -> Command {
                 .arg(Arg::new("renderer").required(true))
                 .about("Check whether a renderer is supported by this preprocessor"),
         )
+        .subcommand(
+            Command::new("bake")
+                .arg(
+                    Arg::new("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .arg(
+                    Arg::new("dest")
+                        .long("dest")
+                        .value_name("DIR")
+                        .conflicts_with("in-place")
+                        .help("Directory where a processed copy of the sources is written"),
+                )
+                .arg(
+                    Arg::new("in-place")
+                        .long("in-place")
+                        .action(ArgAction::SetTrue)
+                        .help("Rewrite the sources of the book in place"),
+                )
+                .about(
+                    "Replace all macros in the sources of a book with numbered headers and links",
+                ),
+        )
+        .subcommand(
+            Command::new("render")
+                .arg(
+                    Arg::new("chapter")
+                        .required(true)
+                        .help("Path of the chapter, relative to the source directory"),
+                )
+                .arg(
+                    Arg::new("database")
+                        .long("database")
+                        .value_name("FILE")
+                        .required(true)
+                        .help(
+                            "Label database (JSON completions file) exported by a previous build",
+                        ),
+                )
+                .arg(
+                    Arg::new("dir")
+                        .long("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .about(
+                    "Print a single processed chapter using a previously exported label database",
+                ),
+        )
+        .subcommand(
+            Command::new("rename-label")
+                .arg(Arg::new("old").required(true).help("Label to rename"))
+                .arg(Arg::new("new").required(true).help("New label"))
+                .arg(
+                    Arg::new("dir")
+                        .long("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the number of occurrences to rename without modifying the sources"),
+                )
+                .about("Rename a label in its definition and in all references of the book"),
+        )
+        .subcommand(
+            Command::new("find")
+                .arg(
+                    Arg::new("query")
+                        .required(true)
+                        .help("Text to search in labels, numbered names, and titles"),
+                )
+                .arg(
+                    Arg::new("database")
+                        .long("database")
+                        .value_name("FILE")
+                        .required(true)
+                        .help(
+                            "Label database (JSON completions file) exported by a previous build",
+                        ),
+                )
+                .about("Print the labeled results of a book matching a query, with their numbers and files"),
+        )
+        .subcommand(
+            Command::new("macros")
+                .arg(
+                    Arg::new("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .about("List the macros of the sources of a book and whether they are processed, without processing them"),
+        )
+        .subcommand(
+            Command::new("snapshot")
+                .arg(
+                    Arg::new("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .arg(
+                    Arg::new("snapshots")
+                        .long("snapshots")
+                        .value_name("DIR")
+                        .default_value("numthm-snapshots")
+                        .help("Directory of the snapshots, relative to the root directory of the book"),
+                )
+                .arg(
+                    Arg::new("update")
+                        .long("update")
+                        .action(ArgAction::SetTrue)
+                        .help("Write the processed chapters as the new snapshots instead of comparing them"),
+                )
+                .about("Compare the processed markdown of each chapter with its snapshot"),
+        )
+        .subcommand(
+            Command::new("check")
+                .arg(
+                    Arg::new("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the problems as a JSON array"),
+                )
+                .about("Check the labels and references of the sources of a book without processing them"),
+        )
+        .subcommand(
+            Command::new("check-anchors")
+                .arg(
+                    Arg::new("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .about("Check that all links with a fragment of the rendered HTML book resolve to an anchor"),
+        )
+        .subcommand(
+            Command::new("assets")
+                .arg(
+                    Arg::new("dir")
+                        .required(true)
+                        .help("Directory where the assets are written"),
+                )
+                .about("Write the CSS/JS assets of the preprocessor for manual setup"),
+        )
+}
+
+/// Loads the book located in `dir` together with a context for running the preprocessor on it.
+fn load_book(dir: &str, renderer: &str) -> Result<(MDBook, PreprocessorContext)> {
+    let md = MDBook::load(dir)?;
+    let ctx = serde_json::from_value(serde_json::json!({
+        "root": md.root,
+        "config": md.config,
+        "renderer": renderer,
+        "mdbook_version": mdbook::MDBOOK_VERSION,
+    }))?;
+    Ok((md, ctx))
 }
 
 fn handle_preprocessing() -> Result<()> {
@@ -61,14 +230,306 @@ fn handle_supports(sub_args: &ArgMatches) -> Result<()> {
     }
 }
 
+fn handle_bake(sub_args: &ArgMatches) -> Result<()> {
+    let dir = sub_args.get_one::<String>("dir").expect("Default value");
+    let (md, ctx) = load_book(dir, "html")?;
+    let src_dir = md.source_dir();
+
+    let dest = match sub_args.get_one::<String>("dest") {
+        Some(dest) => {
+            let dest = PathBuf::from(dest);
+            fs::create_dir_all(&dest)?;
+            copy_files_except_ext(&src_dir, &dest, true, Some(&dest), &[])?;
+            dest
+        }
+        None if sub_args.get_flag("in-place") => src_dir,
+        None => {
+            return Err(Error::msg(
+                "Either a destination directory (--dest) or --in-place must be given",
+            ))
+        }
+    };
+
+    let pre = NumThmPreprocessor::new(&ctx);
+    pre.bake(&ctx, md.book, &dest)
+}
+
+fn handle_render(sub_args: &ArgMatches) -> Result<()> {
+    let dir = sub_args.get_one::<String>("dir").expect("Default value");
+    let chapter_path = PathBuf::from(
+        sub_args
+            .get_one::<String>("chapter")
+            .expect("Required argument"),
+    );
+    let database = fs::read_to_string(
+        sub_args
+            .get_one::<String>("database")
+            .expect("Required argument"),
+    )?;
+
+    let (md, ctx) = load_book(dir, "html")?;
+    let chapter = md
+        .book
+        .iter()
+        .find_map(|item| match item {
+            BookItem::Chapter(chapter) if chapter.source_path.as_ref() == Some(&chapter_path) => {
+                Some(chapter)
+            }
+            _ => None,
+        })
+        .ok_or_else(|| {
+            Error::msg(format!(
+                "No chapter `{}' in the book",
+                chapter_path.display()
+            ))
+        })?;
+
+    let pre = NumThmPreprocessor::new(&ctx);
+    print!("{}", pre.render_chapter(&ctx, chapter, &database)?);
+
+    Ok(())
+}
+
+fn handle_rename_label(sub_args: &ArgMatches) -> Result<()> {
+    let dir = sub_args.get_one::<String>("dir").expect("Default value");
+    let old = sub_args
+        .get_one::<String>("old")
+        .expect("Required argument");
+    let new = sub_args
+        .get_one::<String>("new")
+        .expect("Required argument");
+    let dry_run = sub_args.get_flag("dry-run");
+
+    let (md, ctx) = load_book(dir, "html")?;
+    let src_dir = md.source_dir();
+    let pre = NumThmPreprocessor::new(&ctx);
+
+    let mut renamed = Vec::new();
+    for item in md.book.iter() {
+        if let BookItem::Chapter(chapter) = item {
+            if let Some(path) = &chapter.source_path {
+                let content = fs::read_to_string(src_dir.join(path))?;
+                if pre.rename_label(&content, new, new).1 > 0 {
+                    return Err(Error::msg(format!(
+                        "Label `{new}' is already used in `{}'",
+                        path.display()
+                    )));
+                }
+                let (content, count) = pre.rename_label(&content, old, new);
+                if count > 0 {
+                    renamed.push((path, content, count));
+                }
+            }
+        }
+    }
+
+    for (path, content, count) in renamed {
+        println!("{}: {count} occurrence(s)", path.display());
+        if !dry_run {
+            fs::write(src_dir.join(path), content)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_find(sub_args: &ArgMatches) -> Result<()> {
+    let query = sub_args
+        .get_one::<String>("query")
+        .expect("Required argument");
+    let database = fs::read_to_string(
+        sub_args
+            .get_one::<String>("database")
+            .expect("Required argument"),
+    )?;
+
+    for result in search_labels(&database, query)? {
+        println!("{result}");
+    }
+
+    Ok(())
+}
+
+fn handle_macros(sub_args: &ArgMatches) -> Result<()> {
+    let dir = sub_args.get_one::<String>("dir").expect("Default value");
+    let (md, ctx) = load_book(dir, "html")?;
+
+    let pre = NumThmPreprocessor::new(&ctx);
+    for line in pre.macro_report(&md.book) {
+        println!("{line}");
+    }
+
+    Ok(())
+}
+
+fn handle_snapshot(sub_args: &ArgMatches) -> Result<()> {
+    let dir = sub_args.get_one::<String>("dir").expect("Default value");
+    let (md, ctx) = load_book(dir, "html")?;
+    let snapshots = md.root.join(
+        sub_args
+            .get_one::<String>("snapshots")
+            .expect("Default value"),
+    );
+    let update = sub_args.get_flag("update");
+
+    // the chapters are processed as by `mdbook build', with included files expanded, but without writing any file
+    let pre = NumThmPreprocessor::new(&ctx).without_outputs();
+    let book = LinkPreprocessor::new().run(&ctx, md.book)?;
+    let book = pre.run(&ctx, book)?;
+
+    let mut changed = 0;
+    let mut chapters = Vec::new();
+    for item in book.iter() {
+        if let BookItem::Chapter(chapter) = item {
+            if let Some(path) = &chapter.source_path {
+                let snapshot = snapshots.join(path);
+                chapters.push(snapshot.clone());
+                if update {
+                    if let Some(parent) = snapshot.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::write(&snapshot, &chapter.content)?;
+                    continue;
+                }
+                let Ok(baseline) = fs::read_to_string(&snapshot) else {
+                    println!("{}: no snapshot", path.display());
+                    changed += 1;
+                    continue;
+                };
+                let diff = diff_snapshot(&baseline, &chapter.content);
+                if !diff.is_empty() {
+                    println!("{}:", path.display());
+                    for line in diff {
+                        println!("{line}");
+                    }
+                    changed += 1;
+                }
+            }
+        }
+    }
+
+    // snapshots of chapters which were removed or moved are stale
+    let mut files = Vec::new();
+    if snapshots.is_dir() {
+        snapshot_files(&snapshots, &mut files)?;
+    }
+    for file in files.into_iter().filter(|file| !chapters.contains(file)) {
+        if update {
+            fs::remove_file(&file)?;
+            continue;
+        }
+        let path = file.strip_prefix(&snapshots).unwrap_or(&file);
+        println!("{}: stale snapshot", path.display());
+        changed += 1;
+    }
+
+    if changed == 0 {
+        Ok(())
+    } else {
+        Err(Error::msg(format!(
+            "{changed} snapshot(s) differ from the chapters, run with --update to accept the changes"
+        )))
+    }
+}
+
+/// Collects the files of `dir`, recursively, into `files`.
+fn snapshot_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            snapshot_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn handle_check(sub_args: &ArgMatches) -> Result<()> {
+    let dir = sub_args.get_one::<String>("dir").expect("Default value");
+    let (md, ctx) = load_book(dir, "html")?;
+
+    let pre = NumThmPreprocessor::new(&ctx);
+    // included files are expanded, as by `mdbook build', so that their labels are known
+    let book = LinkPreprocessor::new().run(&ctx, md.book)?;
+    let issues = pre.check(&book);
+    if sub_args.get_flag("json") {
+        println!("{}", serde_json::to_string_pretty(&issues)?);
+    } else {
+        for issue in issues.iter() {
+            println!("{issue}");
+        }
+    }
+
+    // unused labels are reported without failing the check
+    let errors = issues.iter().filter(|issue| issue.error).count();
+    if errors == 0 {
+        Ok(())
+    } else {
+        Err(Error::msg(format!(
+            "{errors} problem(s) with labels and references"
+        )))
+    }
+}
+
+fn handle_check_anchors(sub_args: &ArgMatches) -> Result<()> {
+    let dir = sub_args.get_one::<String>("dir").expect("Default value");
+    let md = MDBook::load(dir)?;
+    let build_dir = md.build_dir_for("html");
+    if !build_dir.is_dir() {
+        return Err(Error::msg(format!(
+            "No rendered book in `{}', run `mdbook build' first",
+            build_dir.display()
+        )));
+    }
+
+    let broken = check_anchors(&build_dir)?;
+    for link in broken.iter() {
+        println!("{link}");
+    }
+    if broken.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::msg(format!("{} broken link(s)", broken.len())))
+    }
+}
+
+fn handle_assets(sub_args: &ArgMatches) -> Result<()> {
+    let dir = sub_args
+        .get_one::<String>("dir")
+        .expect("Required argument");
+    write_assets(Path::new(dir))
+}
+
 fn main() -> Result<()> {
-    ::std::env::set_var("RUST_LOG", "warn");
+    // warnings are shown by default, more (e.g. auto-linked labels) with `RUST_LOG=info`
+    if ::std::env::var_os("RUST_LOG").is_none() {
+        ::std::env::set_var("RUST_LOG", "warn");
+    }
     env_logger::init();
     let matches = make_app().get_matches();
 
     if let Some(sub_args) = matches.subcommand_matches("supports") {
         // handle cmdline supports
         handle_supports(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("bake") {
+        handle_bake(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("render") {
+        handle_render(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("rename-label") {
+        handle_rename_label(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("find") {
+        handle_find(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("macros") {
+        handle_macros(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("snapshot") {
+        handle_snapshot(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("check") {
+        handle_check(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("check-anchors") {
+        handle_check_anchors(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("assets") {
+        handle_assets(sub_args)
     } else {
         // handle preprocessing
         handle_preprocessing()